@@ -0,0 +1,140 @@
+# Pushes a stream through a pair of in-memory connections and reports how
+# fast packets go through on_packets and drain, and how many calls were made
+# into the VM's binary allocator meanwhile. The NIF's packet and stream
+# binaries come from that allocator, so unlike the process's GC counts these
+# see them.
+#
+#   mix run bench/packet_path.exs [megabytes]
+
+alias Requiem.QUIC.Config
+alias Requiem.QUIC.Connection
+alias Requiem.QUIC.Socket
+
+defmodule PacketPathBench do
+  def run(megabytes) do
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+    client_conf = config()
+    server_conf = config()
+    :ok = Config.verify_peer(client_conf, false)
+    :ok = Config.load_cert_chain_from_pem_file(server_conf, "test/support/cert.crt")
+    :ok = Config.load_priv_key_from_pem_file(server_conf, "test/support/cert.key")
+
+    {:ok, server} =
+      Connection.accept(
+        server_conf,
+        :crypto.strong_rand_bytes(20),
+        "",
+        client_peer,
+        self(),
+        1024 * 64,
+        1350
+      )
+
+    {:ok, client} =
+      Connection.connect(
+        client_conf,
+        "example.com",
+        :crypto.strong_rand_bytes(20),
+        server_peer,
+        self(),
+        1024 * 64,
+        1350
+      )
+
+    peers = {client, server, client_peer, server_peer}
+    exchange(peers, 0)
+    true = Connection.is_established?(client)
+
+    chunk = :crypto.strong_rand_bytes(1024 * 64)
+    total = megabytes * 16
+    :erlang.garbage_collect()
+    calls_before = binary_alloc_calls()
+
+    {micros, packets} =
+      :timer.tc(fn ->
+        Enum.reduce(1..total, 0, fn _, acc -> acc + send_all(peers, chunk, 0) end)
+      end)
+
+    calls_after = binary_alloc_calls()
+
+    IO.puts("#{megabytes} MB in #{div(micros, 1000)} ms, #{packets} packets")
+    IO.puts("#{trunc(packets / (micros / 1_000_000))} packets/s")
+
+    for {call, n} <- calls_after do
+      made = n - Map.get(calls_before, call, 0)
+      IO.puts("#{call}: #{made} (#{Float.round(made / max(packets, 1), 2)} per packet)")
+    end
+
+    Connection.destroy(client)
+    Connection.destroy(server)
+    Config.destroy(client_conf)
+    Config.destroy(server_conf)
+  end
+
+  # binary_alloc, binary_free and binary_realloc calls summed over the
+  # allocator's instances; the VM has to run with binary_alloc enabled, as
+  # it is by default
+  defp binary_alloc_calls() do
+    for {:instance, _, info} <- :erlang.system_info({:allocator, :binary_alloc}),
+        {:calls, calls} <- info,
+        {call, giga, n} <- calls,
+        call in [:binary_alloc, :binary_free, :binary_realloc],
+        reduce: %{} do
+      acc -> Map.update(acc, call, giga * 1_000_000_000 + n, &(&1 + giga * 1_000_000_000 + n))
+    end
+  end
+
+  defp config() do
+    {:ok, c} = Config.new()
+    :ok = Config.set_application_protos(c, ["bench"])
+    :ok = Config.set_initial_max_data(c, 1024 * 1024 * 1024)
+    :ok = Config.set_initial_max_stream_data_bidi_local(c, 1024 * 1024 * 1024)
+    :ok = Config.set_initial_max_stream_data_bidi_remote(c, 1024 * 1024 * 1024)
+    :ok = Config.set_initial_max_streams_bidi(c, 100)
+    c
+  end
+
+  # returns how many packets went back and forth meanwhile
+  defp send_all({client, _, _, _} = peers, data, count) do
+    case Connection.stream_send(client, 0, data, false) do
+      {:ok, _, _} ->
+        count + exchange(peers, 0)
+
+      {:partial, written, _} ->
+        rest = binary_part(data, written, byte_size(data) - written)
+        send_all(peers, rest, count + exchange(peers, 0))
+    end
+  end
+
+  # delivers drained packets to the other side until both go quiet, and
+  # counts them; everything else, like the stream data reaching the
+  # server, is dropped
+  defp exchange({client, server, client_peer, server_peer} = peers, count) do
+    {:ok, client_ip, client_port} = Socket.address_parts(client_peer)
+    {:ok, server_ip, server_port} = Socket.address_parts(server_peer)
+
+    receive do
+      {:__drain__, ^server_ip, ^server_port, packets, _delay} ->
+        Connection.on_packets(server, client_peer, packets)
+        exchange(peers, count + length(packets))
+
+      {:__drain__, ^client_ip, ^client_port, packets, _delay} ->
+        Connection.on_packets(client, server_peer, packets)
+        exchange(peers, count + length(packets))
+
+      _ ->
+        exchange(peers, count)
+    after
+      0 -> count
+    end
+  end
+end
+
+megabytes =
+  case System.argv() do
+    [n] -> String.to_integer(n)
+    [] -> 64
+  end
+
+PacketPathBench.run(megabytes)
@@ -350,7 +350,7 @@ defmodule Requiem do
       @spec close(non_neg_integer, atom) :: no_return
       def close(code, reason), do: send(self(), {:__close__, true, code, reason})
 
-      @spec stream_send(non_neg_integer, binary, boolean) :: no_return
+      @spec stream_send(non_neg_integer, Requiem.QUIC.NIF.segments(), boolean) :: no_return
       def stream_send(stream_id, data, fin) do
         if Requiem.StreamId.is_writable?(stream_id) do
           send(self(), {:__stream_send__, stream_id, data, fin})
@@ -361,7 +361,7 @@ defmodule Requiem do
         end
       end
 
-      @spec dgram_send(binary) :: no_return
+      @spec dgram_send(Requiem.QUIC.NIF.segments()) :: no_return
       def dgram_send(data),
         do: send(self(), {:__dgram_send__, data})
 
@@ -11,20 +11,34 @@ defmodule Requiem.Config do
           | :socket_pool_size
           | :socket_read_timeout
           | :socket_write_timeout
+          | :socket_max_datagram_size
+          | :socket_recv_batch_size
+          | :socket_v6_only
+          | :socket_send_retries
+          | :socket_recv_buffer_size
+          | :socket_send_buffer_size
+          | :socket_normalize_v4_mapped
+          | :socket_recv_error_policy
           | :allow_address_routing
           | :token_secret
           | :connection_id_secret
           | :dgram_queue_size
+          | :stream_recv_buffer_size
           | :cert_chain
           | :priv_key
           | :verify_locations_file
           | :verify_locations_directory
           | :grease
           | :verify_peer
+          | :require_peer_cert
           | :enable_early_data
+          | :keylog_file
+          | :ticket_key
           | :application_protos
           | :max_idle_timeout
           | :max_udp_payload_size
+          | :max_recv_udp_payload_size
+          | :max_send_udp_payload_size
           | :initial_max_data
           | :initial_max_stream_data_bidi_local
           | :initial_max_stream_data_bidi_remote
@@ -37,6 +51,8 @@ defmodule Requiem.Config do
           | :cc_algorithm_name
           | :enable_hystart
           | :enable_dgram
+          | :max_connection_window
+          | :max_stream_window
 
   @default_values [
     web_transport: false,
@@ -46,6 +62,11 @@ defmodule Requiem.Config do
     socket_pool_size: 0,
     socket_read_timeout: 100,
     socket_write_timeout: 100,
+    socket_max_datagram_size: 1500,
+    socket_recv_batch_size: 32,
+    socket_normalize_v4_mapped: false,
+    socket_send_retries: 3,
+    socket_recv_error_policy: :notify,
     allow_address_routing: false,
     token_secret: :crypto.strong_rand_bytes(16),
     connection_id_secret: :crypto.strong_rand_bytes(32),
@@ -57,6 +78,7 @@ defmodule Requiem.Config do
     initial_max_streams_bidi: 1,
     initial_max_streams_uni: 2,
     dgram_queue_size: 1000,
+    stream_recv_buffer_size: 65_536,
     max_idle_timeout: 60_000,
     disable_active_migration: true
   ]
@@ -69,20 +91,34 @@ defmodule Requiem.Config do
     socket_pool_size: true,
     socket_read_timeout: true,
     socket_write_timeout: true,
+    socket_max_datagram_size: true,
+    socket_recv_batch_size: true,
+    socket_v6_only: true,
+    socket_send_retries: true,
+    socket_recv_buffer_size: true,
+    socket_send_buffer_size: true,
+    socket_normalize_v4_mapped: true,
+    socket_recv_error_policy: true,
     allow_address_routing: true,
     token_secret: true,
     connection_id_secret: true,
     dgram_queue_size: true,
+    stream_recv_buffer_size: true,
     cert_chain: true,
     priv_key: true,
     verify_locations_file: true,
     verify_locations_directory: true,
     grease: true,
     verify_peer: true,
+    require_peer_cert: true,
     enable_early_data: true,
+    keylog_file: true,
+    ticket_key: true,
     application_protos: true,
     max_idle_timeout: true,
     max_udp_payload_size: true,
+    max_recv_udp_payload_size: true,
+    max_send_udp_payload_size: true,
     initial_max_data: true,
     initial_max_stream_data_bidi_local: true,
     initial_max_stream_data_bidi_remote: true,
@@ -94,7 +130,9 @@ defmodule Requiem.Config do
     disable_active_migration: true,
     cc_algorithm_name: true,
     enable_hystart: true,
-    enable_dgram: true
+    enable_dgram: true,
+    max_connection_window: true,
+    max_stream_window: true
   }
 
   @spec get!(module, config_key) :: term
@@ -124,6 +162,7 @@ defmodule Requiem.Config do
     config1 = Application.get_env(otp_app, handler, [])
     config2 = Keyword.merge(@default_values, config1)
     check_key_existence(config2)
+    check_cc_algorithm(config2)
     store(handler, config2)
   end
 
@@ -142,5 +181,22 @@ defmodule Requiem.Config do
     end)
   end
 
+  @spec check_cc_algorithm(Keyword.t()) :: :ok
+  def check_cc_algorithm(opts) do
+    case Keyword.get(opts, :cc_algorithm_name) do
+      nil ->
+        :ok
+
+      name ->
+        available = Enum.map(Requiem.QUIC.Config.cc_available_algorithms(), &to_string/1)
+
+        if to_string(name) not in available do
+          raise "<Requiem.Config> unsupported cc_algorithm_name:#{name} set."
+        end
+
+        :ok
+    end
+  end
+
   defp config_name(handler), do: Module.concat(handler, __MODULE__)
 end
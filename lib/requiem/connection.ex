@@ -23,6 +23,7 @@ defmodule Requiem.Connection do
           web_transport: boolean,
           conn_state: ConnectionState.t(),
           conn: any,
+          pending_stream_data: %{non_neg_integer => {binary, boolean}},
           timer: reference
         }
 
@@ -34,6 +35,7 @@ defmodule Requiem.Connection do
             web_transport: true,
             conn_state: nil,
             conn: nil,
+            pending_stream_data: %{},
             timer: nil
 
   @spec process_packet(pid, Address.t(), binary) :: :ok
@@ -62,11 +64,13 @@ defmodule Requiem.Connection do
            state.conn_state.odcid,
            state.conn_state.address.raw,
            sender_pid,
-           1024 * 10
+           Config.get!(state.handler, :stream_recv_buffer_size),
+           max_send_udp_payload_size(state.handler)
          ) do
       {:ok, conn} ->
         Tracer.trace(__MODULE__, state.trace_id, "@acccept: completed")
         Process.flag(:trap_exit, true)
+        set_keylog(conn, state)
 
         case ConnectionRegistry.register(
                state.handler,
@@ -163,10 +167,10 @@ defmodule Requiem.Connection do
   end
 
   @impl GenServer
-  def handle_cast({:__packet__, _address, packet}, state) do
+  def handle_cast({:__packet__, address, packet}, state) do
     Tracer.trace(__MODULE__, state.trace_id, "@packet")
 
-    case QUIC.Connection.on_packet(state.conn, packet) do
+    case QUIC.Connection.on_packet(state.conn, address.raw, packet) do
       {:ok, next_timeout} ->
         Tracer.trace(
           __MODULE__,
@@ -174,6 +178,7 @@ defmodule Requiem.Connection do
           "@packet: completed, next_timeout: #{next_timeout}"
         )
 
+        state = update_address(state, address)
         state = reset_conn_timer(state, next_timeout)
         {:noreply, state}
 
@@ -181,7 +186,8 @@ defmodule Requiem.Connection do
         close(false, :no_error, :shutdown)
         {:noreply, state}
 
-      {:error, :system_error} ->
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@packet: error: #{reason}")
         close(false, :internal_error, :server_error)
         {:noreply, state}
     end
@@ -244,23 +250,76 @@ defmodule Requiem.Connection do
     Tracer.trace(__MODULE__, state.trace_id, "@timeout")
 
     case QUIC.Connection.on_timeout(state.conn) do
-      {:ok, next_timeout} ->
+      {:ok, :active, next_timeout} ->
         Tracer.trace(__MODULE__, state.trace_id, "@timeout: done, next_timeout: #{next_timeout}")
         state = reset_conn_timer(state, next_timeout)
         {:noreply, state}
 
+      {:ok, :draining, next_timeout} ->
+        Tracer.trace(
+          __MODULE__,
+          state.trace_id,
+          "@timeout: draining, next_timeout: #{next_timeout}"
+        )
+
+        state = reset_conn_timer(state, next_timeout)
+        {:noreply, state}
+
+      {:ok, :closed} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@timeout: closed")
+        send(self(), {:__delayed_close__, :normal})
+        {:noreply, state}
+
       {:error, :already_closed} ->
         Tracer.trace(__MODULE__, state.trace_id, "@timeout: already closed")
         close(false, :no_error, :shutdown)
         {:noreply, state}
 
-      {:error, :system_error} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@timeout: error")
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@timeout: error: #{reason}")
         close(false, :internal_error, :server_error)
         {:noreply, state}
     end
   end
 
+  def handle_info({:__early_data__}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@early_data")
+    {:noreply, state}
+  end
+
+  def handle_info({:__connected__, alpn}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@connected: alpn: #{inspect(alpn)}")
+    {:noreply, state}
+  end
+
+  def handle_info({:__drain_error__, reason, trace_id}, state) do
+    Logger.error(
+      "<Requiem.Connection:#{inspect(self())}> failed to drain(#{trace_id}): #{reason}"
+    )
+    {:noreply, state}
+  end
+
+  def handle_info({:__peer_migrated__, ip, port}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@peer_migrated: #{inspect(ip)}:#{port}")
+
+    {:noreply, state}
+  end
+
+  def handle_info({:__peer_streams_left__, bidi, uni}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@peer_streams_left: bidi: #{bidi}, uni: #{uni}")
+    {:noreply, state}
+  end
+
+  def handle_info({:__closed__, is_app, error_code, reason}, state) do
+    Tracer.trace(
+      __MODULE__,
+      state.trace_id,
+      "@closed: app: #{is_app}, error_code: #{error_code}, reason: #{inspect(reason)}"
+    )
+
+    {:noreply, state}
+  end
+
   def handle_info(
         {:__stream_recv__, 2, data},
         %{web_transport: true, handler_initialized: false} = state
@@ -387,11 +446,10 @@ defmodule Requiem.Connection do
     {:noreply, state}
   end
 
-  def handle_info({:__close__, app, _err, reason}, state) do
+  def handle_info({:__close__, app, err, reason}, state) do
     Tracer.trace(__MODULE__, state.trace_id, "@close")
 
-    # TODO set proper error code
-    case QUIC.Connection.close(state.conn, app, 0x1, to_string(reason)) do
+    case QUIC.Connection.close(state.conn, app, err, to_string(reason)) do
       {:ok, next_timeout} ->
         Tracer.trace(
           __MODULE__,
@@ -402,14 +460,19 @@ defmodule Requiem.Connection do
         state = reset_conn_timer(state, next_timeout)
         {:noreply, state}
 
-      {:error, :already_closed} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@close: already closed, set delayed close")
-        send(self(), {:__delayed_close__, :normal})
+      :ok ->
+        if QUIC.Connection.is_closed?(state.conn) do
+          Tracer.trace(__MODULE__, state.trace_id, "@close: already closed, set delayed close")
+          send(self(), {:__delayed_close__, :normal})
+        else
+          Tracer.trace(__MODULE__, state.trace_id, "@close: already draining")
+        end
+
         {:noreply, state}
 
-      {:error, :system_error} ->
+      {:error, reason} ->
         Tracer.trace(__MODULE__, state.trace_id, "@close: error, set delayed close")
-        send(self(), {:__delayed_close__, {:shutdown, :system_error}})
+        send(self(), {:__delayed_close__, {:shutdown, reason}})
         {:noreply, state}
     end
   end
@@ -422,25 +485,31 @@ defmodule Requiem.Connection do
   def handle_info({:__stream_send__, stream_id, data, fin}, state) do
     Tracer.trace(__MODULE__, state.trace_id, "@stream_send")
 
-    case QUIC.Connection.stream_send(state.conn, stream_id, data, fin) do
-      {:ok, next_timeout} ->
-        Tracer.trace(
-          __MODULE__,
-          state.trace_id,
-          "@stream_send: completed. next_timeout: #{next_timeout}"
-        )
+    case Map.fetch(state.pending_stream_data, stream_id) do
+      {:ok, {pending, pending_fin}} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: queued behind pending data")
 
-        state = reset_conn_timer(state, next_timeout)
-        {:noreply, state}
+        pending = IO.iodata_to_binary([pending, data])
 
-      {:error, :already_closed} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: already closed")
-        close(false, :no_error, :shutdown)
-        {:noreply, state}
+        pending_stream_data =
+          Map.put(state.pending_stream_data, stream_id, {pending, pending_fin || fin})
 
-      {:error, :system_error} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: error")
-        # close(false, 0, :server_error)
+        {:noreply, %{state | pending_stream_data: pending_stream_data}}
+
+      :error ->
+        send_stream_data(stream_id, data, fin, state)
+    end
+  end
+
+  def handle_info({:__stream_writable__, stream_id}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@stream_writable")
+
+    case Map.pop(state.pending_stream_data, stream_id) do
+      {{data, fin}, pending_stream_data} ->
+        state = %{state | pending_stream_data: pending_stream_data}
+        send_stream_data(stream_id, data, fin, state)
+
+      {nil, _} ->
         {:noreply, state}
     end
   end
@@ -464,8 +533,8 @@ defmodule Requiem.Connection do
         close(false, :no_error, :shutdown)
         {:noreply, state}
 
-      {:error, :system_error} ->
-        Tracer.trace(__MODULE__, state.trace_id, "@dgram_send: error")
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@dgram_send: error: #{reason}")
         # close(false, 0, :server_error)
         {:noreply, state}
     end
@@ -636,12 +705,68 @@ defmodule Requiem.Connection do
     end
   end
 
+  defp send_stream_data(stream_id, data, fin, state) do
+    case QUIC.Connection.stream_send(state.conn, stream_id, data, fin) do
+      {:ok, _len, next_timeout} ->
+        Tracer.trace(
+          __MODULE__,
+          state.trace_id,
+          "@stream_send: completed. next_timeout: #{next_timeout}"
+        )
+
+        state = reset_conn_timer(state, next_timeout)
+        {:noreply, state}
+
+      {:partial, len, next_timeout} ->
+        data = IO.iodata_to_binary(data)
+
+        Tracer.trace(
+          __MODULE__,
+          state.trace_id,
+          "@stream_send: partial(#{len}/#{byte_size(data)}). next_timeout: #{next_timeout}"
+        )
+
+        rest = binary_part(data, len, byte_size(data) - len)
+        pending_stream_data = Map.put(state.pending_stream_data, stream_id, {rest, fin})
+        state = %{state | pending_stream_data: pending_stream_data}
+        state = reset_conn_timer(state, next_timeout)
+        {:noreply, state}
+
+      {:error, :already_closed} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: already closed")
+        close(false, :no_error, :shutdown)
+        {:noreply, state}
+
+      {:error, reason} ->
+        Tracer.trace(__MODULE__, state.trace_id, "@stream_send: error: #{reason}")
+        # close(false, 0, :server_error)
+        {:noreply, state}
+    end
+  end
+
+  # the NIF only switches to a new peer after quiche accepted the packet,
+  # so the routing table follows it here.
+  defp update_address(state, address) do
+    if Address.same?(address, state.conn_state.address) do
+      state
+    else
+      if state.allow_address_routing do
+        AddressTable.delete(state.handler, state.conn_state.address)
+        AddressTable.insert(state.handler, address, state.conn_state.dcid)
+      end
+
+      %{state | conn_state: %{state.conn_state | address: address}}
+    end
+  end
+
   defp reset_conn_timer(state, timeout) do
     state
     |> cancel_conn_timer()
     |> start_conn_timer(timeout)
   end
 
+  defp start_conn_timer(state, :infinity), do: state
+
   defp start_conn_timer(state, timeout) do
     timer = Process.send_after(self(), :__timeout__, timeout)
     %{state | timer: timer}
@@ -658,6 +783,31 @@ defmodule Requiem.Connection do
     end
   end
 
+  # quiche writes packets no bigger than this, and never below 1200 bytes
+  defp max_send_udp_payload_size(handler) do
+    size =
+      Config.get(handler, :max_send_udp_payload_size) ||
+        Config.get!(handler, :max_udp_payload_size)
+
+    max(size, 1200)
+  end
+
+  defp set_keylog(conn, state) do
+    case Config.get(state.handler, :keylog_file) do
+      nil ->
+        :ok
+
+      path ->
+        case QUIC.Connection.set_keylog_path(conn, path) do
+          :ok ->
+            :ok
+
+          {:error, reason} ->
+            Logger.error("<Requiem.Connection:#{inspect(self())}> keylog disabled: #{reason}")
+        end
+    end
+  end
+
   defp close(app, err, reason) when is_atom(err) do
     close(app, ErrorCode.to_integer(err), reason)
   end
@@ -689,6 +839,7 @@ defmodule Requiem.Connection do
       web_transport: Config.get!(handler, :web_transport),
       conn_state: ConnectionState.new(address, dcid, scid, odcid),
       conn: nil,
+      pending_stream_data: %{},
       timer: nil
     }
   end
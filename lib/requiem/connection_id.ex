@@ -1,9 +1,36 @@
 defmodule Requiem.ConnectionID do
+  alias Requiem.QUIC.NIF
+
+  @len 20
+
+  # length of the ids generate_from_odcid/2 hands out
+  @spec len() :: non_neg_integer
+  def len(), do: @len
+
   @spec generate_from_odcid(binary, binary) :: {:ok, binary} | :error
   def generate_from_odcid(key, odcid) do
     case :crypto.mac(:hmac, :sha256, key, odcid) do
-      <<new_id::binary-size(20), _rest::binary>> -> {:ok, new_id}
+      <<new_id::binary-size(@len), _rest::binary>> -> {:ok, new_id}
       _ -> :error
     end
   end
+
+  # random id of 1 to 20 bytes
+  @spec generate(non_neg_integer) :: {:ok, binary} | {:error, :bad_format | :system_error}
+  def generate(len) do
+    NIF.cid_generate(len)
+  end
+
+  # A 20 byte id carrying up to 7 bytes of payload, readable by anyone but
+  # authenticated with the secret (at least 16 bytes), so a dispatcher can
+  # route on it with open/2.
+  @spec sign(binary, binary) :: {:ok, binary} | {:error, :bad_format | :system_error}
+  def sign(secret, payload) do
+    NIF.cid_sign(secret, payload)
+  end
+
+  @spec open(binary, binary) :: {:ok, binary} | {:error, :bad_format}
+  def open(secret, cid) do
+    NIF.cid_open(secret, cid)
+  end
 end
@@ -37,6 +37,7 @@ defmodule Requiem.DispatcherSupervisor do
          token_secret: Keyword.fetch!(opts, :token_secret),
          conn_id_secret: Keyword.fetch!(opts, :conn_id_secret),
          number_of_sockets: Keyword.fetch!(opts, :number_of_sockets),
+         socket_ptr: Keyword.fetch!(opts, :socket_ptr),
          allow_address_routing: Keyword.fetch!(opts, :allow_address_routing)
        ]}
     end)
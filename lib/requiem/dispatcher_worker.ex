@@ -21,6 +21,7 @@ defmodule Requiem.DispatcherWorker do
           worker_index: non_neg_integer,
           number_of_sockets: non_neg_integer,
           allow_address_routing: boolean,
+          socket_ptr: integer,
           config_ptr: integer,
           sender_pid: pid,
           packet_builder: integer,
@@ -32,6 +33,7 @@ defmodule Requiem.DispatcherWorker do
             conn_id_secret: "",
             worker_index: 0,
             number_of_sockets: 0,
+            socket_ptr: 0,
             config_ptr: 0,
             sender_pid: nil,
             allow_address_routing: false,
@@ -84,6 +86,9 @@ defmodule Requiem.DispatcherWorker do
            state.worker_index
          ) do
       {:ok, _pid} ->
+        # a restarted worker takes over its slot; before the first start
+        # of the socket this is :bad_state, and Transport passes our pid
+        QUIC.Socket.set_target_pid(state.socket_ptr, state.worker_index, self())
         {:ok, builder} = QUIC.PacketBuilder.new()
         {:ok, %{state | packet_builder: builder, config_ptr: config}}
 
@@ -94,12 +99,24 @@ defmodule Requiem.DispatcherWorker do
   end
 
   @impl GenServer
-  def handle_info(
-        {:__packet__, peer, packet, scid, dcid, token, version, packet_type,
-         is_version_supported},
-        state
-      ) do
+  def handle_info({:__packets__, packets}, state) do
     # this come from native receiver socket
+    Enum.each(packets, &handle_received_packet(&1, state))
+    {:noreply, state}
+  end
+
+  @impl GenServer
+  def terminate(_reason, state) do
+    DispatcherRegistry.unregister(state.handler, state.worker_index)
+    QUIC.Config.destroy(state.config_ptr)
+    QUIC.PacketBuilder.destroy(state.packet_builder)
+    :ok
+  end
+
+  defp handle_received_packet(
+         {peer, packet, scid, dcid, token, version, packet_type, is_version_supported, _ecn},
+         state
+       ) do
     address = Address.from_rust_peer(peer)
     Tracer.trace(__MODULE__, state.trace_id, "@received: #{address}")
 
@@ -114,16 +131,6 @@ defmodule Requiem.DispatcherWorker do
       is_version_supported,
       state
     )
-
-    {:noreply, state}
-  end
-
-  @impl GenServer
-  def terminate(_reason, state) do
-    DispatcherRegistry.unregister(state.handler, state.worker_index)
-    QUIC.Config.destroy(state.config_ptr)
-    QUIC.PacketBuilder.destroy(state.packet_builder)
-    :ok
   end
 
   defp process_packet(address, _packet, scid, dcid, _token, _version, :initial, false, state) do
@@ -159,6 +166,7 @@ defmodule Requiem.DispatcherWorker do
       handler: Keyword.fetch!(opts, :handler),
       worker_index: Keyword.fetch!(opts, :worker_index),
       number_of_sockets: Keyword.fetch!(opts, :number_of_sockets),
+      socket_ptr: Keyword.fetch!(opts, :socket_ptr),
       token_secret: Keyword.fetch!(opts, :token_secret),
       conn_id_secret: Keyword.fetch!(opts, :conn_id_secret),
       allow_address_routing: Keyword.fetch!(opts, :allow_address_routing),
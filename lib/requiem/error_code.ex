@@ -12,6 +12,7 @@ defmodule Requiem.ErrorCode do
           | :version_negotiation_error
           | :protocol_violation
           | :unsolicited_path_response
+          | :application_error
   # | :frame_error
 
   @code_map %{
@@ -26,7 +27,8 @@ defmodule Requiem.ErrorCode do
     transport_parameter_error: 0x8,
     version_negotiation_error: 0x9,
     protocol_violation: 0xA,
-    unsolicited_path_response: 0xB
+    unsolicited_path_response: 0xB,
+    application_error: 0xC
   }
 
   @spec to_integer(error_code) :: non_neg_integer
@@ -3,10 +3,62 @@ defmodule Requiem.QUIC do
 
   @web_transport_alpn "wq-vvv-01"
 
+  @doc """
+  Sends quiche's internal log lines at or above `level` to `pid` as
+  `{:__quiche_log__, level, line}`. Lines over a rate limit are dropped and
+  counted in a later warning. Only one pid receives them at a time.
+  """
+  @spec enable_debug_logging(pid, :error | :warn | :info | :debug | :trace) ::
+          :ok | {:error, :bad_format}
+  def enable_debug_logging(pid, level) do
+    Requiem.QUIC.NIF.quic_enable_debug_logging(pid, level)
+  end
+
+  @spec disable_debug_logging() :: :ok
+  def disable_debug_logging() do
+    Requiem.QUIC.NIF.quic_disable_debug_logging()
+  end
+
+  @doc """
+  Counters of what the NIF did for `handler`'s connections, summed over its
+  dispatcher workers: connections accepted, live connections, packets fed to
+  quiche and drained from it, stream bytes and datagrams delivered, messages
+  sent to Elixir, and `recv_errors`, a map of quiche error to count.
+  Counting is lock-free, so it's always on.
+  """
+  @spec stats(module) ::
+          {:ok,
+           %{
+             accepted: non_neg_integer,
+             live_connections: non_neg_integer,
+             packets_received: non_neg_integer,
+             packets_drained: non_neg_integer,
+             stream_bytes: non_neg_integer,
+             dgrams: non_neg_integer,
+             messages: non_neg_integer,
+             recv_errors: %{atom => non_neg_integer}
+           }}
+          | {:error, :not_found | :bad_format}
+  def stats(handler) do
+    Requiem.QUIC.NIF.quic_stats(handler)
+  end
+
+  @doc """
+  Zeroes `handler`'s counters, except `live_connections`.
+  """
+  @spec stats_reset(module) :: :ok | {:error, :not_found | :bad_format}
+  def stats_reset(handler) do
+    Requiem.QUIC.NIF.quic_stats_reset(handler)
+  end
+
   @spec init_config(module, integer) :: no_return
   def init_config(handler, ptr) do
     is_web_transport = Config.get(handler, :web_transport)
 
+    if Requiem.QUIC.Config.set_stats_module(ptr, handler) != :ok do
+      raise "<Requiem.QUIC> Requiem.QUIC.set_stats_module failed"
+    end
+
     cert_chain = Config.get(handler, :cert_chain)
 
     if cert_chain != nil do
@@ -58,6 +110,15 @@ defmodule Requiem.QUIC do
       end
     end
 
+    # implies verify_peer, and refuses clients that don't send a certificate
+    require_peer_cert = Config.get(handler, :require_peer_cert)
+
+    if require_peer_cert != nil do
+      if Requiem.QUIC.Config.require_peer_cert(ptr, require_peer_cert) != :ok do
+        raise "<Requiem.QUIC> Requiem.QUIC.require_peer_cert failed"
+      end
+    end
+
     grease = Config.get(handler, :grease)
 
     if grease != nil do
@@ -74,6 +135,24 @@ defmodule Requiem.QUIC do
       end
     end
 
+    # 48 bytes, shared by every node that should resume the others' sessions
+    ticket_key = Config.get(handler, :ticket_key)
+
+    if ticket_key != nil do
+      if Requiem.QUIC.Config.set_ticket_key(ptr, ticket_key) != :ok do
+        raise "<Requiem.QUIC> Requiem.QUIC.set_ticket_key failed"
+      end
+    end
+
+    # exposes TLS secrets, so only turned on when a file is configured
+    keylog_file = Config.get(handler, :keylog_file)
+
+    if keylog_file != nil do
+      if Requiem.QUIC.Config.log_keys(ptr) != :ok do
+        raise "<Requiem.QUIC> Requiem.QUIC.log_keys failed"
+      end
+    end
+
     if is_web_transport do
       Requiem.QUIC.Config.set_application_protos(ptr, [@web_transport_alpn])
     else
@@ -104,6 +183,25 @@ defmodule Requiem.QUIC do
       end
     end
 
+    # overrides max_udp_payload_size for one direction
+    max_recv_udp_payload_size = Config.get(handler, :max_recv_udp_payload_size)
+
+    if max_recv_udp_payload_size != nil do
+      if Requiem.QUIC.Config.set_max_recv_udp_payload_size(ptr, max_recv_udp_payload_size) !=
+           :ok do
+        raise "<Requiem.QUIC> Requiem.QUIC.max_recv_udp_payload_size failed"
+      end
+    end
+
+    max_send_udp_payload_size = Config.get(handler, :max_send_udp_payload_size)
+
+    if max_send_udp_payload_size != nil do
+      if Requiem.QUIC.Config.set_max_send_udp_payload_size(ptr, max_send_udp_payload_size) !=
+           :ok do
+        raise "<Requiem.QUIC> Requiem.QUIC.max_send_udp_payload_size failed"
+      end
+    end
+
     # default is 0
     initial_max_data = Config.get(handler, :initial_max_data)
 
@@ -201,13 +299,22 @@ defmodule Requiem.QUIC do
       end
     end
 
-    # default is "reno"
+    # default is :cubic, validated by Requiem.Config at boot
     cc_algorithm_name = Config.get(handler, :cc_algorithm_name)
 
-    if cc_algorithm_name != nil do
-      if Requiem.QUIC.Config.set_cc_algorithm_name(ptr, cc_algorithm_name) != :ok do
-        raise "<Requiem.QUIC> Requiem.QUIC.set_cc_algorithm_name failed"
-      end
+    cond do
+      cc_algorithm_name == nil ->
+        :ok
+
+      is_atom(cc_algorithm_name) ->
+        if Requiem.QUIC.Config.set_cc_algorithm(ptr, cc_algorithm_name) != :ok do
+          raise "<Requiem.QUIC> Requiem.QUIC.set_cc_algorithm failed"
+        end
+
+      true ->
+        if Requiem.QUIC.Config.set_cc_algorithm_name(ptr, cc_algorithm_name) != :ok do
+          raise "<Requiem.QUIC> Requiem.QUIC.set_cc_algorithm_name failed"
+        end
     end
 
     # default is false
@@ -229,5 +336,23 @@ defmodule Requiem.QUIC do
         raise "<Requiem.QUIC> Requiem.QUIC.enable_dgram failed"
       end
     end
+
+    # default is 24MB
+    max_connection_window = Config.get(handler, :max_connection_window)
+
+    if max_connection_window != nil do
+      if Requiem.QUIC.Config.set_max_connection_window(ptr, max_connection_window) != :ok do
+        raise "<Requiem.QUIC> Requiem.QUIC.set_max_connection_window failed"
+      end
+    end
+
+    # default is 16MB
+    max_stream_window = Config.get(handler, :max_stream_window)
+
+    if max_stream_window != nil do
+      if Requiem.QUIC.Config.set_max_stream_window(ptr, max_stream_window) != :ok do
+        raise "<Requiem.QUIC> Requiem.QUIC.set_max_stream_window failed"
+      end
+    end
   end
 end
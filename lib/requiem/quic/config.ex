@@ -1,20 +1,6 @@
 defmodule Requiem.QUIC.Config do
   alias Requiem.QUIC.NIF
 
-  defmodule ALPN do
-    @spec encode_list(list) :: binary
-    def encode_list(list) do
-      list
-      |> Enum.map(&encode/1)
-      |> Enum.reduce(<<>>, fn x, acc -> <<acc::binary, x::binary>> end)
-    end
-
-    @spec encode(String.t()) :: binary
-    def encode(proto) do
-      <<byte_size(proto)::8, proto::binary>>
-    end
-  end
-
   @spec new() ::
           {:ok, integer} | {:error, :system_error | :not_found}
   def new() do
@@ -27,25 +13,31 @@ defmodule Requiem.QUIC.Config do
     NIF.config_destroy(ptr)
   end
 
+  @spec dump(integer) :: {:ok, map}
+  def dump(ptr) do
+    NIF.config_dump(ptr)
+  end
+
   @spec load_cert_chain_from_pem_file(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def load_cert_chain_from_pem_file(ptr, file) do
     NIF.config_load_cert_chain_from_pem_file(ptr, file)
   end
 
-  @spec load_priv_key_from_pem_file(integer, binary) :: :ok | {:error, :system_error | :not_found}
+  @spec load_priv_key_from_pem_file(integer, binary) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def load_priv_key_from_pem_file(ptr, file) do
     NIF.config_load_priv_key_from_pem_file(ptr, file)
   end
 
   @spec load_verify_locations_from_file(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def load_verify_locations_from_file(ptr, file) do
     NIF.config_load_verify_locations_from_file(ptr, file)
   end
 
   @spec load_verify_locations_from_directory(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def load_verify_locations_from_directory(ptr, dir) do
     NIF.config_load_verify_locations_from_directory(ptr, dir)
   end
@@ -55,6 +47,11 @@ defmodule Requiem.QUIC.Config do
     NIF.config_verify_peer(ptr, verify)
   end
 
+  @spec require_peer_cert(integer, boolean) :: :ok | {:error, :system_error | :not_found}
+  def require_peer_cert(ptr, require) do
+    NIF.config_require_peer_cert(ptr, require)
+  end
+
   @spec grease(integer, boolean) :: :ok | {:error, :system_error | :not_found}
   def grease(ptr, grease) do
     NIF.config_grease(ptr, grease)
@@ -65,9 +62,21 @@ defmodule Requiem.QUIC.Config do
     NIF.config_enable_early_data(ptr)
   end
 
-  @spec set_application_protos(integer, list) :: :ok | {:error, :system_error | :not_found}
+  @spec set_ticket_key(integer, binary) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
+  def set_ticket_key(ptr, key) do
+    NIF.config_set_ticket_key(ptr, key)
+  end
+
+  @spec log_keys(integer) :: :ok | {:error, :system_error | :not_found}
+  def log_keys(ptr) do
+    NIF.config_log_keys(ptr)
+  end
+
+  @spec set_application_protos(integer, [binary]) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def set_application_protos(ptr, protos) do
-    NIF.config_set_application_protos(ptr, ALPN.encode_list(protos))
+    NIF.config_set_application_protos(ptr, protos)
   end
 
   @spec set_max_idle_timeout(integer, non_neg_integer) ::
@@ -82,6 +91,18 @@ defmodule Requiem.QUIC.Config do
     NIF.config_set_max_udp_payload_size(ptr, v)
   end
 
+  @spec set_max_recv_udp_payload_size(integer, non_neg_integer) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
+  def set_max_recv_udp_payload_size(ptr, v) do
+    NIF.config_set_max_recv_udp_payload_size(ptr, v)
+  end
+
+  @spec set_max_send_udp_payload_size(integer, non_neg_integer) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
+  def set_max_send_udp_payload_size(ptr, v) do
+    NIF.config_set_max_send_udp_payload_size(ptr, v)
+  end
+
   @spec set_initial_max_data(integer, non_neg_integer) ::
           :ok | {:error, :system_error | :not_found}
   def set_initial_max_data(ptr, v) do
@@ -135,19 +156,50 @@ defmodule Requiem.QUIC.Config do
     NIF.config_set_disable_active_migration(ptr, v)
   end
 
-  @spec set_cc_algorithm_name(integer, binary) :: :ok | {:error, :system_error | :not_found}
+  @spec set_cc_algorithm_name(integer, binary) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def set_cc_algorithm_name(ptr, name) do
     NIF.config_set_cc_algorithm_name(ptr, name)
   end
 
+  @spec set_cc_algorithm(integer, atom) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
+  def set_cc_algorithm(ptr, algo) do
+    NIF.config_set_cc_algorithm(ptr, algo)
+  end
+
+  @spec cc_available_algorithms() :: [atom]
+  def cc_available_algorithms() do
+    NIF.config_cc_available_algorithms()
+  end
+
   @spec enable_hystart(integer, boolean) :: :ok | {:error, :system_error | :not_found}
   def enable_hystart(ptr, v) do
     NIF.config_enable_hystart(ptr, v)
   end
 
   @spec enable_dgram(integer, boolean, non_neg_integer, non_neg_integer) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def enable_dgram(ptr, enabled, recv_queue_len, send_queue_len) do
     NIF.config_enable_dgram(ptr, enabled, recv_queue_len, send_queue_len)
   end
+
+  @spec set_max_connection_window(integer, non_neg_integer) ::
+          :ok | {:error, :system_error | :not_found}
+  def set_max_connection_window(ptr, v) do
+    NIF.config_set_max_connection_window(ptr, v)
+  end
+
+  @spec set_max_stream_window(integer, non_neg_integer) ::
+          :ok | {:error, :system_error | :not_found}
+  def set_max_stream_window(ptr, v) do
+    NIF.config_set_max_stream_window(ptr, v)
+  end
+
+  # connections made from this config from now on count towards
+  # Requiem.QUIC.stats(module)
+  @spec set_stats_module(integer, module) :: :ok | {:error, :bad_format}
+  def set_stats_module(ptr, module) do
+    NIF.config_set_stats_module(ptr, module)
+  end
 end
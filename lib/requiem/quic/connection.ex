@@ -1,10 +1,76 @@
 defmodule Requiem.QUIC.Connection do
+  alias Requiem.ErrorCode
   alias Requiem.QUIC.NIF
 
-  @spec accept(integer, binary, binary, term, pid, non_neg_integer) ::
-          {:ok, term} | {:error, :system_error | :not_found}
-  def accept(config_ptr, scid, odcid, peer, sender_pid, stream_buf_size) do
-    NIF.connection_accept(config_ptr, scid, odcid, peer, sender_pid, stream_buf_size)
+  # Pass an empty odcid to accept without a preceding retry. The client's
+  # address isn't validated then, so this is meant for trusted networks.
+  @spec accept(
+          integer,
+          binary,
+          binary,
+          term,
+          pid,
+          non_neg_integer,
+          non_neg_integer,
+          boolean
+        ) ::
+          {:ok, term} | {:error, NIF.quic_error() | :bad_format}
+  def accept(
+        config_ptr,
+        scid,
+        odcid,
+        peer,
+        sender_pid,
+        stream_buf_size,
+        max_udp_payload_size,
+        pull_streams \\ false
+      ) do
+    NIF.connection_accept(
+      config_ptr,
+      scid,
+      odcid,
+      peer,
+      sender_pid,
+      stream_buf_size,
+      max_udp_payload_size,
+      pull_streams
+    )
+  end
+
+  @spec connect(
+          integer,
+          binary,
+          binary,
+          term,
+          pid,
+          non_neg_integer,
+          non_neg_integer,
+          binary | nil,
+          boolean
+        ) ::
+          {:ok, term} | {:error, NIF.quic_error() | :bad_format}
+  def connect(
+        config_ptr,
+        server_name,
+        scid,
+        peer,
+        sender_pid,
+        stream_buf_size,
+        max_udp_payload_size,
+        session \\ nil,
+        pull_streams \\ false
+      ) do
+    NIF.connection_connect(
+      config_ptr,
+      server_name,
+      scid,
+      peer,
+      sender_pid,
+      stream_buf_size,
+      max_udp_payload_size,
+      pull_streams,
+      session || ""
+    )
   end
 
   @spec destroy(integer) ::
@@ -13,8 +79,16 @@ defmodule Requiem.QUIC.Connection do
     NIF.connection_destroy(conn)
   end
 
-  @spec close(integer, boolean, non_neg_integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  # :ok when the connection closed right away, having received nothing, in
+  # which case the caller gets {:__closed__, is_app, code, reason} now;
+  # {:ok, timeout} once CONNECTION_CLOSE went out, with {:__closed__, ...}
+  # coming from on_timeout/1 when the connection is done closing
+  @spec close(integer, boolean, non_neg_integer | ErrorCode.error_code(), binary) ::
+          :ok | {:ok, timeout} | {:error, NIF.quic_error()}
+  def close(conn, app, err, reason) when is_atom(err) do
+    close(conn, app, ErrorCode.to_integer(err), reason)
+  end
+
   def close(conn, app, err, reason) do
     NIF.connection_close(conn, app, err, reason)
   end
@@ -24,26 +98,200 @@ defmodule Requiem.QUIC.Connection do
     NIF.connection_is_closed(conn)
   end
 
-  @spec dgram_send(integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @spec is_draining?(integer) :: boolean
+  def is_draining?(conn) do
+    NIF.connection_is_draining(conn)
+  end
+
+  @spec is_established?(integer) :: boolean
+  def is_established?(conn) do
+    NIF.connection_is_established(conn)
+  end
+
+  @spec is_in_early_data?(integer) :: boolean
+  def is_in_early_data?(conn) do
+    NIF.connection_is_in_early_data(conn)
+  end
+
+  @spec is_resumed?(integer) :: boolean
+  def is_resumed?(conn) do
+    NIF.connection_is_resumed(conn)
+  end
+
+  @spec is_early_data_accepted?(integer) :: {:ok, boolean} | {:error, :not_ready}
+  def is_early_data_accepted?(conn) do
+    NIF.connection_is_early_data_accepted(conn)
+  end
+
+  @spec stats(integer) :: {:ok, %{atom => non_neg_integer}}
+  def stats(conn) do
+    NIF.connection_stats(conn)
+  end
+
+  @spec application_proto(integer) :: {:ok, binary} | {:error, :not_ready}
+  def application_proto(conn) do
+    NIF.connection_application_proto(conn)
+  end
+
+  @spec server_name(integer) :: {:ok, binary} | {:error, :not_ready | :not_found}
+  def server_name(conn) do
+    NIF.connection_server_name(conn)
+  end
+
+  @spec set_keylog_path(integer, binary) ::
+          :ok | {:error, :not_found | :eacces | :system_error | :bad_format}
+  def set_keylog_path(conn, path) do
+    NIF.connection_set_keylog_path(conn, path)
+  end
+
+  @spec trace_id(integer) :: binary
+  def trace_id(conn) do
+    NIF.connection_trace_id(conn)
+  end
+
+  @spec source_id(integer) :: binary
+  def source_id(conn) do
+    NIF.connection_source_id(conn)
+  end
+
+  @spec destination_id(integer) :: binary
+  def destination_id(conn) do
+    NIF.connection_destination_id(conn)
+  end
+
+  @spec session(integer) :: {:ok, binary} | {:error, :not_found}
+  def session(conn) do
+    NIF.connection_session(conn)
+  end
+
+  @spec peer_cert(integer) :: {:ok, binary} | {:error, :not_found}
+  def peer_cert(conn) do
+    NIF.connection_peer_cert(conn)
+  end
+
+  # leaf first; quiche 0.12 only exposes the leaf, so the list holds at most one
+  @spec peer_cert_chain(integer) :: {:ok, [binary]}
+  def peer_cert_chain(conn) do
+    NIF.connection_peer_cert_chain(conn)
+  end
+
+  @spec dgram_send(integer, NIF.segments()) ::
+          {:ok, timeout}
+          | {:error,
+             NIF.quic_error() | :already_closed | :would_block | :not_negotiated | :bad_format}
   def dgram_send(conn, data) do
     NIF.connection_dgram_send(conn, data)
   end
 
-  @spec stream_send(integer, non_neg_integer, binary, boolean) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @spec max_send_udp_payload_size(integer) :: non_neg_integer
+  def max_send_udp_payload_size(conn) do
+    NIF.connection_max_send_udp_payload_size(conn)
+  end
+
+  @spec dgram_max_writable_len(integer) :: {:ok, non_neg_integer} | {:error, :not_negotiated}
+  def dgram_max_writable_len(conn) do
+    NIF.connection_dgram_max_writable_len(conn)
+  end
+
+  @spec dgram_send_queue_len(integer) :: non_neg_integer
+  def dgram_send_queue_len(conn) do
+    NIF.connection_dgram_send_queue_len(conn)
+  end
+
+  @spec dgram_send_queue_byte_size(integer) :: non_neg_integer
+  def dgram_send_queue_byte_size(conn) do
+    NIF.connection_dgram_send_queue_byte_size(conn)
+  end
+
+  @spec dgram_recv_queue_len(integer) :: non_neg_integer
+  def dgram_recv_queue_len(conn) do
+    NIF.connection_dgram_recv_queue_len(conn)
+  end
+
+  @spec dgram_purge_outgoing(integer, binary) :: :ok
+  def dgram_purge_outgoing(conn, prefix) do
+    NIF.connection_dgram_purge_outgoing(conn, prefix)
+  end
+
+  @spec stream_send(integer, non_neg_integer, NIF.segments(), boolean) ::
+          {:ok | :partial, non_neg_integer, timeout}
+          | {:error, NIF.quic_error() | :already_closed}
   def stream_send(conn, stream_id, data, fin) do
     NIF.connection_stream_send(conn, stream_id, data, fin)
   end
 
-  @spec on_packet(integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
-  def on_packet(conn, packet) do
-    NIF.connection_on_packet(self(), conn, packet)
+  @spec stream_recv(integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary, boolean}
+          | {:error, NIF.quic_error() | :already_closed | :would_block}
+  def stream_recv(conn, stream_id, max_len) do
+    NIF.connection_stream_recv(conn, stream_id, max_len)
+  end
+
+  @spec stream_priority(integer, non_neg_integer, non_neg_integer, boolean) ::
+          :ok | {:error, NIF.quic_error() | :already_closed | :bad_format}
+  def stream_priority(conn, stream_id, urgency, incremental) do
+    NIF.connection_stream_priority(conn, stream_id, urgency, incremental)
+  end
+
+  @spec readable(integer) :: [non_neg_integer]
+  def readable(conn) do
+    NIF.connection_readable(conn)
+  end
+
+  @spec writable(integer) :: [non_neg_integer]
+  def writable(conn) do
+    NIF.connection_writable(conn)
+  end
+
+  @spec peer_streams_left_bidi(integer) :: non_neg_integer
+  def peer_streams_left_bidi(conn) do
+    NIF.connection_peer_streams_left_bidi(conn)
+  end
+
+  @spec peer_streams_left_uni(integer) :: non_neg_integer
+  def peer_streams_left_uni(conn) do
+    NIF.connection_peer_streams_left_uni(conn)
+  end
+
+  @spec on_packet(integer, term, binary) ::
+          {:ok, timeout} | {:error, NIF.quic_error() | :already_closed}
+  def on_packet(conn, peer, packet) do
+    NIF.connection_on_packet(self(), conn, peer, packet)
+  end
+
+  @spec on_packets(integer, term, [binary]) ::
+          {:ok, timeout, [{non_neg_integer, NIF.quic_error() | :already_closed}]}
+          | {:error, NIF.quic_error() | :already_closed}
+  def on_packets(conn, peer, packets) do
+    NIF.connection_on_packets(self(), conn, peer, packets)
+  end
+
+  # Lets on_packet_by_dcid/3 feed conn packets addressed to dcid from any
+  # process, with the messages they cause going to the caller of register/2
+  # as they would from on_packet/3. destroy/1 unregisters every dcid of conn.
+  @spec register(integer, binary) :: :ok | {:error, :bad_format | :already_exists}
+  def register(conn, dcid) do
+    NIF.connection_register(self(), conn, dcid)
+  end
+
+  @spec unregister(binary) :: :ok | {:error, :not_found}
+  def unregister(dcid) do
+    NIF.connection_unregister(dcid)
+  end
+
+  # The returned timeout is the caller's to pass on, the registered process
+  # doesn't hear about it
+  @spec on_packet_by_dcid(binary, term, binary) ::
+          {:ok, timeout} | {:error, NIF.quic_error() | :already_closed | :not_found}
+  def on_packet_by_dcid(dcid, peer, packet) do
+    NIF.connection_on_packet_by_dcid(dcid, peer, packet)
   end
 
-  @spec on_timeout(integer) :: {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @spec on_timeout(integer) ::
+          {:ok, :active | :draining, timeout}
+          | {:ok, :closed}
+          | {:error, NIF.quic_error() | :already_closed}
   def on_timeout(conn) do
-    NIF.connection_on_timeout(conn)
+    NIF.connection_on_timeout(self(), conn)
   end
 end
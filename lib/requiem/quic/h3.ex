@@ -0,0 +1,90 @@
+defmodule Requiem.QUIC.H3 do
+  alias Requiem.QUIC.NIF
+
+  # HTTP/3 over a connection, with QPACK and framing done by quiche. The NIF
+  # has to be built with its h3 feature, e.g.
+  #   config :requiem, Requiem.QUIC.NIF, features: ["h3"]
+  # otherwise everything here returns {:error, :not_supported}.
+  #
+  # Once attached, the connection's owner gets
+  #   {:__h3_headers__, stream_id, [{name, value}]}
+  #   {:__h3_data__, stream_id}, then recv_body/3 until :would_block
+  #   {:__h3_finished__, stream_id}
+  #   {:__h3_reset__, stream_id, error_code}
+  #   {:__h3_goaway__, id}
+  # in place of __stream_recv__ and __stream_readable__.
+
+  @spec config_new() :: {:ok, integer} | {:error, :not_supported}
+  def config_new() do
+    NIF.h3_config_new()
+  end
+
+  @spec config_destroy(integer) :: :ok | {:error, :not_supported}
+  def config_destroy(config_ptr) do
+    NIF.h3_config_destroy(config_ptr)
+  end
+
+  @spec config_set_max_field_section_size(integer, non_neg_integer) ::
+          :ok | {:error, :not_supported}
+  def config_set_max_field_section_size(config_ptr, size) do
+    NIF.h3_config_set_max_field_section_size(config_ptr, size)
+  end
+
+  @spec config_set_qpack_max_table_capacity(integer, non_neg_integer) ::
+          :ok | {:error, :not_supported}
+  def config_set_qpack_max_table_capacity(config_ptr, capacity) do
+    NIF.h3_config_set_qpack_max_table_capacity(config_ptr, capacity)
+  end
+
+  @spec config_set_qpack_blocked_streams(integer, non_neg_integer) ::
+          :ok | {:error, :not_supported}
+  def config_set_qpack_blocked_streams(config_ptr, streams) do
+    NIF.h3_config_set_qpack_blocked_streams(config_ptr, streams)
+  end
+
+  # any time after accept/connect; before the handshake gets far enough the
+  # connection holds on to the config and attaches as soon as it can, so the
+  # peer's control stream is never seen as plain stream data. A config that
+  # quiche refuses then closes the connection with H3_INTERNAL_ERROR
+  @spec attach(integer, integer) ::
+          {:ok, timeout}
+          | {:error,
+             NIF.h3_error()
+             | :already_closed
+             | :already_exists
+             | :not_supported}
+  def attach(conn, config_ptr) do
+    NIF.h3_conn_new_with_transport(conn, config_ptr)
+  end
+
+  @spec send_request(integer, NIF.h3_headers(), boolean) ::
+          {:ok, non_neg_integer, timeout}
+          | {:error, NIF.h3_error() | :already_closed | :bad_state | :not_supported}
+  def send_request(conn, headers, fin) do
+    NIF.h3_send_request(conn, headers, fin)
+  end
+
+  @spec send_response(integer, non_neg_integer, NIF.h3_headers(), boolean) ::
+          {:ok, timeout}
+          | {:error, NIF.h3_error() | :already_closed | :bad_state | :not_supported}
+  def send_response(conn, stream_id, headers, fin) do
+    NIF.h3_send_response(conn, stream_id, headers, fin)
+  end
+
+  # :partial as with Connection.stream_send/4, __stream_writable__ tells
+  # when to send the rest
+  @spec send_body(integer, non_neg_integer, binary, boolean) ::
+          {:ok | :partial, non_neg_integer, timeout}
+          | {:error, NIF.h3_error() | :already_closed | :bad_state | :not_supported}
+  def send_body(conn, stream_id, data, fin) do
+    NIF.h3_send_body(conn, stream_id, data, fin)
+  end
+
+  @spec recv_body(integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary}
+          | {:error,
+             NIF.h3_error() | :already_closed | :bad_state | :would_block | :not_supported}
+  def recv_body(conn, stream_id, max_len) do
+    NIF.h3_recv_body(conn, stream_id, max_len)
+  end
+end
@@ -4,6 +4,27 @@ defmodule Requiem.QUIC.NIF do
     crate: "requiem_nif",
     mode: :release
 
+  @type quic_error ::
+          :done
+          | :buffer_too_short
+          | :unknown_version
+          | :invalid_frame
+          | :invalid_packet
+          | :invalid_state
+          | :invalid_stream_state
+          | :invalid_transport_param
+          | :crypto_fail
+          | :tls_fail
+          | :flow_control
+          | :stream_limit
+          | :stream_stopped
+          | :stream_reset
+          | :final_size
+          | :congestion_control
+
+  # a binary or a list of binaries nested at most one level
+  @type segments :: binary | [binary | [binary]]
+
   @spec config_new() ::
           {:ok, integer} | {:error, :system_error | :not_found}
   def config_new(), do: error()
@@ -12,33 +33,46 @@ defmodule Requiem.QUIC.NIF do
           :ok | {:error, :system_error | :not_found}
   def config_destroy(_ptr), do: error()
 
+  @spec config_dump(integer) :: {:ok, map}
+  def config_dump(_ptr), do: error()
+
   @spec config_load_cert_chain_from_pem_file(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def config_load_cert_chain_from_pem_file(_ptr, _file), do: error()
 
   @spec config_load_priv_key_from_pem_file(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def config_load_priv_key_from_pem_file(_ptr, _file), do: error()
 
   @spec config_load_verify_locations_from_file(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def config_load_verify_locations_from_file(_ptr, _file), do: error()
 
   @spec config_load_verify_locations_from_directory(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def config_load_verify_locations_from_directory(_ptr, _dir), do: error()
 
   @spec config_verify_peer(integer, boolean) :: :ok | {:error, :system_error | :not_found}
   def config_verify_peer(_ptr, _verify), do: error()
 
+  @spec config_require_peer_cert(integer, boolean) :: :ok | {:error, :system_error | :not_found}
+  def config_require_peer_cert(_ptr, _require), do: error()
+
   @spec config_grease(integer, boolean) :: :ok | {:error, :system_error | :not_found}
   def config_grease(_ptr, _grease), do: error()
 
   @spec config_enable_early_data(integer) :: :ok | {:error, :system_error | :not_found}
   def config_enable_early_data(_ptr), do: error()
 
-  @spec config_set_application_protos(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+  @spec config_set_ticket_key(integer, binary) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
+  def config_set_ticket_key(_ptr, _key), do: error()
+
+  @spec config_log_keys(integer) :: :ok | {:error, :system_error | :not_found}
+  def config_log_keys(_ptr), do: error()
+
+  @spec config_set_application_protos(integer, [binary]) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def config_set_application_protos(_ptr, _protos), do: error()
 
   @spec config_set_max_idle_timeout(integer, non_neg_integer) ::
@@ -49,6 +83,14 @@ defmodule Requiem.QUIC.NIF do
           :ok | {:error, :system_error | :not_found}
   def config_set_max_udp_payload_size(_ptr, _v), do: error()
 
+  @spec config_set_max_recv_udp_payload_size(integer, non_neg_integer) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
+  def config_set_max_recv_udp_payload_size(_ptr, _v), do: error()
+
+  @spec config_set_max_send_udp_payload_size(integer, non_neg_integer) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
+  def config_set_max_send_udp_payload_size(_ptr, _v), do: error()
+
   @spec config_set_initial_max_data(integer, non_neg_integer) ::
           :ok | {:error, :system_error | :not_found}
   def config_set_initial_max_data(_ptr, _v), do: error()
@@ -86,48 +128,293 @@ defmodule Requiem.QUIC.NIF do
   def config_set_disable_active_migration(_ptr, _v), do: error()
 
   @spec config_set_cc_algorithm_name(integer, binary) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def config_set_cc_algorithm_name(_ptr, _name), do: error()
 
+  @spec config_set_cc_algorithm(integer, atom) ::
+          :ok | {:error, :system_error | :not_found | :bad_format}
+  def config_set_cc_algorithm(_ptr, _algo), do: error()
+
+  @spec config_cc_available_algorithms() :: [atom]
+  def config_cc_available_algorithms(), do: error()
+
   @spec config_enable_hystart(integer, boolean) :: :ok | {:error, :system_error | :not_found}
   def config_enable_hystart(_ptr, _v), do: error()
 
   @spec config_enable_dgram(integer, boolean, non_neg_integer, non_neg_integer) ::
-          :ok | {:error, :system_error | :not_found}
+          :ok | {:error, :system_error | :not_found | :bad_format}
   def config_enable_dgram(_ptr, _enabled, _recv_queue_len, _send_queue_len), do: error()
 
-  @spec connection_accept(integer, binary, binary, term, pid, non_neg_integer) ::
-          {:ok, integer} | {:error, :system_error | :not_found}
-  def connection_accept(_config_ptr, _scid, _odcid, _peer, _sender_pid, _stream_buf_size),
-    do: error()
+  @spec config_set_max_connection_window(integer, non_neg_integer) ::
+          :ok | {:error, :system_error | :not_found}
+  def config_set_max_connection_window(_ptr, _v), do: error()
+
+  @spec config_set_max_stream_window(integer, non_neg_integer) ::
+          :ok | {:error, :system_error | :not_found}
+  def config_set_max_stream_window(_ptr, _v), do: error()
+
+  @spec config_set_stats_module(integer, module) :: :ok | {:error, :bad_format}
+  def config_set_stats_module(_ptr, _module), do: error()
+
+  @spec connection_accept(
+          integer,
+          binary,
+          binary,
+          term,
+          pid,
+          non_neg_integer,
+          non_neg_integer,
+          boolean
+        ) ::
+          {:ok, integer} | {:error, quic_error | :bad_format}
+  def connection_accept(
+        _config_ptr,
+        _scid,
+        _odcid,
+        _peer,
+        _sender_pid,
+        _stream_buf_size,
+        _max_udp_payload_size,
+        _pull_streams
+      ),
+      do: error()
+
+  @spec connection_connect(
+          integer,
+          binary,
+          binary,
+          term,
+          pid,
+          non_neg_integer,
+          non_neg_integer,
+          boolean,
+          binary
+        ) ::
+          {:ok, integer} | {:error, quic_error | :bad_format}
+  def connection_connect(
+        _config_ptr,
+        _server_name,
+        _scid,
+        _peer,
+        _sender_pid,
+        _stream_buf_size,
+        _max_udp_payload_size,
+        _pull_streams,
+        _session
+      ),
+      do: error()
 
   @spec connection_destroy(integer) ::
           :ok | {:error, :system_error | :not_found}
   def connection_destroy(_conn_ptr), do: error()
 
   @spec connection_close(integer, boolean, non_neg_integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+          :ok | {:ok, timeout} | {:error, quic_error | :already_closed}
   def connection_close(_conn, _app, _err, _reason), do: error()
 
   @spec connection_is_closed(integer) :: boolean
   def connection_is_closed(_conn), do: error()
 
-  @spec connection_on_packet(pid, integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
-  def connection_on_packet(_pid, _conn, _packet), do: error()
+  @spec connection_is_draining(integer) :: boolean
+  def connection_is_draining(_conn), do: error()
+
+  @spec connection_is_established(integer) :: boolean
+  def connection_is_established(_conn), do: error()
+
+  @spec connection_is_in_early_data(integer) :: boolean
+  def connection_is_in_early_data(_conn), do: error()
+
+  @spec connection_is_resumed(integer) :: boolean
+  def connection_is_resumed(_conn), do: error()
+
+  @spec connection_is_early_data_accepted(integer) :: {:ok, boolean} | {:error, :not_ready}
+  def connection_is_early_data_accepted(_conn), do: error()
+
+  @spec connection_stats(integer) :: {:ok, %{atom => non_neg_integer}}
+  def connection_stats(_conn), do: error()
+
+  @spec connection_application_proto(integer) :: {:ok, binary} | {:error, :not_ready}
+  def connection_application_proto(_conn), do: error()
+
+  @spec connection_server_name(integer) :: {:ok, binary} | {:error, :not_ready | :not_found}
+  def connection_server_name(_conn), do: error()
 
-  @spec connection_on_timeout(integer) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
-  def connection_on_timeout(_conn), do: error()
+  @spec connection_set_keylog_path(integer, binary) ::
+          :ok | {:error, :not_found | :eacces | :system_error | :bad_format}
+  def connection_set_keylog_path(_conn, _path), do: error()
 
-  @spec connection_stream_send(integer, non_neg_integer, binary, boolean) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @spec connection_trace_id(integer) :: binary
+  def connection_trace_id(_conn), do: error()
+
+  @spec connection_source_id(integer) :: binary
+  def connection_source_id(_conn), do: error()
+
+  @spec connection_destination_id(integer) :: binary
+  def connection_destination_id(_conn), do: error()
+
+  @spec connection_session(integer) :: {:ok, binary} | {:error, :not_found}
+  def connection_session(_conn), do: error()
+
+  @spec connection_peer_cert(integer) :: {:ok, binary} | {:error, :not_found}
+  def connection_peer_cert(_conn), do: error()
+
+  @spec connection_peer_cert_chain(integer) :: {:ok, [binary]}
+  def connection_peer_cert_chain(_conn), do: error()
+
+  @spec connection_on_packet(pid, integer, term, binary) ::
+          {:ok, timeout} | {:error, quic_error | :already_closed}
+  def connection_on_packet(_pid, _conn, _peer, _packet), do: error()
+
+  @spec connection_on_packets(pid, integer, term, [binary]) ::
+          {:ok, timeout, [{non_neg_integer, quic_error | :already_closed}]}
+          | {:error, quic_error | :already_closed}
+  def connection_on_packets(_pid, _conn, _peer, _packets), do: error()
+
+  @spec connection_on_timeout(pid, integer) ::
+          {:ok, :active | :draining, timeout}
+          | {:ok, :closed}
+          | {:error, quic_error | :already_closed}
+  def connection_on_timeout(_pid, _conn), do: error()
+
+  @spec connection_stream_send(integer, non_neg_integer, segments, boolean) ::
+          {:ok | :partial, non_neg_integer, timeout}
+          | {:error, quic_error | :already_closed}
   def connection_stream_send(_conn, _stream_id, _data, _fin), do: error()
 
-  @spec connection_dgram_send(integer, binary) ::
-          {:ok, non_neg_integer} | {:error, :system_error | :already_closed}
+  @spec connection_stream_recv(integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary, boolean} | {:error, quic_error | :already_closed | :would_block}
+  def connection_stream_recv(_conn, _stream_id, _max_len), do: error()
+
+  @spec connection_stream_priority(integer, non_neg_integer, non_neg_integer, boolean) ::
+          :ok | {:error, quic_error | :already_closed | :bad_format}
+  def connection_stream_priority(_conn, _stream_id, _urgency, _incremental), do: error()
+
+  @spec connection_readable(integer) :: [non_neg_integer]
+  def connection_readable(_conn), do: error()
+
+  @spec connection_writable(integer) :: [non_neg_integer]
+  def connection_writable(_conn), do: error()
+
+  @spec connection_peer_streams_left_bidi(integer) :: non_neg_integer
+  def connection_peer_streams_left_bidi(_conn), do: error()
+
+  @spec connection_peer_streams_left_uni(integer) :: non_neg_integer
+  def connection_peer_streams_left_uni(_conn), do: error()
+
+  @spec connection_dgram_send(integer, segments) ::
+          {:ok, timeout}
+          | {:error,
+             quic_error | :already_closed | :would_block | :not_negotiated | :bad_format}
   def connection_dgram_send(_conn, _data), do: error()
 
+  @spec connection_max_send_udp_payload_size(integer) :: non_neg_integer
+  def connection_max_send_udp_payload_size(_conn), do: error()
+
+  @spec connection_dgram_max_writable_len(integer) ::
+          {:ok, non_neg_integer} | {:error, :not_negotiated}
+  def connection_dgram_max_writable_len(_conn), do: error()
+
+  @spec connection_dgram_send_queue_len(integer) :: non_neg_integer
+  def connection_dgram_send_queue_len(_conn), do: error()
+
+  @spec connection_dgram_send_queue_byte_size(integer) :: non_neg_integer
+  def connection_dgram_send_queue_byte_size(_conn), do: error()
+
+  @spec connection_dgram_recv_queue_len(integer) :: non_neg_integer
+  def connection_dgram_recv_queue_len(_conn), do: error()
+
+  @spec connection_dgram_purge_outgoing(integer, binary) :: :ok
+  def connection_dgram_purge_outgoing(_conn, _prefix), do: error()
+
+  @spec connection_register(pid, integer, binary) ::
+          :ok | {:error, :bad_format | :already_exists}
+  def connection_register(_pid, _conn, _dcid), do: error()
+
+  @spec connection_unregister(binary) :: :ok | {:error, :not_found}
+  def connection_unregister(_dcid), do: error()
+
+  @spec connection_on_packet_by_dcid(binary, term, binary) ::
+          {:ok, timeout} | {:error, quic_error | :already_closed | :not_found}
+  def connection_on_packet_by_dcid(_dcid, _peer, _packet), do: error()
+
+  @type h3_error ::
+          quic_error
+          | :internal_error
+          | :excessive_load
+          | :id_error
+          | :stream_creation_error
+          | :closed_critical_stream
+          | :missing_settings
+          | :frame_unexpected
+          | :frame_error
+          | :qpack_decompression_failed
+          | :stream_blocked
+          | :settings_error
+          | :request_rejected
+          | :request_cancelled
+          | :request_incomplete
+          | :message_error
+          | :connect_error
+          | :version_fallback
+
+  @type h3_headers :: [{binary, binary}]
+
+  @spec h3_config_new() :: {:ok, integer} | {:error, :not_supported}
+  def h3_config_new(), do: error()
+
+  @spec h3_config_destroy(integer) :: :ok | {:error, :not_supported}
+  def h3_config_destroy(_ptr), do: error()
+
+  @spec h3_config_set_max_field_section_size(integer, non_neg_integer) ::
+          :ok | {:error, :not_supported}
+  def h3_config_set_max_field_section_size(_ptr, _v), do: error()
+
+  @spec h3_config_set_qpack_max_table_capacity(integer, non_neg_integer) ::
+          :ok | {:error, :not_supported}
+  def h3_config_set_qpack_max_table_capacity(_ptr, _v), do: error()
+
+  @spec h3_config_set_qpack_blocked_streams(integer, non_neg_integer) ::
+          :ok | {:error, :not_supported}
+  def h3_config_set_qpack_blocked_streams(_ptr, _v), do: error()
+
+  @spec h3_conn_new_with_transport(integer, integer) ::
+          {:ok, timeout}
+          | {:error,
+             h3_error | :already_closed | :already_exists | :not_supported}
+  def h3_conn_new_with_transport(_conn, _h3_config), do: error()
+
+  @spec h3_send_request(integer, h3_headers, boolean) ::
+          {:ok, non_neg_integer, timeout}
+          | {:error, h3_error | :already_closed | :bad_state | :not_supported}
+  def h3_send_request(_conn, _headers, _fin), do: error()
+
+  @spec h3_send_response(integer, non_neg_integer, h3_headers, boolean) ::
+          {:ok, timeout} | {:error, h3_error | :already_closed | :bad_state | :not_supported}
+  def h3_send_response(_conn, _stream_id, _headers, _fin), do: error()
+
+  @spec h3_send_body(integer, non_neg_integer, binary, boolean) ::
+          {:ok | :partial, non_neg_integer, timeout}
+          | {:error, h3_error | :already_closed | :bad_state | :not_supported}
+  def h3_send_body(_conn, _stream_id, _data, _fin), do: error()
+
+  @spec h3_recv_body(integer, non_neg_integer, non_neg_integer) ::
+          {:ok, binary}
+          | {:error, h3_error | :already_closed | :bad_state | :would_block | :not_supported}
+  def h3_recv_body(_conn, _stream_id, _max_len), do: error()
+
+  @spec cid_generate(non_neg_integer) :: {:ok, binary} | {:error, :bad_format | :system_error}
+  def cid_generate(_len), do: error()
+
+  @spec cid_sign(binary, binary) :: {:ok, binary} | {:error, :bad_format | :system_error}
+  def cid_sign(_secret, _payload), do: error()
+
+  @spec cid_open(binary, binary) :: {:ok, binary} | {:error, :bad_format}
+  def cid_open(_secret, _cid), do: error()
+
+  @spec packet_parse_header(binary, non_neg_integer) ::
+          {:ok, map} | {:error, quic_error | :bad_format}
+  def packet_parse_header(_packet, _dcid_len), do: error()
+
   @spec packet_builder_new() ::
           {:ok, integer} | {:error, :system_error}
   def packet_builder_new(), do: error()
@@ -137,37 +424,116 @@ defmodule Requiem.QUIC.NIF do
   def packet_builder_destroy(_builder), do: error()
 
   @spec packet_builder_build_negotiate_version(integer, binary, binary) ::
-          {:ok, binary} | {:error, :system_error}
+          {:ok, binary} | {:error, quic_error | :bad_format}
   def packet_builder_build_negotiate_version(_builder, _scid, _dcid), do: error()
 
   @spec packet_builder_build_retry(integer, binary, binary, binary, binary, non_neg_integer) ::
-          {:ok, binary} | {:error, :system_error}
+          {:ok, binary} | {:error, quic_error | :bad_format}
   def packet_builder_build_retry(_builder, _scid, _dcid, _new_scid, _token, _version), do: error()
 
+  @spec quic_enable_debug_logging(pid, :error | :warn | :info | :debug | :trace) ::
+          :ok | {:error, :bad_format}
+  def quic_enable_debug_logging(_pid, _level), do: error()
+
+  @spec quic_disable_debug_logging() :: :ok
+  def quic_disable_debug_logging(), do: error()
+
+  @spec quic_stats(module) :: {:ok, map} | {:error, :not_found | :bad_format}
+  def quic_stats(_module), do: error()
+
+  @spec quic_stats_reset(module) :: :ok | {:error, :not_found | :bad_format}
+  def quic_stats_reset(_module), do: error()
+
   @spec cpu_num() ::
           integer | {:error, :system_error | :not_found}
   def cpu_num(), do: error()
 
   @spec socket_sender_get(integer, non_neg_integer) ::
-          {:ok, integer} | {:error, :system_error | :not_found}
+          {:ok, integer} | {:error, :system_error | :not_found | :bad_format}
   def socket_sender_get(_socket_ptr, _idx), do: error()
 
   @spec socket_sender_send(integer, term, binary) ::
           :ok | {:error, :system_error | :not_found}
   def socket_sender_send(_socket_ptr, _addr, _packet), do: error()
 
-  @spec socket_sender_destroy(integer) ::
+  @spec socket_sender_send_batch(integer, term, [binary]) ::
           :ok | {:error, :system_error | :not_found}
-  def socket_sender_destroy(_socket_ptr), do: error()
+  def socket_sender_send_batch(_socket_ptr, _addr, _packets), do: error()
+
+  @spec socket_sender_send_batch_to(integer, binary, :inet.port_number(), [binary]) ::
+          :ok | {:error, :bad_format}
+  def socket_sender_send_batch_to(_socket_ptr, _ip, _port, _packets), do: error()
 
-  @spec socket_new(integer, non_neg_integer, non_neg_integer) ::
-          {:ok, integer} | {:error, :system_error | :socket_error}
-  def socket_new(_num_node, _read_timeout, _write_timeout),
-    do: error()
+  @spec socket_sender_send_gso(integer, term, binary, pos_integer) ::
+          :ok | {:error, :bad_format | :not_supported}
+  def socket_sender_send_gso(_socket_ptr, _peer, _packet, _segment_size), do: error()
 
-  @spec socket_start(integer, binary, pid, [pid]) ::
+  @spec socket_sender_destroy(integer) ::
           :ok | {:error, :system_error | :not_found}
-  def socket_start(_ptr, _address, _pid, _target_pids), do: error()
+  def socket_sender_destroy(_socket_ptr), do: error()
+
+  @spec socket_new(
+          integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer
+        ) ::
+          {:ok, integer} | {:error, :system_error | :socket_error | :bad_format}
+  def socket_new(
+        _num_node,
+        _read_timeout,
+        _write_timeout,
+        _dcid_len,
+        _max_datagram_size,
+        _recv_batch_size
+      ),
+      do: error()
+
+  @spec socket_start(integer, binary | {binary, :inet.port_number()}, pid, [pid], keyword) ::
+          :ok
+          | {:error,
+             :system_error
+             | :not_found
+             | :bad_format
+             | :socket_error
+             | :eaddrinuse
+             | :eaddrnotavail
+             | :eacces
+             | :bad_state}
+  def socket_start(_ptr, _address, _pid, _target_pids, _options), do: error()
+
+  @spec socket_stop(integer) :: :ok | {:error, :bad_state}
+  def socket_stop(_ptr), do: error()
+
+  @spec socket_set_controlling_process(integer, pid) ::
+          :ok | {:error, :bad_state | :not_owner}
+  def socket_set_controlling_process(_ptr, _pid), do: error()
+
+  @spec socket_set_target_pid(integer, non_neg_integer, pid) ::
+          :ok | {:error, :bad_state | :bad_format}
+  def socket_set_target_pid(_ptr, _idx, _pid), do: error()
+
+  @spec socket_stats(integer) :: {:ok, map}
+  def socket_stats(_ptr), do: error()
+
+  @spec socket_stats_reset(integer) :: :ok
+  def socket_stats_reset(_ptr), do: error()
+
+  @spec socket_set_ecn(integer, :not_ect | :ect0 | :ect1) ::
+          :ok | {:error, :bad_format | :bad_state | :not_supported}
+  def socket_set_ecn(_ptr, _codepoint), do: error()
+
+  @spec socket_set_send_retries(integer, non_neg_integer) :: :ok | {:error, :bad_state}
+  def socket_set_send_retries(_ptr, _retries), do: error()
+
+  @spec socket_set_poll_interval(integer, pos_integer) ::
+          :ok | {:error, :bad_format | :socket_error}
+  def socket_set_poll_interval(_ptr, _interval), do: error()
+
+  @spec socket_gso_supported(integer) :: {:ok, boolean} | {:error, :bad_state}
+  def socket_gso_supported(_ptr), do: error()
 
   @spec socket_destroy(integer) ::
           :ok | {:error, :system_error | :not_found}
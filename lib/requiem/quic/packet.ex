@@ -12,13 +12,13 @@ defmodule Requiem.QUIC.PacketBuilder do
   end
 
   @spec build_negotiate_version(integer, binary, binary) ::
-          {:ok, binary} | {:error, :system_error}
+          {:ok, binary} | {:error, NIF.quic_error() | :bad_format}
   def build_negotiate_version(builder, scid, dcid) do
     NIF.packet_builder_build_negotiate_version(builder, scid, dcid)
   end
 
   @spec build_retry(integer, binary, binary, binary, binary, non_neg_integer) ::
-          {:ok, binary} | {:error, :system_error}
+          {:ok, binary} | {:error, NIF.quic_error() | :bad_format}
   def build_retry(builder, scid, dcid, new_scid, token, version) do
     NIF.packet_builder_build_retry(builder, scid, dcid, new_scid, token, version)
   end
@@ -0,0 +1,20 @@
+defmodule Requiem.QUIC.PacketHeader do
+  alias Requiem.QUIC.NIF
+
+  @type t :: %{
+          packet_type:
+            :initial | :handshake | :retry | :zero_rtt | :version_negotiation | :short,
+          form: :long | :short,
+          version: non_neg_integer,
+          dcid: binary,
+          scid: binary,
+          token: binary,
+          versions: [non_neg_integer] | nil
+        }
+
+  # dcid_len is only used for short headers, which don't carry it
+  @spec parse(binary, non_neg_integer) :: {:ok, t} | {:error, NIF.quic_error() | :bad_format}
+  def parse(packet, dcid_len) do
+    NIF.packet_parse_header(packet, dcid_len)
+  end
+end
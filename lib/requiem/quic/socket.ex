@@ -6,16 +6,179 @@ defmodule Requiem.QUIC.Socket do
     NIF.cpu_num()
   end
 
-  @spec new(integer, non_neg_integer, non_neg_integer) ::
-          {:ok, integer} | {:error, :system_error | :socket_error}
-  def new(num_node, read_timeout, write_timeout) do
-    NIF.socket_new(num_node, read_timeout, write_timeout)
+  # dcid_len is used for the dcid of short header packets, which don't carry
+  # its length, so it has to match the length of the ids the server issues.
+  # Datagrams larger than max_datagram_size (1200 to 65527) are dropped.
+  # Each receiver reads up to recv_batch_size (1 to 256) datagrams at once and
+  # hands them to the target pids as {:__packets__, [packet]}, one message per
+  # pid, where packet is
+  # {peer, packet, scid, dcid, token, version, packet_type, is_version_supported, ecn}
+  # and ecn is :not_ect, :ect0, :ect1, :ce, or nil where the OS doesn't say.
+  @spec new(
+          integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer,
+          non_neg_integer
+        ) ::
+          {:ok, integer} | {:error, :system_error | :socket_error | :bad_format}
+  def new(num_node, read_timeout, write_timeout, dcid_len, max_datagram_size, recv_batch_size) do
+    NIF.socket_new(
+      num_node,
+      read_timeout,
+      write_timeout,
+      dcid_len,
+      max_datagram_size,
+      recv_batch_size
+    )
   end
 
-  @spec start(integer, binary, non_neg_integer, pid, [pid]) ::
-          :ok | {:error, :system_error | :socket_error}
-  def start(socket_ptr, host, port, pid, target_pids) do
-    NIF.socket_start(socket_ptr, "#{host}:#{port}", pid, target_pids)
+  # host is a string or an :inet address tuple. opts:
+  #   v6_only: sets IPV6_V6ONLY on an IPv6 socket, false to serve IPv4 as well
+  #   normalize_v4_mapped: reports peers at ::ffff:a.b.c.d as a.b.c.d
+  #   peer_parts: puts {ip_binary, port} in place of the peer resource in
+  #     received packets and send errors, ready to use as a map key;
+  #     SocketSender still takes a resource (see address_from_string/1) or
+  #     send_batch_to/4
+  #   supervisor: a pid told {:__owner_down__} when the socket shuts itself
+  #     down, which it does once a message to pid or to a target pid finds
+  #     that process gone; stop/1 still has to be called before a restart
+  #   other_pid: gets {:__other_packet__, peer, data} for each datagram that
+  #     isn't QUIC (short ones, those with the first byte's 0x40 bit clear as
+  #     STUN, DTLS and RTP have it, and those that don't parse) instead of
+  #     it being dropped
+  #   other_prefix: also hands datagrams starting with it to other_pid
+  #   fallback_pid: gets {:__unparsed_packet__, peer, data} for each datagram
+  #     whose header can't be parsed, short ones included, instead of it
+  #     being dropped; takes those ahead of other_pid, which still gets the
+  #     ones its first-byte and prefix checks pick out
+  #   recv_error_policy: what a failed read does besides counting in
+  #     recv_errors; :ignore, :notify (the default) sends pid
+  #     {:socket_error, reason}, {:shutdown, n} notifies and shuts the socket
+  #     down after n failures in a row as if pid had gone
+  #   recv_buffer_size, send_buffer_size: SO_RCVBUF and SO_SNDBUF in bytes;
+  #     stats/1 reports what the kernel made of them
+  # pid gets {:socket_error, reason} when a receiver fails (reason as for
+  # send errors below, or :cant_receive), and
+  # {:__send_error__, reason, peer, size} for each datagram that couldn't be
+  # sent. reason is :would_block when the send buffer stayed full (see
+  # set_send_retries/2), :message_too_big when it didn't fit the MTU (the
+  # sockets never fragment), :network_unreachable, :host_unreachable,
+  # :connection_refused, :no_buffer_space, :eacces, :bad_format or
+  # :socket_error.
+  @spec start(integer, binary | :inet.ip_address(), non_neg_integer, pid, [pid], keyword) ::
+          :ok
+          | {:error,
+             :system_error
+             | :socket_error
+             | :bad_format
+             | :eaddrinuse
+             | :eaddrnotavail
+             | :eacces
+             | :bad_state}
+  def start(socket_ptr, host, port, pid, target_pids, opts \\ []) do
+    NIF.socket_start(socket_ptr, address(host, port), pid, target_pids, opts)
+  end
+
+  defp address({_, _, _, _} = ip, port),
+    do: {ip |> Tuple.to_list() |> :binary.list_to_bin(), port}
+
+  defp address({_, _, _, _, _, _, _, _} = ip, port),
+    do: {for(n <- Tuple.to_list(ip), into: <<>>, do: <<n::16>>), port}
+
+  defp address(host, port) do
+    if String.contains?(host, ":") do
+      "[#{host}]:#{port}"
+    else
+      "#{host}:#{port}"
+    end
+  end
+
+  # hands the error reports that go to the pid given to start over to pid,
+  # like :gen_udp.controlling_process/2; only the current owner may call it
+  @spec set_controlling_process(integer, pid) ::
+          :ok | {:error, :bad_state | :not_owner}
+  def set_controlling_process(socket_ptr, pid) do
+    NIF.socket_set_controlling_process(socket_ptr, pid)
+  end
+
+  # sends the packets that went to the idx-th of the target_pids given to
+  # start to pid instead, e.g. a restarted dispatcher; :bad_state until
+  # started, :bad_format for an idx out of range
+  @spec set_target_pid(integer, non_neg_integer, pid) ::
+          :ok | {:error, :bad_state | :bad_format}
+  def set_target_pid(socket_ptr, idx, pid) do
+    NIF.socket_set_target_pid(socket_ptr, idx, pid)
+  end
+
+  # releases the port and sends {:__socket_closed__} to the caller once done,
+  # after which the socket can be started again
+  @spec stop(integer) :: :ok | {:error, :bad_state}
+  def stop(socket_ptr) do
+    NIF.socket_stop(socket_ptr)
+  end
+
+  # counts of datagrams received, of those dropped for being too small, too
+  # big or not QUIC, and of datagrams sent, failed to send, or held up by a
+  # full send buffer (would_block), and of those handed to other_pid
+  # (passed_through) or to fallback_pid (fell_back), plus the socket buffer
+  # sizes the last start got
+  @spec stats(integer) ::
+          {:ok,
+           %{
+             received: non_neg_integer,
+             bytes_received: non_neg_integer,
+             too_small: non_neg_integer,
+             too_big: non_neg_integer,
+             not_quic: non_neg_integer,
+             recv_errors: non_neg_integer,
+             sent: non_neg_integer,
+             bytes_sent: non_neg_integer,
+             send_errors: non_neg_integer,
+             would_block: non_neg_integer,
+             passed_through: non_neg_integer,
+             fell_back: non_neg_integer,
+             recv_buffer_size: non_neg_integer | nil,
+             send_buffer_size: non_neg_integer | nil
+           }}
+  def stats(socket_ptr) do
+    NIF.socket_stats(socket_ptr)
+  end
+
+  @spec stats_reset(integer) :: :ok
+  def stats_reset(socket_ptr) do
+    NIF.socket_stats_reset(socket_ptr)
+  end
+
+  # marks sent datagrams with the ECN codepoint, from the next start on;
+  # Linux only
+  @spec set_ecn(integer, :not_ect | :ect0 | :ect1) ::
+          :ok | {:error, :bad_format | :bad_state | :not_supported}
+  def set_ecn(socket_ptr, codepoint) do
+    NIF.socket_set_ecn(socket_ptr, codepoint)
+  end
+
+  # how many more times a datagram that finds the send buffer full is tried,
+  # each waiting up to the write timeout; from the next start on
+  @spec set_send_retries(integer, non_neg_integer) :: :ok | {:error, :bad_state}
+  def set_send_retries(socket_ptr, retries) do
+    NIF.socket_set_send_retries(socket_ptr, retries)
+  end
+
+  # how long (ms) receivers block in a read, and senders wait for work,
+  # before checking whether the socket is shutting itself down (see the
+  # supervisor option of start/6); replaces read_timeout at once. Received
+  # datagrams and sends never wait for it, and on Linux neither does stop/1
+  @spec set_poll_interval(integer, pos_integer) :: :ok | {:error, :bad_format | :socket_error}
+  def set_poll_interval(socket_ptr, interval) do
+    NIF.socket_set_poll_interval(socket_ptr, interval)
+  end
+
+  # whether the kernel takes UDP_SEGMENT on the bound sockets, once started
+  @spec gso_supported(integer) :: {:ok, boolean} | {:error, :bad_state}
+  def gso_supported(socket_ptr) do
+    NIF.socket_gso_supported(socket_ptr)
   end
 
   @spec destroy(integer) :: :ok | {:error, :system_error | :not_found}
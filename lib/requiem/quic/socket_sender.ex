@@ -1,7 +1,8 @@
 defmodule Requiem.QUIC.SocketSender do
   alias Requiem.QUIC.NIF
 
-  @spec get(integer, integer) :: {:ok, integer} | {:error, :system_error | :not_found}
+  @spec get(integer, integer) ::
+          {:ok, integer} | {:error, :system_error | :not_found | :bad_format}
   def get(socket_ptr, idx) do
     NIF.socket_sender_get(socket_ptr, idx)
   end
@@ -11,6 +12,26 @@ defmodule Requiem.QUIC.SocketSender do
     NIF.socket_sender_send(sender_ptr, address, packet)
   end
 
+  @spec send_batch(integer, term, [binary]) :: :ok | {:error, :system_error | :not_found}
+  def send_batch(sender_ptr, address, packets) do
+    NIF.socket_sender_send_batch(sender_ptr, address, packets)
+  end
+
+  @spec send_batch_to(integer, binary, :inet.port_number(), [binary]) ::
+          :ok | {:error, :bad_format}
+  def send_batch_to(sender_ptr, ip, port, packets) do
+    NIF.socket_sender_send_batch_to(sender_ptr, ip, port, packets)
+  end
+
+  # packet is cut into datagrams of segment_size bytes by the kernel (UDP GSO),
+  # or by the sender thread where Socket.gso_supported/1 says it can't.
+  # Not available outside Linux.
+  @spec send_gso(integer, term, binary, pos_integer) ::
+          :ok | {:error, :bad_format | :not_supported}
+  def send_gso(sender_ptr, address, packet, segment_size) do
+    NIF.socket_sender_send_gso(sender_ptr, address, packet, segment_size)
+  end
+
   @spec destroy(integer) :: :ok | {:error, :system_error | :not_found}
   def destroy(sender_ptr) do
     NIF.socket_sender_destroy(sender_ptr)
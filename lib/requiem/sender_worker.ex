@@ -79,9 +79,15 @@ defmodule Requiem.SenderWorker do
   end
 
   @impl GenServer
-  def handle_info({:__drain__, address, packet}, state) do
+  def handle_info({:__drain__, ip, port, packets, 0}, state) do
     Tracer.trace(__MODULE__, state.trace_id, "@drain")
-    QUIC.SocketSender.send(state.sender_ptr, address, packet)
+    QUIC.SocketSender.send_batch_to(state.sender_ptr, ip, port, packets)
+    {:noreply, state}
+  end
+
+  def handle_info({:__drain__, ip, port, packets, delay}, state) do
+    Tracer.trace(__MODULE__, state.trace_id, "@drain: paced #{delay}ms")
+    Process.send_after(self(), {:__drain__, ip, port, packets, 0}, delay)
     {:noreply, state}
   end
 
@@ -0,0 +1,41 @@
+defmodule Requiem.SocketKeeper do
+  # Owns the socket cluster for as long as the root supervisor lives: it is
+  # started before and stopped after everything using the socket, and only
+  # then destroys it. Transport just starts and stops the socket, so a
+  # restarted Transport can start it again.
+  use GenServer
+
+  alias Requiem.QUIC
+
+  @spec child_spec(Keyword.t()) :: map
+  def child_spec(opts) do
+    %{
+      id: Keyword.fetch!(opts, :handler) |> name(),
+      start: {__MODULE__, :start_link, [opts]},
+      shutdown: 5_000,
+      restart: :permanent,
+      type: :worker
+    }
+  end
+
+  @spec start_link(Keyword.t()) :: GenServer.on_start()
+  def start_link(opts) do
+    name = Keyword.fetch!(opts, :handler) |> name()
+    GenServer.start_link(__MODULE__, Keyword.fetch!(opts, :socket_ptr), name: name)
+  end
+
+  @impl GenServer
+  def init(socket_ptr) do
+    Process.flag(:trap_exit, true)
+    {:ok, socket_ptr}
+  end
+
+  @impl GenServer
+  def terminate(_reason, socket_ptr) do
+    QUIC.Socket.destroy(socket_ptr)
+    :ok
+  end
+
+  defp name(handler),
+    do: Module.concat(handler, __MODULE__)
+end
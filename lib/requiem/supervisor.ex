@@ -6,6 +6,7 @@ defmodule Requiem.Supervisor do
   use Supervisor
   alias Requiem.AddressTable
   alias Requiem.Config
+  alias Requiem.ConnectionID
   alias Requiem.QUIC
   alias Requiem.ConnectionRegistry
   alias Requiem.ConnectionSupervisor
@@ -13,6 +14,7 @@ defmodule Requiem.Supervisor do
   alias Requiem.DispatcherRegistry
   alias Requiem.SenderSupervisor
   alias Requiem.SenderRegistry
+  alias Requiem.SocketKeeper
   alias Requiem.Transport
 
   @spec child_spec(module, atom) :: Supervisor.child_spec()
@@ -58,9 +60,22 @@ defmodule Requiem.Supervisor do
     read_timeout = Config.get!(handler, :socket_read_timeout)
     write_timeout = Config.get!(handler, :socket_write_timeout)
 
-    case QUIC.Socket.new(num_socket, read_timeout, write_timeout) do
+    max_datagram_size = Config.get!(handler, :socket_max_datagram_size)
+    recv_batch_size = Config.get!(handler, :socket_recv_batch_size)
+
+    case QUIC.Socket.new(
+           num_socket,
+           read_timeout,
+           write_timeout,
+           ConnectionID.len(),
+           max_datagram_size,
+           recv_batch_size
+         ) do
       {:ok, socket_ptr} ->
+        :ok = QUIC.Socket.set_send_retries(socket_ptr, Config.get!(handler, :socket_send_retries))
+
         [
+          {SocketKeeper, [handler: handler, socket_ptr: socket_ptr]},
           {Registry, keys: :unique, name: ConnectionRegistry.name(handler)},
           {Registry, keys: :unique, name: DispatcherRegistry.name(handler)},
           {Registry, keys: :unique, name: SenderRegistry.name(handler)},
@@ -78,6 +93,7 @@ defmodule Requiem.Supervisor do
              conn_id_secret: handler |> Config.get!(:connection_id_secret),
              number_of_dispatchers: dispatcher_pool_size,
              number_of_sockets: num_socket,
+             socket_ptr: socket_ptr,
              allow_address_routing: handler |> Config.get!(:allow_address_routing)
            ]},
           {Transport,
@@ -85,6 +101,7 @@ defmodule Requiem.Supervisor do
              handler: handler,
              host: handler |> Config.get!(:host),
              port: handler |> Config.get!(:port),
+             socket_options: socket_options(handler),
              socket_ptr: socket_ptr,
              number_of_dispatchers: dispatcher_pool_size
            ]}
@@ -96,6 +113,21 @@ defmodule Requiem.Supervisor do
     end
   end
 
+  defp socket_options(handler) do
+    optional =
+      [
+        v6_only: Config.get(handler, :socket_v6_only),
+        recv_buffer_size: Config.get(handler, :socket_recv_buffer_size),
+        send_buffer_size: Config.get(handler, :socket_send_buffer_size)
+      ]
+      |> Enum.reject(fn {_key, value} -> value == nil end)
+
+    [
+      normalize_v4_mapped: Config.get!(handler, :socket_normalize_v4_mapped),
+      recv_error_policy: Config.get!(handler, :socket_recv_error_policy)
+    ] ++ optional
+  end
+
   defp name(handler),
     do: Module.concat(handler, __MODULE__)
 end
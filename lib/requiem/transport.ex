@@ -3,6 +3,7 @@ defmodule Requiem.Transport do
   require Logger
   # require Requiem.Tracer
 
+  alias Requiem.Address
   alias Requiem.DispatcherRegistry
   alias Requiem.QUIC
   # alias Requiem.Tracer
@@ -30,21 +31,39 @@ defmodule Requiem.Transport do
 
     state = new(opts)
 
-    host = Keyword.fetch!(opts, :host)
+    bind_host = Keyword.fetch!(opts, :host)
+    host = format_host(bind_host)
     port = Keyword.fetch!(opts, :port)
 
     case QUIC.Socket.start(
            state.socket_ptr,
-           host,
+           bind_host,
            port,
            self(),
-           dispatchers
+           dispatchers,
+           [supervisor: self()] ++ Keyword.get(opts, :socket_options, [])
          ) do
       :ok ->
         Logger.info("<Requiem.Transport> socket started on #{host}:#{port}")
         Process.flag(:trap_exit, true)
         {:ok, state}
 
+      {:error, :eaddrinuse} ->
+        Logger.error("<Requiem.Transport> port(#{port}) on host(#{host}) is already in use")
+        {:stop, :normal}
+
+      {:error, :eaddrnotavail} ->
+        Logger.error("<Requiem.Transport> host(#{host}) is not an address of this machine")
+        {:stop, :normal}
+
+      {:error, :eacces} ->
+        Logger.error("<Requiem.Transport> not permitted to bind port(#{port}) on host(#{host})")
+        {:stop, :normal}
+
+      {:error, :bad_format} ->
+        Logger.error("<Requiem.Transport> invalid host(#{host}) or port(#{port})")
+        {:stop, :normal}
+
       {:error, :socket_error} ->
         Logger.error(
           "<Requiem.Transport> failed to bind UDP port, make sure that the values for this host(#{host}) and port(#{port}) are correct and that the port(#{port}) is not already in use."
@@ -62,13 +81,33 @@ defmodule Requiem.Transport do
   @impl GenServer
   def handle_info({:socket_error, reason}, state) do
     Logger.error("<Requiem.Transport> socket error. #{inspect(reason)}")
+    {:noreply, state}
+  end
+
+  # the socket shut itself down, see the socket_recv_error_policy config
+  def handle_info({:__owner_down__}, state) do
     {:stop, {:shutdown, :socket_error}, state}
   end
 
+  def handle_info({:__send_error__, :message_too_big, peer, size}, state) do
+    address = Address.from_rust_peer(peer)
+
+    Logger.warn("<Requiem.Transport> dropped a #{size} byte datagram to #{address}, over the MTU")
+
+    {:noreply, state}
+  end
+
+  def handle_info({:__send_error__, reason, peer, size}, state) do
+    address = Address.from_rust_peer(peer)
+    Logger.warn("<Requiem.Transport> failed to send #{size} bytes to #{address}: #{reason}")
+    {:noreply, state}
+  end
+
   @impl GenServer
   def terminate(reason, state) do
     Logger.info("<Requiem.Transport> @terminate: #{inspect(reason)}")
-    QUIC.Socket.destroy(state.socket_ptr)
+    # SocketKeeper destroys it; a restarted Transport starts it again
+    QUIC.Socket.stop(state.socket_ptr)
     :ok
   end
 
@@ -79,6 +118,9 @@ defmodule Requiem.Transport do
     }
   end
 
+  defp format_host(host) when is_tuple(host), do: host |> :inet.ntoa() |> to_string()
+  defp format_host(host), do: host
+
   defp name(handler),
     do: Module.concat(handler, __MODULE__)
 end
@@ -6,6 +6,7 @@ defmodule Requiem.MixProject do
       app: :requiem,
       version: "0.3.9",
       elixir: "~> 1.11",
+      elixirc_paths: elixirc_paths(Mix.env()),
       package: package(),
       start_permanent: Mix.env() == :prod,
       deps: deps()
@@ -19,6 +20,9 @@ defmodule Requiem.MixProject do
     ]
   end
 
+  defp elixirc_paths(:test), do: ["lib", "test/support"]
+  defp elixirc_paths(_), do: ["lib"]
+
   # Run "mix help deps" to learn about dependencies.
   defp deps do
     [
@@ -0,0 +1,98 @@
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use rustler::types::binary::Binary;
+use rustler::{Atom, Env, NifResult};
+
+use crate::common::{self, atoms};
+
+// A signed id is laid out as
+//
+//   payload length (1) | payload | random | tag (TAG_LEN)
+//
+// and always MAX_CONN_ID_LEN long. The payload travels in the clear, so it
+// should be a routing hint rather than anything secret, but the tag means a
+// dispatcher can trust it. The random part keeps ids carrying the same
+// payload distinct.
+const TAG_LEN: usize = 8;
+const MIN_RANDOM_LEN: usize = 4;
+const MAX_PAYLOAD_LEN: usize = quiche::MAX_CONN_ID_LEN - 1 - MIN_RANDOM_LEN - TAG_LEN;
+const MIN_SECRET_LEN: usize = 16;
+
+fn signing_key(secret: &[u8]) -> Result<hmac::Key, Atom> {
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(atoms::bad_format());
+    }
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, secret))
+}
+
+fn random_bytes(buf: &mut [u8]) -> Result<(), Atom> {
+    SystemRandom::new()
+        .fill(buf)
+        .map_err(|_| atoms::system_error())
+}
+
+fn sign(secret: &[u8], payload: &[u8]) -> Result<Vec<u8>, Atom> {
+    let key = signing_key(secret)?;
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(atoms::bad_format());
+    }
+
+    let mut cid = vec![0; quiche::MAX_CONN_ID_LEN];
+    let body_len = cid.len() - TAG_LEN;
+    cid[0] = payload.len() as u8;
+    cid[1..=payload.len()].copy_from_slice(payload);
+    random_bytes(&mut cid[1 + payload.len()..body_len])?;
+
+    let tag = hmac::sign(&key, &cid[..body_len]);
+    cid[body_len..].copy_from_slice(&tag.as_ref()[..TAG_LEN]);
+    Ok(cid)
+}
+
+fn open<'a>(secret: &[u8], cid: &'a [u8]) -> Result<&'a [u8], Atom> {
+    let key = signing_key(secret)?;
+    if cid.len() != quiche::MAX_CONN_ID_LEN {
+        return Err(atoms::bad_format());
+    }
+
+    let body_len = cid.len() - TAG_LEN;
+    let payload_len = cid[0] as usize;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(atoms::bad_format());
+    }
+
+    // hmac::verify needs the full tag, so compare the truncated one here in
+    // constant time instead
+    let tag = hmac::sign(&key, &cid[..body_len]);
+    ring::constant_time::verify_slices_are_equal(&tag.as_ref()[..TAG_LEN], &cid[body_len..])
+        .map_err(|_| atoms::bad_format())?;
+
+    Ok(&cid[1..=payload_len])
+}
+
+#[rustler::nif]
+pub fn cid_generate(env: Env, len: u64) -> NifResult<(Atom, Binary)> {
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| (1..=quiche::MAX_CONN_ID_LEN).contains(len))
+        .ok_or_else(|| common::error_term(atoms::bad_format()))?;
+
+    let mut cid = vec![0; len];
+    random_bytes(&mut cid).map_err(common::error_term)?;
+    Ok((atoms::ok(), common::owned_binary(&cid).release(env)))
+}
+
+#[rustler::nif]
+pub fn cid_sign<'a>(
+    env: Env<'a>,
+    secret: Binary,
+    payload: Binary,
+) -> NifResult<(Atom, Binary<'a>)> {
+    let cid = sign(secret.as_slice(), payload.as_slice()).map_err(common::error_term)?;
+    Ok((atoms::ok(), common::owned_binary(&cid).release(env)))
+}
+
+#[rustler::nif]
+pub fn cid_open<'a>(env: Env<'a>, secret: Binary, cid: Binary) -> NifResult<(Atom, Binary<'a>)> {
+    let payload = open(secret.as_slice(), cid.as_slice()).map_err(common::error_term)?;
+    Ok((atoms::ok(), common::owned_binary(payload).release(env)))
+}
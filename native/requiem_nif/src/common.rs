@@ -1,4 +1,10 @@
-use rustler::Atom;
+use rustler::types::binary::{Binary, OwnedBinary};
+use rustler::types::ListIterator;
+use rustler::{Atom, Term};
+
+// The largest payload a UDP datagram can carry (65535 less the 8 byte UDP
+// header), and so the most any buffer sized from a datagram size needs.
+pub(crate) const MAX_UDP_PAYLOAD_SIZE: usize = 65527;
 
 pub(crate) mod atoms {
     rustler::atoms! {
@@ -7,24 +13,284 @@ pub(crate) mod atoms {
         socket_error,
         cant_receive,
         cant_bind,
+        eaddrinuse,
+        eaddrnotavail,
+        eacces,
         already_exists,
         already_closed,
         bad_format,
         bad_state,
         not_found,
+        not_ready,
+        not_negotiated,
+        would_block,
+        not_supported,
+        not_owner,
+        message_too_big,
+        network_unreachable,
+        host_unreachable,
+        connection_refused,
+        no_buffer_space,
+        v6_only,
+        normalize_v4_mapped,
+        peer_parts,
+        supervisor,
+        other_pid,
+        other_prefix,
+        fallback_pid,
+        recv_error_policy,
+        ignore,
+        notify,
+        shutdown,
+        not_ect,
+        ect0,
+        ect1,
+        ce,
         __drain__,
-        __packet__,
+        __drain_error__,
+        __packets__,
         __stream_recv__,
         __dgram_recv__,
+        __stream_writable__,
+        __stream_readable__,
+        __connected__,
+        __early_data__,
+        __closed__,
+        __peer_migrated__,
+        __peer_streams_left__,
+        __quiche_log__,
+        __socket_closed__,
+        __send_error__,
+        __h3_headers__,
+        __h3_data__,
+        __h3_finished__,
+        __h3_reset__,
+        __h3_goaway__,
+        __owner_down__,
+        __other_packet__,
+        __unparsed_packet__,
+        active,
+        draining,
+        closed,
+        infinity,
+        error, // log level
+        warn,  // log level
+        info,  // log level
+        debug, // log level
+        trace, // log level
+        partial,
+        done,
+        buffer_too_short,
+        unknown_version,
+        invalid_frame,
+        invalid_packet,
+        invalid_state,
+        invalid_stream_state,
+        invalid_transport_param,
+        crypto_fail,
+        tls_fail,
+        flow_control,
+        stream_limit,
+        stream_stopped,
+        stream_reset,
+        final_size,
+        congestion_control,
+        internal_error,             // h3
+        excessive_load,             // h3
+        id_error,                   // h3
+        stream_creation_error,      // h3
+        closed_critical_stream,     // h3
+        missing_settings,           // h3
+        frame_unexpected,           // h3
+        frame_error,                // h3
+        qpack_decompression_failed, // h3
+        stream_blocked,             // h3
+        settings_error,             // h3
+        request_rejected,           // h3
+        request_cancelled,          // h3
+        request_incomplete,         // h3
+        message_error,              // h3
+        connect_error,              // h3
+        version_fallback,           // h3
+        recv,                      // stats
+        sent,                      // stats
+        lost,                      // stats
+        retrans,                   // stats
+        rtt,                       // stats
+        cwnd,                      // stats
+        sent_bytes,                // stats
+        recv_bytes,                // stats
+        lost_bytes,                // stats
+        stream_retrans_bytes,      // stats
+        pmtu,                      // stats
+        delivery_rate,             // stats
+        peer_active_conn_id_limit, // stats
+        received,                  // socket stats
+        too_small,                 // socket stats
+        too_big,                   // socket stats
+        not_quic,                  // socket stats
+        bytes_received,            // socket stats
+        recv_errors,               // socket stats
+        bytes_sent,                // socket stats
+        send_errors,               // socket stats
+        recv_buffer_size,          // socket stats
+        send_buffer_size,          // socket stats
+        passed_through,            // socket stats
+        fell_back,                 // socket stats
+        accepted,                  // nif stats
+        live_connections,          // nif stats
+        packets_received,          // nif stats
+        packets_drained,           // nif stats
+        stream_bytes,              // nif stats
+        dgrams,                    // nif stats
+        messages,                  // nif stats
         initial,             // packet type
         handshake,           // packet type
         retry,               // packet type
         zero_rtt,            // packet type
         version_negotiation, // packet type
-        short                // packet type
+        short,               // packet type
+        packet_type,         // header
+        form,                // header
+        long,                // header
+        version,             // header
+        dcid,                // header
+        scid,                // header
+        token,               // header
+        versions             // header
     }
 }
 
 pub(crate) fn error_term(reason: Atom) -> rustler::Error {
     rustler::Error::Term(Box::new(reason))
 }
+
+// Accepts a binary or a list of binaries, nested at most one level, so
+// callers can pass an iolist without flattening it first.
+pub(crate) fn binary_segments<'a>(data: Term<'a>) -> Result<Vec<Binary<'a>>, Atom> {
+    if let Ok(bin) = data.decode::<Binary>() {
+        return Ok(vec![bin]);
+    }
+    let list = data
+        .decode::<ListIterator>()
+        .map_err(|_| atoms::bad_format())?;
+    let mut segments = Vec::new();
+    for elem in list {
+        if let Ok(bin) = elem.decode::<Binary>() {
+            segments.push(bin);
+        } else {
+            let inner = elem
+                .decode::<ListIterator>()
+                .map_err(|_| atoms::bad_format())?;
+            for bin in inner {
+                segments.push(bin.decode::<Binary>().map_err(|_| atoms::bad_format())?);
+            }
+        }
+    }
+    Ok(segments)
+}
+
+pub(crate) fn owned_binary(data: &[u8]) -> OwnedBinary {
+    let mut bin = OwnedBinary::new(data.len()).unwrap();
+    bin.as_mut_slice().copy_from_slice(data);
+    bin
+}
+
+// No wildcard arm on purpose: a quiche upgrade that adds a variant
+// should fail to compile here instead of falling back to system_error.
+// Written once for both the atom and, for the tests, its name, as atoms
+// can't be made outside the VM.
+macro_rules! quiche_errors {
+    ($($pattern:pat => $atom:ident,)*) => {
+        pub(crate) fn quiche_error_atom(err: quiche::Error) -> Atom {
+            match err {
+                $($pattern => atoms::$atom(),)*
+            }
+        }
+
+        #[cfg(test)]
+        fn quiche_error_name(err: quiche::Error) -> &'static str {
+            match err {
+                $($pattern => stringify!($atom),)*
+            }
+        }
+    };
+}
+
+quiche_errors! {
+    quiche::Error::Done => done,
+    quiche::Error::BufferTooShort => buffer_too_short,
+    quiche::Error::UnknownVersion => unknown_version,
+    quiche::Error::InvalidFrame => invalid_frame,
+    quiche::Error::InvalidPacket => invalid_packet,
+    quiche::Error::InvalidState => invalid_state,
+    quiche::Error::InvalidStreamState(_) => invalid_stream_state,
+    quiche::Error::InvalidTransportParam => invalid_transport_param,
+    quiche::Error::CryptoFail => crypto_fail,
+    quiche::Error::TlsFail => tls_fail,
+    quiche::Error::FlowControl => flow_control,
+    quiche::Error::StreamLimit => stream_limit,
+    quiche::Error::StreamStopped(_) => stream_stopped,
+    quiche::Error::StreamReset(_) => stream_reset,
+    quiche::Error::FinalSize => final_size,
+    quiche::Error::CongestionControl => congestion_control,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_quiche_error_has_its_own_atom() {
+        let cases = [
+            (quiche::Error::Done, "done"),
+            (quiche::Error::BufferTooShort, "buffer_too_short"),
+            (quiche::Error::UnknownVersion, "unknown_version"),
+            (quiche::Error::InvalidFrame, "invalid_frame"),
+            (quiche::Error::InvalidPacket, "invalid_packet"),
+            (quiche::Error::InvalidState, "invalid_state"),
+            (quiche::Error::InvalidStreamState(4), "invalid_stream_state"),
+            (
+                quiche::Error::InvalidTransportParam,
+                "invalid_transport_param",
+            ),
+            (quiche::Error::CryptoFail, "crypto_fail"),
+            (quiche::Error::TlsFail, "tls_fail"),
+            (quiche::Error::FlowControl, "flow_control"),
+            (quiche::Error::StreamLimit, "stream_limit"),
+            (quiche::Error::StreamStopped(4), "stream_stopped"),
+            (quiche::Error::StreamReset(4), "stream_reset"),
+            (quiche::Error::FinalSize, "final_size"),
+            (quiche::Error::CongestionControl, "congestion_control"),
+        ];
+        for (err, name) in cases.iter() {
+            assert_eq!(quiche_error_name(*err), *name, "{:?}", err);
+        }
+
+        let mut names: Vec<&str> = cases
+            .iter()
+            .map(|(err, _)| quiche_error_name(*err))
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), cases.len());
+    }
+
+    #[test]
+    fn stream_errors_ignore_the_payload() {
+        for code in [0, 1, u64::MAX].iter() {
+            assert_eq!(
+                quiche_error_name(quiche::Error::StreamStopped(*code)),
+                "stream_stopped"
+            );
+            assert_eq!(
+                quiche_error_name(quiche::Error::StreamReset(*code)),
+                "stream_reset"
+            );
+            assert_eq!(
+                quiche_error_name(quiche::Error::InvalidStreamState(*code)),
+                "invalid_stream_state"
+            );
+        }
+    }
+}
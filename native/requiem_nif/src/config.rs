@@ -1,15 +1,152 @@
-use std::str;
+use std::str::{self, FromStr};
 
 use rustler::types::binary::Binary;
-use rustler::{Atom, NifResult};
+use rustler::types::Encoder;
+use rustler::{Atom, Env, NifResult, Term};
 
 use crate::common::{self, atoms};
+use crate::telemetry::{self, Telemetry};
 
-fn set_config<F>(config: &mut quiche::Config, setter: F) -> NifResult<Atom>
+// Everything set through the config_* NIFs, since quiche::Config can't be
+// read back. None means the setter was never called and quiche's default
+// is in effect.
+#[derive(Default)]
+struct Settings {
+    cert_chain_file: Option<String>,
+    priv_key_file: Option<String>,
+    verify_locations_file: Option<String>,
+    verify_locations_directory: Option<String>,
+    verify_peer: Option<bool>,
+    require_peer_cert: Option<bool>,
+    grease: Option<bool>,
+    early_data: Option<bool>,
+    log_keys: Option<bool>,
+    // the key itself is never kept around
+    ticket_key: Option<bool>,
+    application_protos: Option<Vec<String>>,
+    max_idle_timeout: Option<u64>,
+    max_recv_udp_payload_size: Option<u64>,
+    max_send_udp_payload_size: Option<u64>,
+    initial_max_data: Option<u64>,
+    initial_max_stream_data_bidi_local: Option<u64>,
+    initial_max_stream_data_bidi_remote: Option<u64>,
+    initial_max_stream_data_uni: Option<u64>,
+    initial_max_streams_bidi: Option<u64>,
+    initial_max_streams_uni: Option<u64>,
+    ack_delay_exponent: Option<u64>,
+    max_ack_delay: Option<u64>,
+    disable_active_migration: Option<bool>,
+    cc_algorithm_name: Option<String>,
+    hystart: Option<bool>,
+    dgram: Option<bool>,
+    dgram_recv_queue_len: Option<u64>,
+    dgram_send_queue_len: Option<u64>,
+    max_connection_window: Option<u64>,
+    max_stream_window: Option<u64>,
+}
+
+impl Settings {
+    fn to_term<'a>(&self, env: Env<'a>) -> NifResult<Term<'a>> {
+        let entries = [
+            ("cert_chain_file", self.cert_chain_file.encode(env)),
+            ("priv_key_file", self.priv_key_file.encode(env)),
+            (
+                "verify_locations_file",
+                self.verify_locations_file.encode(env),
+            ),
+            (
+                "verify_locations_directory",
+                self.verify_locations_directory.encode(env),
+            ),
+            ("verify_peer", self.verify_peer.encode(env)),
+            ("require_peer_cert", self.require_peer_cert.encode(env)),
+            ("grease", self.grease.encode(env)),
+            ("early_data", self.early_data.encode(env)),
+            ("log_keys", self.log_keys.encode(env)),
+            ("ticket_key", self.ticket_key.encode(env)),
+            ("application_protos", self.application_protos.encode(env)),
+            ("max_idle_timeout", self.max_idle_timeout.encode(env)),
+            (
+                "max_recv_udp_payload_size",
+                self.max_recv_udp_payload_size.encode(env),
+            ),
+            (
+                "max_send_udp_payload_size",
+                self.max_send_udp_payload_size.encode(env),
+            ),
+            ("initial_max_data", self.initial_max_data.encode(env)),
+            (
+                "initial_max_stream_data_bidi_local",
+                self.initial_max_stream_data_bidi_local.encode(env),
+            ),
+            (
+                "initial_max_stream_data_bidi_remote",
+                self.initial_max_stream_data_bidi_remote.encode(env),
+            ),
+            (
+                "initial_max_stream_data_uni",
+                self.initial_max_stream_data_uni.encode(env),
+            ),
+            (
+                "initial_max_streams_bidi",
+                self.initial_max_streams_bidi.encode(env),
+            ),
+            (
+                "initial_max_streams_uni",
+                self.initial_max_streams_uni.encode(env),
+            ),
+            ("ack_delay_exponent", self.ack_delay_exponent.encode(env)),
+            ("max_ack_delay", self.max_ack_delay.encode(env)),
+            (
+                "disable_active_migration",
+                self.disable_active_migration.encode(env),
+            ),
+            ("cc_algorithm_name", self.cc_algorithm_name.encode(env)),
+            ("hystart", self.hystart.encode(env)),
+            ("dgram", self.dgram.encode(env)),
+            (
+                "dgram_recv_queue_len",
+                self.dgram_recv_queue_len.encode(env),
+            ),
+            (
+                "dgram_send_queue_len",
+                self.dgram_send_queue_len.encode(env),
+            ),
+            (
+                "max_connection_window",
+                self.max_connection_window.encode(env),
+            ),
+            ("max_stream_window", self.max_stream_window.encode(env)),
+        ];
+
+        let mut keys = Vec::with_capacity(entries.len());
+        let mut values = Vec::with_capacity(entries.len());
+        for (key, value) in entries.iter() {
+            keys.push(Atom::from_str(env, key)?.to_term(env));
+            values.push(*value);
+        }
+        Term::map_from_arrays(env, &keys, &values)
+    }
+}
+
+pub struct Config {
+    pub raw: quiche::Config,
+    settings: Settings,
+    pub(crate) telemetry: Telemetry,
+}
+
+impl Config {
+    pub fn requires_peer_cert(&self) -> bool {
+        self.settings.require_peer_cert.unwrap_or(false)
+    }
+}
+
+// The setter is only recorded in Settings if quiche accepted it.
+fn set_config<F>(config: &mut Config, setter: F) -> NifResult<Atom>
 where
-    F: FnOnce(&mut quiche::Config) -> quiche::Result<()>,
+    F: FnOnce(&mut quiche::Config, &mut Settings) -> quiche::Result<()>,
 {
-    match setter(config) {
+    match setter(&mut config.raw, &mut config.settings) {
         Ok(_) => Ok(atoms::ok()),
         Err(_) => Err(common::error_term(atoms::system_error())),
     }
@@ -19,215 +156,387 @@ where
 pub fn config_new() -> NifResult<(Atom, i64)> {
     let raw = quiche::Config::new(quiche::PROTOCOL_VERSION)
         .map_err(|_| common::error_term(atoms::system_error()))?;
-    let ptr = Box::into_raw(Box::new(raw)) as i64;
+    let config = Config {
+        raw,
+        settings: Settings::default(),
+        telemetry: Telemetry::default(),
+    };
+    let ptr = Box::into_raw(Box::new(config)) as i64;
     Ok((atoms::ok(), ptr))
 }
 
+#[rustler::nif]
+pub fn config_dump(env: Env, conf_ptr: i64) -> NifResult<(Atom, Term)> {
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    Ok((atoms::ok(), cp.settings.to_term(env)?))
+}
+
 #[rustler::nif]
 pub fn config_destroy(conf_ptr: i64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     unsafe { drop(Box::from_raw(conf_ptr)) };
     Ok(atoms::ok())
 }
 
 #[rustler::nif]
 pub fn config_load_cert_chain_from_pem_file(conf_ptr: i64, file: Binary) -> NifResult<Atom> {
-    let file = str::from_utf8(file.as_slice()).unwrap();
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let file =
+        str::from_utf8(file.as_slice()).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| config.load_cert_chain_from_pem_file(file))
+    set_config(cp, |config, settings| {
+        config.load_cert_chain_from_pem_file(file)?;
+        settings.cert_chain_file = Some(file.to_owned());
+        Ok(())
+    })
 }
 
 #[rustler::nif]
 pub fn config_load_priv_key_from_pem_file(conf_ptr: i64, file: Binary) -> NifResult<Atom> {
-    let file = str::from_utf8(file.as_slice()).unwrap();
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let file =
+        str::from_utf8(file.as_slice()).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| config.load_priv_key_from_pem_file(file))
+    set_config(cp, |config, settings| {
+        config.load_priv_key_from_pem_file(file)?;
+        settings.priv_key_file = Some(file.to_owned());
+        Ok(())
+    })
 }
 
 #[rustler::nif]
 pub fn config_load_verify_locations_from_file(conf_ptr: i64, file: Binary) -> NifResult<Atom> {
-    let file = str::from_utf8(file.as_slice()).unwrap();
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let file =
+        str::from_utf8(file.as_slice()).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| config.load_verify_locations_from_file(file))
+    set_config(cp, |config, settings| {
+        config.load_verify_locations_from_file(file)?;
+        settings.verify_locations_file = Some(file.to_owned());
+        Ok(())
+    })
 }
 
 #[rustler::nif]
 pub fn config_load_verify_locations_from_directory(conf_ptr: i64, dir: Binary) -> NifResult<Atom> {
-    let dir = str::from_utf8(dir.as_slice()).unwrap();
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let dir =
+        str::from_utf8(dir.as_slice()).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
-        config.load_verify_locations_from_directory(dir)
+    set_config(cp, |config, settings| {
+        config.load_verify_locations_from_directory(dir)?;
+        settings.verify_locations_directory = Some(dir.to_owned());
+        Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_verify_peer(conf_ptr: i64, verify: bool) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.verify_peer(verify);
+        settings.verify_peer = Some(verify);
+        Ok(())
+    })
+}
+
+// On a server, verify_peer(true) asks the client for a certificate and
+// verifies any it sends. Requiring one additionally refuses handshakes that
+// complete without it; see Connection::reject_missing_peer_cert.
+#[rustler::nif]
+pub fn config_require_peer_cert(conf_ptr: i64, require: bool) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    set_config(cp, |config, settings| {
+        if require {
+            config.verify_peer(true);
+            settings.verify_peer = Some(true);
+        }
+        settings.require_peer_cert = Some(require);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_grease(conf_ptr: i64, grease: bool) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.grease(grease);
+        settings.grease = Some(grease);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_enable_early_data(conf_ptr: i64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.enable_early_data();
+        settings.early_data = Some(true);
         Ok(())
     })
 }
 
+// Only has an effect on connections that are given a keylog writer with
+// connection_set_keylog_path.
 #[rustler::nif]
-pub fn config_set_application_protos(conf_ptr: i64, protos: Binary) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+pub fn config_log_keys(conf_ptr: i64) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
-        config.set_application_protos(protos.as_slice())
+    set_config(cp, |config, settings| {
+        config.log_keys();
+        settings.log_keys = Some(true);
+        Ok(())
+    })
+}
+
+// BoringSSL expects 48 bytes: a 16 byte key name followed by the AES and
+// HMAC keys. Nodes sharing the key can resume each other's sessions.
+#[rustler::nif]
+pub fn config_set_ticket_key(conf_ptr: i64, key: Binary) -> NifResult<Atom> {
+    if key.len() != 48 {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    set_config(cp, |config, settings| {
+        config.set_ticket_key(key.as_slice())?;
+        settings.ticket_key = Some(true);
+        Ok(())
+    })
+}
+
+#[rustler::nif]
+pub fn config_set_application_protos(conf_ptr: i64, protos: Vec<Binary>) -> NifResult<Atom> {
+    // quiche 0.12 takes the ALPN wire format: each protocol prefixed by its
+    // length in a single byte
+    let mut wire = Vec::new();
+    for proto in protos.iter() {
+        let proto = proto.as_slice();
+        if proto.is_empty() || proto.len() > 255 {
+            return Err(common::error_term(atoms::bad_format()));
+        }
+        wire.push(proto.len() as u8);
+        wire.extend_from_slice(proto);
+    }
+
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    set_config(cp, |config, settings| {
+        config.set_application_protos(&wire)?;
+        let names = protos
+            .iter()
+            .map(|proto| String::from_utf8_lossy(proto.as_slice()).into_owned())
+            .collect();
+        settings.application_protos = Some(names);
+        Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_max_idle_timeout(conf_ptr: i64, timeout: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_max_idle_timeout(timeout);
+        settings.max_idle_timeout = Some(timeout);
         Ok(())
     })
 }
 
+#[rustler::nif]
+pub fn config_set_max_recv_udp_payload_size(conf_ptr: i64, size: u64) -> NifResult<Atom> {
+    let size = usize::try_from(size).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    set_config(cp, |config, settings| {
+        config.set_max_recv_udp_payload_size(size);
+        settings.max_recv_udp_payload_size = Some(size as u64);
+        Ok(())
+    })
+}
+
+// quiche never goes below 1200 bytes here, whatever is passed in.
+#[rustler::nif]
+pub fn config_set_max_send_udp_payload_size(conf_ptr: i64, size: u64) -> NifResult<Atom> {
+    let size = usize::try_from(size).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    set_config(cp, |config, settings| {
+        config.set_max_send_udp_payload_size(size);
+        settings.max_send_udp_payload_size = Some(size as u64);
+        Ok(())
+    })
+}
+
+// Sets both the receive and the send limit.
 #[rustler::nif]
 pub fn config_set_max_udp_payload_size(conf_ptr: i64, size: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
-        config.set_max_recv_udp_payload_size(size as usize);
-        config.set_max_send_udp_payload_size(size as usize);
+    let len = usize::try_from(size).map_err(|_| common::error_term(atoms::bad_format()))?;
+    set_config(cp, |config, settings| {
+        config.set_max_recv_udp_payload_size(len);
+        config.set_max_send_udp_payload_size(len);
+        settings.max_recv_udp_payload_size = Some(size);
+        settings.max_send_udp_payload_size = Some(size);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_initial_max_data(conf_ptr: i64, v: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_initial_max_data(v);
+        settings.initial_max_data = Some(v);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_initial_max_stream_data_bidi_local(conf_ptr: i64, v: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_initial_max_stream_data_bidi_local(v);
+        settings.initial_max_stream_data_bidi_local = Some(v);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_initial_max_stream_data_bidi_remote(conf_ptr: i64, v: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_initial_max_stream_data_bidi_remote(v);
+        settings.initial_max_stream_data_bidi_remote = Some(v);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_initial_max_stream_data_uni(conf_ptr: i64, v: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_initial_max_stream_data_uni(v);
+        settings.initial_max_stream_data_uni = Some(v);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_initial_max_streams_bidi(conf_ptr: i64, v: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_initial_max_streams_bidi(v);
+        settings.initial_max_streams_bidi = Some(v);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_initial_max_streams_uni(conf_ptr: i64, v: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_initial_max_streams_uni(v);
+        settings.initial_max_streams_uni = Some(v);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_ack_delay_exponent(conf_ptr: i64, v: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_ack_delay_exponent(v);
+        settings.ack_delay_exponent = Some(v);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_max_ack_delay(conf_ptr: i64, v: u64) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_max_ack_delay(v);
+        settings.max_ack_delay = Some(v);
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_disable_active_migration(conf_ptr: i64, disabled: bool) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.set_disable_active_migration(disabled);
+        settings.disable_active_migration = Some(disabled);
+        Ok(())
+    })
+}
+
+// What the linked quiche understands, by the names it parses.
+const CC_ALGORITHMS: &[&str] = &["reno", "cubic"];
+
+// Shared by the binary and the atom variants, so an unknown name is
+// bad_format either way rather than an opaque failure inside quiche.
+fn set_cc_algorithm(cp: &mut Config, name: &str) -> NifResult<Atom> {
+    let algo = quiche::CongestionControlAlgorithm::from_str(name)
+        .map_err(|_| common::error_term(atoms::bad_format()))?;
+    set_config(cp, |config, settings| {
+        config.set_cc_algorithm(algo);
+        settings.cc_algorithm_name = Some(name.to_owned());
         Ok(())
     })
 }
 
 #[rustler::nif]
 pub fn config_set_cc_algorithm_name(conf_ptr: i64, name: Binary) -> NifResult<Atom> {
-    let name = str::from_utf8(name.as_slice()).unwrap();
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let name =
+        str::from_utf8(name.as_slice()).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    set_cc_algorithm(cp, name)
+}
+
+#[rustler::nif]
+pub fn config_set_cc_algorithm(env: Env, conf_ptr: i64, algo: Atom) -> NifResult<Atom> {
+    let name = CC_ALGORITHMS
+        .iter()
+        .find(|name| Atom::from_str(env, name).ok() == Some(algo))
+        .ok_or_else(|| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| config.set_cc_algorithm_name(name))
+    set_cc_algorithm(cp, name)
+}
+
+#[rustler::nif]
+pub fn config_cc_available_algorithms(env: Env) -> NifResult<Vec<Atom>> {
+    CC_ALGORITHMS
+        .iter()
+        .map(|name| Atom::from_str(env, name))
+        .collect()
 }
 
 #[rustler::nif]
 pub fn config_enable_hystart(conf_ptr: i64, enabled: bool) -> NifResult<Atom> {
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.enable_hystart(enabled);
+        settings.hystart = Some(enabled);
         Ok(())
     })
 }
@@ -239,13 +548,51 @@ pub fn config_enable_dgram(
     recv_queue_len: u64,
     send_queue_len: u64,
 ) -> NifResult<Atom> {
-    let recv: usize = recv_queue_len as usize;
-    let send: usize = send_queue_len as usize;
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let recv =
+        usize::try_from(recv_queue_len).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let send =
+        usize::try_from(send_queue_len).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let conf_ptr = conf_ptr as *mut Config;
     let cp = unsafe { &mut *conf_ptr };
 
-    set_config(cp, |config| {
+    set_config(cp, |config, settings| {
         config.enable_dgram(enabled, recv, send);
+        settings.dgram = Some(enabled);
+        settings.dgram_recv_queue_len = Some(recv_queue_len);
+        settings.dgram_send_queue_len = Some(send_queue_len);
         Ok(())
     })
 }
+
+#[rustler::nif]
+pub fn config_set_max_connection_window(conf_ptr: i64, v: u64) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    set_config(cp, |config, settings| {
+        config.set_max_connection_window(v);
+        settings.max_connection_window = Some(v);
+        Ok(())
+    })
+}
+
+#[rustler::nif]
+pub fn config_set_max_stream_window(conf_ptr: i64, v: u64) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    set_config(cp, |config, settings| {
+        config.set_max_stream_window(v);
+        settings.max_stream_window = Some(v);
+        Ok(())
+    })
+}
+
+// Connections made from this config from now on count towards module's
+// quic_stats. Not part of the quiche config, so config_dump leaves it out.
+#[rustler::nif]
+pub fn config_set_stats_module(env: Env, conf_ptr: i64, module: Atom) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut Config;
+    let cp = unsafe { &mut *conf_ptr };
+    let module = telemetry::module_name(env, module)?;
+    cp.telemetry = Telemetry::for_module(&module);
+    Ok(atoms::ok())
+}
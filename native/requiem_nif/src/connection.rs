@@ -1,44 +1,137 @@
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem;
 use std::pin::Pin;
+use std::str;
+use std::sync::Arc;
+use std::time::Instant;
 
+use parking_lot::{Mutex, MutexGuard};
 use rustler::types::binary::{Binary, OwnedBinary};
 use rustler::types::tuple::make_tuple;
 use rustler::types::{Encoder, LocalPid};
-use rustler::{Atom, Env, NifResult, ResourceArc};
+use rustler::{Atom, Env, NifResult, ResourceArc, Term};
 
 use crate::common::{self, atoms};
+use crate::config::Config;
+use crate::registry;
 use crate::socket::Peer;
+use crate::telemetry::{Counter, Telemetry};
 
-macro_rules! empty_vec {
-    ($x:expr) => {
-        unsafe {
-            let mut v = Vec::with_capacity($x);
-            v.set_len($x);
-            v
+// The stream read buffer is allocated per connection, so it is bounded like
+// the send buffer. Zero would never let a read make progress.
+const MAX_STREAM_BUF_SIZE: u64 = 16 * 1024 * 1024;
+
+// CRYPTO_ERROR carrying TLS's certificate_required alert (116).
+const CERTIFICATE_REQUIRED: u64 = 0x100 + 116;
+
+// Keeps CONNECTION_CLOSE within a minimum-size (1200 byte) packet.
+const MAX_CLOSE_REASON_LEN: usize = 1024;
+
+// The timer the owner has to arm before calling on_timeout. quiche reports
+// no timer at all once idle_timeout is disabled, which is encoded as
+// :infinity rather than a made-up deadline.
+pub enum NextTimeout {
+    After(u64),
+    Infinity,
+}
+
+impl Encoder for NextTimeout {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            NextTimeout::After(millis) => millis.encode(env),
+            NextTimeout::Infinity => atoms::infinity().encode(env),
         }
-    };
+    }
+}
+
+// (index in the batch, reason) for each packet quiche refused.
+type Rejected = Vec<(usize, Atom)>;
+
+// A connection handle is the pointer of one of these, so the dcid registry
+// can keep the connection alive while it feeds it a packet, and the lock
+// keeps that from running at the same time as a call from the owner.
+pub(crate) type SharedConnection = Arc<Mutex<Connection>>;
+
+pub(crate) fn lock_connection<'a>(conn_ptr: i64) -> MutexGuard<'a, Connection> {
+    let conn_ptr = conn_ptr as *const Mutex<Connection>;
+    unsafe { &*conn_ptr }.lock()
+}
+
+fn into_handle(conn: Connection) -> i64 {
+    Arc::into_raw(Arc::new(Mutex::new(conn))) as i64
 }
 
 pub struct Connection {
-    raw: Pin<Box<quiche::Connection>>,
+    pub(crate) raw: Pin<Box<quiche::Connection>>,
     peer: ResourceArc<Peer>,
     sender: LocalPid,
-    dgram_buf: Vec<u8>,
-    stream_buf: Vec<u8>,
+    // quiche wants to decrypt in place, so each incoming packet is copied
+    // here rather than into a fresh binary
+    recv_buf: Vec<u8>,
+    max_udp_payload_size: usize,
+    // a packet binary drain allocated but had nothing to write into, kept
+    // for the next drain
+    spare_packet: Option<OwnedBinary>,
+    pub(crate) stream_buf: Vec<u8>,
+    // the config's, so the connection counts towards its module's stats
+    pub(crate) telemetry: Telemetry,
+    // dcids this connection is registered under, dropped on destroy
+    pub(crate) registered_dcids: Vec<Vec<u8>>,
+    pub(crate) blocked_streams: HashSet<u64>,
+    pull_streams: bool,
+    require_peer_cert: bool,
+    readable_streams: HashSet<u64>,
+    early_data_reported: bool,
+    established_reported: bool,
+    closed_reported: bool,
+    peer_streams_left_bidi: u64,
+    peer_streams_left_uni: u64,
+    // set by h3_conn_new_with_transport, after which it owns the streams
+    #[cfg(feature = "h3")]
+    pub(crate) h3: Option<quiche::h3::Connection>,
+    // asked for before the handshake got far enough to attach
+    #[cfg(feature = "h3")]
+    pub(crate) h3_pending: Option<crate::h3::H3Config>,
 }
 
 impl Connection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         raw: Pin<Box<quiche::Connection>>,
         peer: ResourceArc<Peer>,
         sender: LocalPid,
         default_stream_buf_size: usize,
+        max_udp_payload_size: usize,
+        pull_streams: bool,
+        require_peer_cert: bool,
+        telemetry: Telemetry,
     ) -> Self {
+        telemetry.connection_opened();
         Self {
             raw,
             peer,
             sender,
-            dgram_buf: empty_vec!(1500),
-            stream_buf: empty_vec!(default_stream_buf_size),
+            recv_buf: Vec::with_capacity(max_udp_payload_size),
+            max_udp_payload_size,
+            spare_packet: None,
+            stream_buf: vec![0; default_stream_buf_size],
+            telemetry,
+            registered_dcids: Vec::new(),
+            blocked_streams: HashSet::new(),
+            pull_streams,
+            require_peer_cert,
+            readable_streams: HashSet::new(),
+            early_data_reported: false,
+            established_reported: false,
+            closed_reported: false,
+            peer_streams_left_bidi: 0,
+            peer_streams_left_uni: 0,
+            #[cfg(feature = "h3")]
+            h3: None,
+            #[cfg(feature = "h3")]
+            h3_pending: None,
         }
     }
 
@@ -46,138 +139,624 @@ impl Connection {
         self.raw.is_closed()
     }
 
+    pub fn is_established(&self) -> bool {
+        self.raw.is_established()
+    }
+
+    pub fn is_in_early_data(&self) -> bool {
+        self.raw.is_in_early_data()
+    }
+
+    pub fn is_resumed(&self) -> bool {
+        self.raw.is_resumed()
+    }
+
+    pub fn stats(&self) -> quiche::Stats {
+        self.raw.stats()
+    }
+
+    pub(crate) fn is_handshake_progressed(&self) -> bool {
+        self.raw.is_in_early_data() || self.raw.is_established()
+    }
+
+    pub fn application_proto(&self) -> Result<&[u8], Atom> {
+        if self.is_handshake_progressed() {
+            Ok(self.raw.application_proto())
+        } else {
+            Err(atoms::not_ready())
+        }
+    }
+
+    // Whether the handshake went through early data. On a server that means
+    // the client's 0-RTT was accepted; a client only learns that it tried,
+    // as quiche doesn't report the server's decision.
+    pub fn early_data_accepted(&self) -> Result<bool, Atom> {
+        if self.raw.is_established() {
+            Ok(self.early_data_reported)
+        } else {
+            Err(atoms::not_ready())
+        }
+    }
+
+    pub fn set_keylog(&mut self, writer: Box<dyn std::io::Write + Send + Sync>) {
+        self.raw.set_keylog(writer);
+    }
+
+    pub fn trace_id(&self) -> &str {
+        self.raw.trace_id()
+    }
+
+    // Both ids are read fresh from quiche, so they follow any change made
+    // during the connection's lifetime, e.g. the client adopting the
+    // server-chosen DCID once the handshake starts.
+    pub fn source_id(&self) -> quiche::ConnectionId<'_> {
+        self.raw.source_id()
+    }
+
+    pub fn destination_id(&self) -> quiche::ConnectionId<'_> {
+        self.raw.destination_id()
+    }
+
+    pub fn session(&self) -> Option<&[u8]> {
+        self.raw.session()
+    }
+
+    pub fn peer_cert(&self) -> Option<&[u8]> {
+        self.raw.peer_cert()
+    }
+
+    // quiche 0.12 only hands out the leaf, so that's all the chain holds
+    // for now.
+    pub fn peer_cert_chain(&self) -> Vec<&[u8]> {
+        self.raw.peer_cert().into_iter().collect()
+    }
+
+    pub fn server_name(&self) -> Result<&str, Atom> {
+        if self.is_handshake_progressed() {
+            self.raw.server_name().ok_or_else(atoms::not_found)
+        } else {
+            Err(atoms::not_ready())
+        }
+    }
+
     pub fn process_packet(
         &mut self,
         env: &Env,
         pid: &LocalPid,
-        packet: &mut [u8],
-    ) -> Result<u64, Atom> {
+        peer: ResourceArc<Peer>,
+        packet: &[u8],
+    ) -> Result<NextTimeout, Atom> {
         if !self.raw.is_closed() {
-            let info = quiche::RecvInfo {
-                from: self.peer.addr,
-            };
-            match self.raw.recv(packet, info) {
+            match self.recv_copy(packet, &peer) {
                 Ok(_len) => {
-                    self.handle_stream(env, pid);
-                    self.handle_dgram(env, pid);
-                    self.drain(env);
-                    self.next_timeout()
+                    self.handle_migration(env, pid, peer);
+                    self.handle_received(env, pid)
                 }
-                Err(_e) => Err(atoms::system_error()),
+                Err(e) => Err(common::quiche_error_atom(e)),
             }
         } else {
             Err(atoms::already_closed())
         }
     }
 
-    pub fn execute_timeout(&mut self, env: &Env) -> Result<u64, Atom> {
+    // Feeds every packet to quiche before running the handlers and drain
+    // once. A rejected packet doesn't stop the rest of the batch; its index
+    // is returned with the reason instead.
+    pub fn process_packets(
+        &mut self,
+        env: &Env,
+        pid: &LocalPid,
+        peer: ResourceArc<Peer>,
+        packets: &[Binary],
+    ) -> Result<(NextTimeout, Rejected), Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let mut rejected = Vec::new();
+        let mut accepted = false;
+        for (idx, packet) in packets.iter().enumerate() {
+            if self.raw.is_closed() {
+                rejected.push((idx, atoms::already_closed()));
+                continue;
+            }
+            match self.recv_copy(packet.as_slice(), &peer) {
+                Ok(_len) => accepted = true,
+                Err(e) => rejected.push((idx, common::quiche_error_atom(e))),
+            }
+        }
+        if accepted {
+            self.handle_migration(env, pid, peer);
+        }
+        let next_timeout = self.handle_received(env, pid)?;
+        Ok((next_timeout, rejected))
+    }
+
+    fn recv_copy(&mut self, packet: &[u8], peer: &Peer) -> quiche::Result<usize> {
+        self.recv_buf.clear();
+        self.recv_buf.extend_from_slice(packet);
+        let info = quiche::RecvInfo { from: peer.addr };
+        self.telemetry.add(Counter::PacketsReceived, 1);
+        let result = self.raw.recv(&mut self.recv_buf, info);
+        if let Err(e) = result.as_ref() {
+            self.telemetry.recv_error(e);
+        }
+        result
+    }
+
+    // Everything delivered between {:__early_data__} and {:__connected__}
+    // may have come in 0-RTT packets, which an attacker can replay. Data read
+    // in the same call that completes the handshake can't be told apart, so
+    // it's sent ahead of {:__connected__} and counts as early too.
+    fn handle_received(&mut self, env: &Env, pid: &LocalPid) -> Result<NextTimeout, Atom> {
+        if self.reject_missing_peer_cert() {
+            return self.drain_rejected(env, pid);
+        }
+        self.handle_early_data(env, pid);
+        self.handle_stream(env, pid);
+        self.handle_dgram(env, pid);
+        self.handle_established(env, pid);
+        self.handle_writable(env, pid);
+        self.handle_streams_left(env, pid);
+        let drained = self.drain(env, pid);
+        self.handle_close(env, pid);
+        drained?;
+        self.next_timeout()
+    }
+
+    pub fn execute_timeout(&mut self, env: &Env, pid: &LocalPid) -> Result<NextTimeout, Atom> {
         if !self.raw.is_closed() {
             self.raw.on_timeout();
-            self.drain(env);
-            self.next_timeout()
+            if self.reject_missing_peer_cert() {
+                return self.drain_rejected(env, pid);
+            }
+            self.handle_early_data(env, pid);
+            self.handle_established(env, pid);
+            let drained = self.drain(env, pid);
+            self.handle_close(env, pid);
+            drained?;
+            if self.raw.is_closed() {
+                Ok(NextTimeout::Infinity)
+            } else {
+                self.next_timeout()
+            }
         } else {
             Err(atoms::already_closed())
         }
     }
 
+    // Writes the segments in order, with fin only on the last one. Returns the
+    // number of bytes accepted by quiche, which can be less than the total
+    // when flow control doesn't leave enough room. In that case
+    // __stream_writable__ is sent once the stream gets capacity again.
     pub fn send_stream_data(
         &mut self,
         env: &Env,
         stream_id: u64,
-        data: &[u8],
+        segments: &[&[u8]],
         fin: bool,
-    ) -> Result<u64, Atom> {
-        let size = data.len();
+    ) -> Result<(usize, NextTimeout), Atom> {
         if !self.raw.is_closed() {
-            let mut pos = 0;
-            loop {
-                match self.raw.stream_send(stream_id, &data[pos..], fin) {
-                    Ok(len) => {
-                        pos += len;
-                        self.drain(env);
-                        if pos >= size {
-                            break;
+            let size: usize = segments.iter().map(|seg| seg.len()).sum();
+            let last = segments.len().saturating_sub(1);
+            let mut sent = 0;
+            for (idx, data) in segments.iter().enumerate() {
+                let seg_fin = fin && idx == last;
+                let mut pos = 0;
+                loop {
+                    match self.raw.stream_send(stream_id, &data[pos..], seg_fin) {
+                        Ok(len) => {
+                            pos += len;
+                            self.drain(env, &env.pid())?;
+                            if pos >= data.len() {
+                                break;
+                            }
                         }
-                    }
 
-                    Err(quiche::Error::Done) => {
-                        break;
-                    }
+                        Err(quiche::Error::Done) => {
+                            break;
+                        }
 
-                    Err(_e) => {
-                        return Err(atoms::system_error());
+                        Err(e) => {
+                            return Err(common::quiche_error_atom(e));
+                        }
                     }
                 }
+                sent += pos;
+                if pos < data.len() {
+                    break;
+                }
+            }
+            if sent < size {
+                self.blocked_streams.insert(stream_id);
             }
-            self.next_timeout()
+            let next_timeout = self.next_timeout()?;
+            Ok((sent, next_timeout))
         } else {
             Err(atoms::already_closed())
         }
     }
 
-    pub fn send_dgram(&mut self, env: &Env, data: &[u8]) -> Result<u64, Atom> {
+    pub fn send_dgram(&mut self, env: &Env, data: &[u8]) -> Result<NextTimeout, Atom> {
         if !self.raw.is_closed() {
             match self.raw.dgram_send(data) {
                 Ok(()) => {
-                    self.drain(env);
+                    self.drain(env, &env.pid())?;
                     self.next_timeout()
                 }
-                Err(_e) => Err(atoms::system_error()),
+                // send queue is full, try again later
+                Err(quiche::Error::Done) => Err(atoms::would_block()),
+                // peer didn't negotiate DATAGRAM support
+                Err(quiche::Error::InvalidState) => Err(atoms::not_negotiated()),
+                // larger than dgram_max_writable_len
+                Err(quiche::Error::BufferTooShort) => Err(atoms::bad_format()),
+                Err(e) => Err(common::quiche_error_atom(e)),
             }
         } else {
             Err(atoms::already_closed())
         }
     }
 
-    pub fn close(&mut self, env: &Env, app: bool, err: u64, reason: &[u8]) -> Result<u64, Atom> {
-        if !self.raw.is_closed() {
-            match self.raw.close(app, err, reason) {
-                Ok(()) => {
-                    self.drain(env);
-                    self.next_timeout()
+    // Reads at most max_len bytes, capped at the stream buffer size. Reading
+    // reopens the receive window, so the resulting MAX_STREAM_DATA is sent
+    // right away.
+    pub fn recv_stream_data(
+        &mut self,
+        env: &Env,
+        stream_id: u64,
+        max_len: usize,
+    ) -> Result<(OwnedBinary, bool), Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let cap = max_len.min(self.stream_buf.len());
+        match self.raw.stream_recv(stream_id, &mut self.stream_buf[..cap]) {
+            Ok((len, fin)) => {
+                let data = common::owned_binary(&self.stream_buf[..len]);
+                self.telemetry.add(Counter::StreamBytes, len);
+                if !self.raw.stream_readable(stream_id) {
+                    self.readable_streams.remove(&stream_id);
                 }
-
-                Err(quiche::Error::Done) => self.next_timeout(),
-
-                Err(_e) => Err(atoms::system_error()),
+                self.drain(env, &env.pid())?;
+                Ok((data, fin))
             }
+            Err(quiche::Error::Done) => {
+                self.readable_streams.remove(&stream_id);
+                Err(atoms::would_block())
+            }
+            Err(e) => Err(common::quiche_error_atom(e)),
+        }
+    }
+
+    pub fn set_stream_priority(
+        &mut self,
+        stream_id: u64,
+        urgency: u8,
+        incremental: bool,
+    ) -> Result<(), Atom> {
+        if !self.raw.is_closed() {
+            self.raw
+                .stream_priority(stream_id, urgency, incremental)
+                .map_err(common::quiche_error_atom)
         } else {
             Err(atoms::already_closed())
         }
     }
 
+    pub fn readable(&self) -> Vec<u64> {
+        if self.raw.is_closed() || !self.is_handshake_progressed() {
+            return Vec::new();
+        }
+        self.raw.readable().collect()
+    }
+
+    pub fn writable(&self) -> Vec<u64> {
+        if self.raw.is_closed() || !self.is_handshake_progressed() {
+            return Vec::new();
+        }
+        self.raw.writable().collect()
+    }
+
+    pub fn peer_streams_left_bidi(&self) -> u64 {
+        self.raw.peer_streams_left_bidi()
+    }
+
+    pub fn peer_streams_left_uni(&self) -> u64 {
+        self.raw.peer_streams_left_uni()
+    }
+
+    pub fn max_send_udp_payload_size(&self) -> usize {
+        self.raw.max_send_udp_payload_size()
+    }
+
+    pub fn dgram_max_writable_len(&self) -> Option<usize> {
+        self.raw.dgram_max_writable_len()
+    }
+
+    pub fn dgram_send_queue_len(&self) -> usize {
+        self.raw.dgram_send_queue_len()
+    }
+
+    pub fn dgram_send_queue_byte_size(&self) -> usize {
+        self.raw.dgram_send_queue_byte_size()
+    }
+
+    pub fn dgram_recv_queue_len(&self) -> usize {
+        self.raw.dgram_recv_queue_len()
+    }
+
+    pub fn dgram_purge_outgoing(&mut self, prefix: &[u8]) {
+        self.raw
+            .dgram_purge_outgoing(|d: &[u8]| d.starts_with(prefix));
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.raw.is_draining()
+    }
+
+    // Returns None when there's nothing left to wait for: the connection is
+    // already closed or draining, or was closed on the spot. Calling close
+    // more than once is harmless.
+    pub fn close(
+        &mut self,
+        env: &Env,
+        app: bool,
+        err: u64,
+        reason: &[u8],
+    ) -> Result<Option<NextTimeout>, Atom> {
+        if self.raw.is_closed() || self.raw.is_draining() {
+            return Ok(None);
+        }
+        let reason = &reason[..reason.len().min(MAX_CLOSE_REASON_LEN)];
+        match self.raw.close(app, err, reason) {
+            Ok(()) => {
+                self.drain(env, &env.pid())?;
+                if self.raw.is_closed() {
+                    // nothing had been received yet, so quiche closed
+                    // without sending CONNECTION_CLOSE, and no timeout will
+                    // come to report it
+                    self.handle_close(env, &env.pid());
+                    Ok(None)
+                } else {
+                    self.next_timeout().map(Some)
+                }
+            }
+
+            Err(quiche::Error::Done) => self.next_timeout().map(Some),
+
+            Err(e) => Err(common::quiche_error_atom(e)),
+        }
+    }
+
+    // Only a packet that quiche accepted can move the connection to a new
+    // address, so a spoofed source can't redirect our replies.
+    fn handle_migration(&mut self, env: &Env, pid: &LocalPid, peer: ResourceArc<Peer>) {
+        if peer.addr == self.peer.addr {
+            return;
+        }
+        let ip = common::owned_binary(&peer.ip_octets());
+        self.telemetry.send(
+            env,
+            pid,
+            make_tuple(
+                *env,
+                &[
+                    atoms::__peer_migrated__().to_term(*env),
+                    ip.release(*env).to_term(*env),
+                    peer.addr.port().encode(*env),
+                ],
+            ),
+        );
+        self.peer = peer;
+    }
+
+    // Reports handshake progress to the owner exactly once per stage, so the
+    // Elixir side doesn't need to poll is_established.
+    fn handle_early_data(&mut self, env: &Env, pid: &LocalPid) {
+        if !self.early_data_reported && self.raw.is_in_early_data() {
+            self.early_data_reported = true;
+            self.telemetry.send(
+                env,
+                pid,
+                make_tuple(*env, &[atoms::__early_data__().to_term(*env)]),
+            );
+        }
+    }
+
+    // BoringSSL only verifies a client certificate when one is sent, so a
+    // server requiring one refuses the handshake here instead, closing with
+    // certificate_required and failing the call with tls_fail.
+    // Checked before anything is delivered, so a client that was required to
+    // present a certificate and didn't never gets its stream data or
+    // datagrams to the owner, even those that came with its Finished.
+    fn reject_missing_peer_cert(&mut self) -> bool {
+        if self.established_reported
+            || !self.require_peer_cert
+            || !self.raw.is_established()
+            || self.raw.peer_cert().is_some()
+        {
+            return false;
+        }
+        self.established_reported = true;
+        let _ = self
+            .raw
+            .close(false, CERTIFICATE_REQUIRED, b"certificate required");
+        true
+    }
+
+    // Sends the CONNECTION_CLOSE and reports it; nothing else runs.
+    fn drain_rejected(&mut self, env: &Env, pid: &LocalPid) -> Result<NextTimeout, Atom> {
+        let drained = self.drain(env, pid);
+        self.handle_close(env, pid);
+        drained?;
+        Err(atoms::tls_fail())
+    }
+
+    fn handle_established(&mut self, env: &Env, pid: &LocalPid) {
+        if !self.established_reported && self.raw.is_established() {
+            self.established_reported = true;
+            let alpn = common::owned_binary(self.raw.application_proto());
+            self.telemetry.send(
+                env,
+                pid,
+                make_tuple(
+                    *env,
+                    &[
+                        atoms::__connected__().to_term(*env),
+                        alpn.release(*env).to_term(*env),
+                    ],
+                ),
+            );
+        }
+    }
+
+    // Sends {:__closed__, is_app, error_code, reason} once the connection
+    // starts draining or is closed. The peer's CONNECTION_CLOSE takes
+    // precedence over our own, and an idle timeout is reported as (false, 0).
+    fn handle_close(&mut self, env: &Env, pid: &LocalPid) {
+        if self.closed_reported || !(self.raw.is_draining() || self.raw.is_closed()) {
+            return;
+        }
+        self.closed_reported = true;
+
+        let (is_app, error_code, reason) =
+            match self.raw.peer_error().or_else(|| self.raw.local_error()) {
+                Some(e) => (e.is_app, e.error_code, e.reason.as_slice()),
+                None => (false, 0, &[][..]),
+            };
+        let reason = common::owned_binary(reason);
+        self.telemetry.send(
+            env,
+            pid,
+            make_tuple(
+                *env,
+                &[
+                    atoms::__closed__().to_term(*env),
+                    is_app.encode(*env),
+                    error_code.encode(*env),
+                    reason.release(*env).to_term(*env),
+                ],
+            ),
+        );
+    }
+
+    // Everything readable on a stream is delivered as a single __stream_recv__,
+    // so a large body doesn't turn into one message per buffer-sized chunk.
+    // In pull mode only {:__stream_readable__, stream_id} is sent, once until
+    // the owner has read the stream dry with stream_recv.
     fn handle_stream(&mut self, env: &Env, pid: &LocalPid) {
+        #[cfg(feature = "h3")]
+        if self.h3.is_some() || self.h3_pending.is_some() {
+            return self.handle_h3(env, pid);
+        }
         if self.raw.is_in_early_data() || self.raw.is_established() {
-            for sid in self.raw.readable() {
-                while let Ok((len, _fin)) = self.raw.stream_recv(sid, &mut self.stream_buf) {
-                    if len > 0 {
-                        let mut data = OwnedBinary::new(len).unwrap();
-                        data.as_mut_slice().copy_from_slice(&self.stream_buf[..len]);
-                        env.send(
+            if self.pull_streams {
+                for sid in self.raw.readable() {
+                    if self.readable_streams.insert(sid) {
+                        self.telemetry.send(
+                            env,
                             pid,
                             make_tuple(
                                 *env,
-                                &[
-                                    atoms::__stream_recv__().to_term(*env),
-                                    sid.encode(*env),
-                                    data.release(*env).to_term(*env),
-                                ],
+                                &[atoms::__stream_readable__().to_term(*env), sid.encode(*env)],
                             ),
                         );
                     }
                 }
+                return;
             }
+
+            // read straight into the binary that's sent, growing it by
+            // stream_buf's size at a time
+            let chunk = self.stream_buf.len();
+            for sid in self.raw.readable() {
+                let mut data = OwnedBinary::new(chunk).unwrap();
+                let mut filled = 0;
+                loop {
+                    if filled == data.len() {
+                        data.realloc_or_copy(filled + chunk);
+                    }
+                    match self
+                        .raw
+                        .stream_recv(sid, &mut data.as_mut_slice()[filled..])
+                    {
+                        Ok((len, _fin)) => filled += len,
+                        Err(_) => break,
+                    }
+                }
+                if filled > 0 {
+                    data.realloc_or_copy(filled);
+                    self.telemetry.add(Counter::StreamBytes, filled);
+                    self.telemetry.send(
+                        env,
+                        pid,
+                        make_tuple(
+                            *env,
+                            &[
+                                atoms::__stream_recv__().to_term(*env),
+                                sid.encode(*env),
+                                data.release(*env).to_term(*env),
+                            ],
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn handle_writable(&mut self, env: &Env, pid: &LocalPid) {
+        if self.blocked_streams.is_empty() {
+            return;
+        }
+        for sid in self.raw.writable() {
+            if self.blocked_streams.remove(&sid) {
+                self.telemetry.send(
+                    env,
+                    pid,
+                    make_tuple(
+                        *env,
+                        &[atoms::__stream_writable__().to_term(*env), sid.encode(*env)],
+                    ),
+                );
+            }
+        }
+    }
+
+    // The peer raised its MAX_STREAMS limit if the budget grew since the
+    // last packet, which is when queued stream opens can be retried.
+    fn handle_streams_left(&mut self, env: &Env, pid: &LocalPid) {
+        let bidi = self.raw.peer_streams_left_bidi();
+        let uni = self.raw.peer_streams_left_uni();
+        if bidi > self.peer_streams_left_bidi || uni > self.peer_streams_left_uni {
+            self.telemetry.send(
+                env,
+                pid,
+                make_tuple(
+                    *env,
+                    &[
+                        atoms::__peer_streams_left__().to_term(*env),
+                        bidi.encode(*env),
+                        uni.encode(*env),
+                    ],
+                ),
+            );
         }
+        self.peer_streams_left_bidi = bidi;
+        self.peer_streams_left_uni = uni;
     }
 
     fn handle_dgram(&mut self, env: &Env, pid: &LocalPid) {
         if self.raw.is_in_early_data() || self.raw.is_established() {
-            while let Ok(len) = self.raw.dgram_recv(&mut self.dgram_buf) {
+            // sized from the queue, as a DATAGRAM frame can be as large as
+            // whatever datagram size the socket layer lets through
+            while let Some(len) = self.raw.dgram_recv_front_len() {
+                let mut data = OwnedBinary::new(len).unwrap();
+                if self.raw.dgram_recv(data.as_mut_slice()).is_err() {
+                    break;
+                }
                 if len > 0 {
-                    let mut data = OwnedBinary::new(len).unwrap();
-                    data.as_mut_slice().copy_from_slice(&self.dgram_buf[..len]);
-
-                    env.send(
+                    self.telemetry.add(Counter::Dgrams, 1);
+                    self.telemetry.send(
+                        env,
                         pid,
                         make_tuple(
                             *env,
@@ -192,50 +771,135 @@ impl Connection {
         }
     }
 
-    fn drain(&mut self, env: &Env) {
+    // Packets written in one call go out as {:__drain__, ip, port, [packet],
+    // delay}, where delay is how many milliseconds the pacer wants them held
+    // back. Consecutive packets with the same delay share one message. If
+    // quiche fails to write a packet the connection is closed, and pid gets
+    // {:__drain_error__, reason, trace_id} in addition to the error returned
+    // here, so the failure isn't only noticed later as already_closed.
+    pub(crate) fn drain(&mut self, env: &Env, pid: &LocalPid) -> Result<(), Atom> {
+        let mut packets = Vec::new();
+        let mut delay = 0;
+        let mut result = Ok(());
         loop {
-            match self.raw.send(&mut self.dgram_buf) {
-                Ok((len, _send_info)) => {
-                    let mut packet = OwnedBinary::new(len).unwrap();
-                    packet
-                        .as_mut_slice()
-                        .copy_from_slice(&self.dgram_buf[..len]);
-                    env.send(
-                        &self.sender,
-                        make_tuple(
-                            *env,
-                            &[
-                                atoms::__drain__().to_term(*env),
-                                self.peer.encode(*env),
-                                packet.release(*env).to_term(*env),
-                            ],
-                        ),
-                    );
+            // quiche writes into the binary that's sent, which is then cut
+            // down to the packet's length
+            let mut packet = match self.spare_packet.take() {
+                Some(packet) => packet,
+                None => OwnedBinary::new(self.max_udp_payload_size).unwrap(),
+            };
+            match self.raw.send(packet.as_mut_slice()) {
+                // SendInfo.to is always the address the connection was
+                // created with in this quiche version, so it would undo a
+                // migration picked up in process_packet. Use our own peer.
+                Ok((len, send_info)) => {
+                    let at = send_info.at.saturating_duration_since(Instant::now());
+                    let at = u64::try_from(at.as_millis()).unwrap_or(u64::MAX);
+                    if at != delay && !packets.is_empty() {
+                        self.send_drained(env, &packets, delay);
+                        packets.clear();
+                    }
+                    delay = at;
+                    packet.realloc_or_copy(len);
+                    self.telemetry.add(Counter::PacketsDrained, 1);
+                    packets.push(packet.release(*env).to_term(*env));
                 }
                 Err(quiche::Error::Done) => {
+                    self.spare_packet = Some(packet);
                     break;
                 }
-                Err(_e) => {
+                Err(e) => {
                     self.raw.close(false, 0x1, b"fail").ok();
+                    result = Err(common::quiche_error_atom(e));
                     break;
                 }
             }
         }
+        if !packets.is_empty() {
+            self.send_drained(env, &packets, delay);
+        }
+        if let Err(reason) = result {
+            let trace_id = common::owned_binary(self.raw.trace_id().as_bytes());
+            self.telemetry.send(
+                env,
+                pid,
+                make_tuple(
+                    *env,
+                    &[
+                        atoms::__drain_error__().to_term(*env),
+                        reason.to_term(*env),
+                        trace_id.release(*env).to_term(*env),
+                    ],
+                ),
+            );
+        }
+        result
     }
 
-    fn next_timeout(&mut self) -> Result<u64, Atom> {
+    fn send_drained(&self, env: &Env, packets: &[Term], delay: u64) {
+        let ip = common::owned_binary(&self.peer.ip_octets());
+        self.telemetry.send(
+            env,
+            &self.sender,
+            make_tuple(
+                *env,
+                &[
+                    atoms::__drain__().to_term(*env),
+                    ip.release(*env).to_term(*env),
+                    self.peer.addr.port().encode(*env),
+                    packets.encode(*env),
+                    delay.encode(*env),
+                ],
+            ),
+        );
+    }
+
+    pub(crate) fn next_timeout(&mut self) -> Result<NextTimeout, Atom> {
         if let Some(timeout) = self.raw.timeout() {
-            let to: u64 = timeout.as_millis() as u64;
-            Ok(to)
+            let to = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+            Ok(NextTimeout::After(to))
         } else if self.raw.is_closed() {
             Err(atoms::already_closed())
         } else {
-            // unreachable if 'idle_timeout' is set
-            Ok(60000)
+            Ok(NextTimeout::Infinity)
         }
     }
 }
 
+// connection_destroy can run while the connection is still open, e.g. when
+// the owning process crashed. Close it so the quiche state is torn down in a
+// defined way. The final packets are discarded since there's no env to send
+// them with, and the peer will notice through its idle timeout.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !self.raw.is_closed() {
+            self.raw.close(false, 0x0, b"dropped").ok();
+        }
+        self.telemetry.connection_dropped();
+    }
+}
+
+fn check_new_conn_args(
+    cids: &[&[u8]],
+    stream_buf_size: u64,
+    max_udp_payload_size: u64,
+) -> Result<(usize, usize), Atom> {
+    if cids.iter().any(|cid| cid.len() > quiche::MAX_CONN_ID_LEN) {
+        return Err(atoms::bad_format());
+    }
+    // the send buffer is allocated up front from it, so anything beyond
+    // what a datagram can carry is refused instead of risking an allocation
+    // that takes the VM down
+    if !(1200..=common::MAX_UDP_PAYLOAD_SIZE as u64).contains(&max_udp_payload_size) {
+        return Err(atoms::bad_format());
+    }
+    if !(1..=MAX_STREAM_BUF_SIZE).contains(&stream_buf_size) {
+        return Err(atoms::bad_format());
+    }
+    Ok((stream_buf_size as usize, max_udp_payload_size as usize))
+}
+
+#[allow(clippy::too_many_arguments)]
 #[rustler::nif]
 pub fn connection_accept(
     conf_ptr: i64,
@@ -244,82 +908,359 @@ pub fn connection_accept(
     peer: ResourceArc<Peer>,
     sender_pid: LocalPid,
     stream_buf_size: u64,
+    max_udp_payload_size: u64,
+    pull_streams: bool,
 ) -> NifResult<(Atom, i64)> {
     let scid = scid.as_slice();
     let odcid = odcid.as_slice();
+    let (stream_buf_size, max_udp_payload_size) =
+        check_new_conn_args(&[scid, odcid], stream_buf_size, max_udp_payload_size)
+            .map_err(common::error_term)?;
 
-    let conf_ptr = conf_ptr as *mut quiche::Config;
+    let conf_ptr = conf_ptr as *mut Config;
     let conf = unsafe { &mut *conf_ptr };
 
     let scid = quiche::ConnectionId::from_ref(scid);
-    let odcid = quiche::ConnectionId::from_ref(odcid);
 
-    match quiche::accept(&scid, Some(&odcid), peer.addr, conf) {
+    // An empty odcid means no retry took place, so the peer's address is
+    // left unvalidated and only the anti-amplification limit protects
+    // against spoofed sources until the handshake completes.
+    let odcid = if odcid.is_empty() {
+        None
+    } else {
+        Some(quiche::ConnectionId::from_ref(odcid))
+    };
+
+    match quiche::accept(&scid, odcid.as_ref(), peer.addr, &mut conf.raw) {
         Ok(raw_conn) => {
-            let conn = Connection::new(raw_conn, peer, sender_pid, stream_buf_size as usize);
-            Ok((atoms::ok(), Box::into_raw(Box::new(conn)) as i64))
+            let conn = Connection::new(
+                raw_conn,
+                peer,
+                sender_pid,
+                stream_buf_size,
+                max_udp_payload_size,
+                pull_streams,
+                conf.requires_peer_cert(),
+                conf.telemetry.clone(),
+            );
+            conn.telemetry.add(Counter::Accepted, 1);
+            Ok((atoms::ok(), into_handle(conn)))
         }
 
-        Err(_) => Err(common::error_term(atoms::system_error())),
+        Err(e) => Err(common::error_term(common::quiche_error_atom(e))),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif]
+pub fn connection_connect(
+    env: Env,
+    conf_ptr: i64,
+    server_name: Binary,
+    scid: Binary,
+    peer: ResourceArc<Peer>,
+    sender_pid: LocalPid,
+    stream_buf_size: u64,
+    max_udp_payload_size: u64,
+    pull_streams: bool,
+    session: Binary,
+) -> NifResult<(Atom, i64)> {
+    let server_name = if server_name.is_empty() {
+        None
+    } else {
+        Some(
+            str::from_utf8(server_name.as_slice())
+                .map_err(|_| common::error_term(atoms::bad_format()))?,
+        )
+    };
+
+    let (stream_buf_size, max_udp_payload_size) =
+        check_new_conn_args(&[scid.as_slice()], stream_buf_size, max_udp_payload_size)
+            .map_err(common::error_term)?;
+
+    let conf_ptr = conf_ptr as *mut Config;
+    let conf = unsafe { &mut *conf_ptr };
+
+    let scid = quiche::ConnectionId::from_ref(scid.as_slice());
+
+    let mut raw_conn = quiche::connect(server_name, &scid, peer.addr, &mut conf.raw)
+        .map_err(|e| common::error_term(common::quiche_error_atom(e)))?;
+
+    // the session has to be set before the first flight is written
+    if !session.is_empty() {
+        raw_conn
+            .set_session(session.as_slice())
+            .map_err(|e| common::error_term(common::quiche_error_atom(e)))?;
     }
+
+    let mut conn = Connection::new(
+        raw_conn,
+        peer,
+        sender_pid,
+        stream_buf_size,
+        max_udp_payload_size,
+        pull_streams,
+        false,
+        conf.telemetry.clone(),
+    );
+
+    // client speaks first
+    conn.drain(&env, &env.pid()).map_err(common::error_term)?;
+
+    Ok((atoms::ok(), into_handle(conn)))
 }
 
 #[rustler::nif]
 pub fn connection_destroy(conn_ptr: i64) -> NifResult<Atom> {
-    let conn_ptr = conn_ptr as *mut Connection;
-    unsafe { drop(Box::from_raw(conn_ptr)) };
+    let conn = unsafe { Arc::from_raw(conn_ptr as *const Mutex<Connection>) };
+    let mut locked = conn.lock();
+    let dcids = mem::take(&mut locked.registered_dcids);
+    registry::unregister_all(&conn, dcids);
+    drop(locked);
     Ok(atoms::ok())
 }
 
 #[rustler::nif]
-pub fn connection_close(
-    env: Env,
+pub fn connection_close<'a>(
+    env: Env<'a>,
     conn_ptr: i64,
     app: bool,
     err: u64,
     reason: Binary,
-) -> NifResult<(Atom, u64)> {
-    let conn_ptr = conn_ptr as *mut Connection;
-    let conn = unsafe { &mut *conn_ptr };
+) -> NifResult<Term<'a>> {
+    let mut conn = lock_connection(conn_ptr);
 
     match conn.close(&env, app, err, reason.as_slice()) {
-        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Ok(Some(next_timeout)) => Ok((atoms::ok(), next_timeout).encode(env)),
+        Ok(None) => Ok(atoms::ok().encode(env)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
 #[rustler::nif]
 pub fn connection_is_closed(conn_ptr: i64) -> bool {
-    let conn_ptr = conn_ptr as *mut Connection;
-    let conn = unsafe { &mut *conn_ptr };
+    let conn = lock_connection(conn_ptr);
     conn.is_closed()
 }
 
+#[rustler::nif]
+pub fn connection_is_draining(conn_ptr: i64) -> bool {
+    let conn = lock_connection(conn_ptr);
+    conn.is_draining()
+}
+
+#[rustler::nif]
+pub fn connection_is_established(conn_ptr: i64) -> bool {
+    let conn = lock_connection(conn_ptr);
+    conn.is_established()
+}
+
+#[rustler::nif]
+pub fn connection_is_in_early_data(conn_ptr: i64) -> bool {
+    let conn = lock_connection(conn_ptr);
+    conn.is_in_early_data()
+}
+
+#[rustler::nif]
+pub fn connection_is_resumed(conn_ptr: i64) -> bool {
+    let conn = lock_connection(conn_ptr);
+    conn.is_resumed()
+}
+
+#[rustler::nif]
+pub fn connection_stats(env: Env, conn_ptr: i64) -> NifResult<(Atom, Term)> {
+    let conn = lock_connection(conn_ptr);
+
+    // works on closed connections too, so the final numbers can be collected
+    let stats = conn.stats();
+    let rtt = u64::try_from(stats.rtt.as_micros()).unwrap_or(u64::MAX);
+
+    let keys = [
+        atoms::recv().to_term(env),
+        atoms::sent().to_term(env),
+        atoms::lost().to_term(env),
+        atoms::retrans().to_term(env),
+        atoms::rtt().to_term(env),
+        atoms::cwnd().to_term(env),
+        atoms::sent_bytes().to_term(env),
+        atoms::recv_bytes().to_term(env),
+        atoms::lost_bytes().to_term(env),
+        atoms::stream_retrans_bytes().to_term(env),
+        atoms::pmtu().to_term(env),
+        atoms::delivery_rate().to_term(env),
+        atoms::peer_active_conn_id_limit().to_term(env),
+    ];
+    let values = [
+        (stats.recv as u64).encode(env),
+        (stats.sent as u64).encode(env),
+        (stats.lost as u64).encode(env),
+        (stats.retrans as u64).encode(env),
+        rtt.encode(env),
+        (stats.cwnd as u64).encode(env),
+        stats.sent_bytes.encode(env),
+        stats.recv_bytes.encode(env),
+        stats.lost_bytes.encode(env),
+        stats.stream_retrans_bytes.encode(env),
+        (stats.pmtu as u64).encode(env),
+        stats.delivery_rate.encode(env),
+        stats.peer_active_conn_id_limit.encode(env),
+    ];
+
+    let map = Term::map_from_arrays(env, &keys, &values)?;
+    Ok((atoms::ok(), map))
+}
+
+#[rustler::nif]
+pub fn connection_application_proto(env: Env, conn_ptr: i64) -> NifResult<(Atom, Binary)> {
+    let conn = lock_connection(conn_ptr);
+
+    match conn.application_proto() {
+        Ok(proto) => Ok((atoms::ok(), common::owned_binary(proto).release(env))),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_server_name(env: Env, conn_ptr: i64) -> NifResult<(Atom, Binary)> {
+    let conn = lock_connection(conn_ptr);
+
+    match conn.server_name() {
+        Ok(name) => Ok((
+            atoms::ok(),
+            common::owned_binary(name.as_bytes()).release(env),
+        )),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_is_early_data_accepted(conn_ptr: i64) -> NifResult<(Atom, bool)> {
+    let conn = lock_connection(conn_ptr);
+
+    match conn.early_data_accepted() {
+        Ok(accepted) => Ok((atoms::ok(), accepted)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+// Writes TLS secrets in NSS key log format, for decrypting captures. The file
+// is appended to, so several connections can share it. Needs
+// config_log_keys, and should be set before the handshake gets going.
+// Fails with not_found when the directory doesn't exist and eacces when the
+// file can't be written to.
+#[rustler::nif]
+pub fn connection_set_keylog_path(conn_ptr: i64, path: Binary) -> NifResult<Atom> {
+    let mut conn = lock_connection(conn_ptr);
+
+    let path =
+        str::from_utf8(path.as_slice()).map_err(|_| common::error_term(atoms::bad_format()))?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| {
+            common::error_term(match e.kind() {
+                io::ErrorKind::NotFound => atoms::not_found(),
+                io::ErrorKind::PermissionDenied => atoms::eacces(),
+                _ => atoms::system_error(),
+            })
+        })?;
+    conn.set_keylog(Box::new(file));
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_trace_id(env: Env, conn_ptr: i64) -> Binary {
+    let conn = lock_connection(conn_ptr);
+    common::owned_binary(conn.trace_id().as_bytes()).release(env)
+}
+
+#[rustler::nif]
+pub fn connection_source_id(env: Env, conn_ptr: i64) -> Binary {
+    let conn = lock_connection(conn_ptr);
+    common::owned_binary(conn.source_id().as_ref()).release(env)
+}
+
+#[rustler::nif]
+pub fn connection_destination_id(env: Env, conn_ptr: i64) -> Binary {
+    let conn = lock_connection(conn_ptr);
+    common::owned_binary(conn.destination_id().as_ref()).release(env)
+}
+
+#[rustler::nif]
+pub fn connection_session(env: Env, conn_ptr: i64) -> NifResult<(Atom, Binary)> {
+    let conn = lock_connection(conn_ptr);
+
+    match conn.session() {
+        Some(session) => Ok((atoms::ok(), common::owned_binary(session).release(env))),
+        None => Err(common::error_term(atoms::not_found())),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_peer_cert(env: Env, conn_ptr: i64) -> NifResult<(Atom, Binary)> {
+    let conn = lock_connection(conn_ptr);
+
+    match conn.peer_cert() {
+        Some(der) => Ok((atoms::ok(), common::owned_binary(der).release(env))),
+        None => Err(common::error_term(atoms::not_found())),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_peer_cert_chain(env: Env, conn_ptr: i64) -> NifResult<(Atom, Vec<Binary>)> {
+    let conn = lock_connection(conn_ptr);
+
+    let chain = conn
+        .peer_cert_chain()
+        .into_iter()
+        .map(|der| common::owned_binary(der).release(env))
+        .collect();
+    Ok((atoms::ok(), chain))
+}
+
 #[rustler::nif]
 pub fn connection_on_packet(
     env: Env,
     pid: LocalPid,
     conn_ptr: i64,
+    peer: ResourceArc<Peer>,
     packet: Binary,
-) -> NifResult<(Atom, u64)> {
-    let conn_ptr = conn_ptr as *mut Connection;
-    let conn = unsafe { &mut *conn_ptr };
+) -> NifResult<(Atom, NextTimeout)> {
+    let mut conn = lock_connection(conn_ptr);
 
-    let mut packet = packet.to_owned().unwrap();
-
-    match conn.process_packet(&env, &pid, &mut packet.as_mut_slice()) {
+    match conn.process_packet(&env, &pid, peer, packet.as_slice()) {
         Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
 #[rustler::nif]
-pub fn connection_on_timeout(env: Env, conn_ptr: i64) -> NifResult<(Atom, u64)> {
-    let conn_ptr = conn_ptr as *mut Connection;
-    let conn = unsafe { &mut *conn_ptr };
+pub fn connection_on_packets(
+    env: Env,
+    pid: LocalPid,
+    conn_ptr: i64,
+    peer: ResourceArc<Peer>,
+    packets: Vec<Binary>,
+) -> NifResult<(Atom, NextTimeout, Rejected)> {
+    let mut conn = lock_connection(conn_ptr);
 
-    match conn.execute_timeout(&env) {
-        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+    match conn.process_packets(&env, &pid, peer, &packets) {
+        Ok((next_timeout, rejected)) => Ok((atoms::ok(), next_timeout, rejected)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_on_timeout(env: Env, pid: LocalPid, conn_ptr: i64) -> NifResult<Term> {
+    let mut conn = lock_connection(conn_ptr);
+
+    match conn.execute_timeout(&env, &pid) {
+        Ok(_) if conn.is_closed() => Ok((atoms::ok(), atoms::closed()).encode(env)),
+        Ok(next_timeout) if conn.is_draining() => {
+            Ok((atoms::ok(), atoms::draining(), next_timeout).encode(env))
+        }
+        Ok(next_timeout) => Ok((atoms::ok(), atoms::active(), next_timeout).encode(env)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
@@ -329,24 +1270,150 @@ pub fn connection_stream_send(
     env: Env,
     conn_ptr: i64,
     stream_id: u64,
-    data: Binary,
+    data: Term,
     fin: bool,
-) -> NifResult<(Atom, u64)> {
-    let conn_ptr = conn_ptr as *mut Connection;
-    let conn = unsafe { &mut *conn_ptr };
+) -> NifResult<(Atom, u64, NextTimeout)> {
+    let mut conn = lock_connection(conn_ptr);
 
-    match conn.send_stream_data(&env, stream_id, data.as_slice(), fin) {
-        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+    let segments = common::binary_segments(data).map_err(common::error_term)?;
+    let mut segments: Vec<&[u8]> = segments.iter().map(|seg| seg.as_slice()).collect();
+    if segments.is_empty() {
+        // still has to carry fin
+        segments.push(&[]);
+    }
+    let size: usize = segments.iter().map(|seg| seg.len()).sum();
+
+    match conn.send_stream_data(&env, stream_id, &segments, fin) {
+        Ok((sent, next_timeout)) if sent < size => {
+            Ok((atoms::partial(), sent as u64, next_timeout))
+        }
+        Ok((sent, next_timeout)) => Ok((atoms::ok(), sent as u64, next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_stream_recv(
+    env: Env,
+    conn_ptr: i64,
+    stream_id: u64,
+    max_len: u64,
+) -> NifResult<(Atom, Binary, bool)> {
+    let mut conn = lock_connection(conn_ptr);
+
+    match conn.recv_stream_data(&env, stream_id, max_len as usize) {
+        Ok((data, fin)) => Ok((atoms::ok(), data.release(env), fin)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_stream_priority(
+    conn_ptr: i64,
+    stream_id: u64,
+    urgency: u64,
+    incremental: bool,
+) -> NifResult<Atom> {
+    let urgency = u8::try_from(urgency).map_err(|_| common::error_term(atoms::bad_format()))?;
+
+    let mut conn = lock_connection(conn_ptr);
+
+    match conn.set_stream_priority(stream_id, urgency, incremental) {
+        Ok(()) => Ok(atoms::ok()),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
 #[rustler::nif]
-pub fn connection_dgram_send(env: Env, conn_ptr: i64, data: Binary) -> NifResult<(Atom, u64)> {
-    let conn_ptr = conn_ptr as *mut Connection;
-    let conn = unsafe { &mut *conn_ptr };
-    match conn.send_dgram(&env, data.as_slice()) {
+pub fn connection_dgram_send(
+    env: Env,
+    conn_ptr: i64,
+    data: Term,
+) -> NifResult<(Atom, NextTimeout)> {
+    let mut conn = lock_connection(conn_ptr);
+
+    // a datagram is sent as one unit, so segments have to be joined here
+    let segments = common::binary_segments(data).map_err(common::error_term)?;
+    let joined;
+    let data = match segments.as_slice() {
+        [single] => single.as_slice(),
+        _ => {
+            joined = segments
+                .iter()
+                .flat_map(|seg| seg.iter().copied())
+                .collect::<Vec<u8>>();
+            joined.as_slice()
+        }
+    };
+
+    match conn.send_dgram(&env, data) {
         Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
+
+#[rustler::nif]
+pub fn connection_readable(conn_ptr: i64) -> Vec<u64> {
+    let conn = lock_connection(conn_ptr);
+    conn.readable()
+}
+
+#[rustler::nif]
+pub fn connection_writable(conn_ptr: i64) -> Vec<u64> {
+    let conn = lock_connection(conn_ptr);
+    conn.writable()
+}
+
+#[rustler::nif]
+pub fn connection_peer_streams_left_bidi(conn_ptr: i64) -> u64 {
+    let conn = lock_connection(conn_ptr);
+    conn.peer_streams_left_bidi()
+}
+
+#[rustler::nif]
+pub fn connection_peer_streams_left_uni(conn_ptr: i64) -> u64 {
+    let conn = lock_connection(conn_ptr);
+    conn.peer_streams_left_uni()
+}
+
+// 1200 until the handshake completes, then the smaller of our send limit
+// and what the peer accepts.
+#[rustler::nif]
+pub fn connection_max_send_udp_payload_size(conn_ptr: i64) -> u64 {
+    let conn = lock_connection(conn_ptr);
+    conn.max_send_udp_payload_size() as u64
+}
+
+#[rustler::nif]
+pub fn connection_dgram_max_writable_len(conn_ptr: i64) -> NifResult<(Atom, u64)> {
+    let conn = lock_connection(conn_ptr);
+    match conn.dgram_max_writable_len() {
+        Some(len) => Ok((atoms::ok(), len as u64)),
+        None => Err(common::error_term(atoms::not_negotiated())),
+    }
+}
+
+#[rustler::nif]
+pub fn connection_dgram_send_queue_len(conn_ptr: i64) -> u64 {
+    let conn = lock_connection(conn_ptr);
+    conn.dgram_send_queue_len() as u64
+}
+
+#[rustler::nif]
+pub fn connection_dgram_send_queue_byte_size(conn_ptr: i64) -> u64 {
+    let conn = lock_connection(conn_ptr);
+    conn.dgram_send_queue_byte_size() as u64
+}
+
+#[rustler::nif]
+pub fn connection_dgram_recv_queue_len(conn_ptr: i64) -> u64 {
+    let conn = lock_connection(conn_ptr);
+    conn.dgram_recv_queue_len() as u64
+}
+
+#[rustler::nif]
+pub fn connection_dgram_purge_outgoing(conn_ptr: i64, prefix: Binary) -> Atom {
+    let mut conn = lock_connection(conn_ptr);
+    conn.dgram_purge_outgoing(prefix.as_slice());
+    atoms::ok()
+}
@@ -0,0 +1,477 @@
+// HTTP/3 on top of a connection, through quiche's h3 module. Only built with
+// the h3 feature; without it every NIF here returns not_supported.
+//
+// Once a connection has HTTP/3 attached its streams belong to the h3 layer:
+// instead of __stream_recv__ (or __stream_readable__) the owner gets
+//   {:__h3_headers__, stream_id, [{name, value}]}
+//   {:__h3_data__, stream_id}, after which h3_recv_body reads the body
+//   {:__h3_finished__, stream_id}
+//   {:__h3_reset__, stream_id, error_code}
+//   {:__h3_goaway__, id}
+// and sending goes through h3_send_request, h3_send_response and
+// h3_send_body rather than stream_send.
+
+#[cfg(feature = "h3")]
+use quiche::h3::NameValue;
+#[cfg(feature = "h3")]
+use rustler::types::binary::{Binary, OwnedBinary};
+#[cfg(feature = "h3")]
+use rustler::types::tuple::make_tuple;
+#[cfg(feature = "h3")]
+use rustler::types::{Encoder, LocalPid};
+#[cfg(feature = "h3")]
+use rustler::Env;
+use rustler::{Atom, NifResult, Term};
+
+use crate::common::{self, atoms};
+#[cfg(feature = "h3")]
+use crate::connection::{lock_connection, Connection, NextTimeout};
+#[cfg(feature = "h3")]
+use crate::telemetry::Counter;
+
+#[cfg(feature = "h3")]
+type Headers<'a> = Vec<(Binary<'a>, Binary<'a>)>;
+
+// What h3_config_* set, kept apart from quiche::h3::Config so a connection
+// can hold on to a copy until its handshake is far enough along to attach.
+#[cfg(feature = "h3")]
+#[derive(Clone, Copy, Default)]
+pub struct H3Config {
+    max_field_section_size: Option<u64>,
+    qpack_max_table_capacity: Option<u64>,
+    qpack_blocked_streams: Option<u64>,
+}
+
+#[cfg(feature = "h3")]
+impl H3Config {
+    fn build(&self) -> Result<quiche::h3::Config, quiche::h3::Error> {
+        let mut config = quiche::h3::Config::new()?;
+        if let Some(v) = self.max_field_section_size {
+            config.set_max_field_section_size(v);
+        }
+        if let Some(v) = self.qpack_max_table_capacity {
+            config.set_qpack_max_table_capacity(v);
+        }
+        if let Some(v) = self.qpack_blocked_streams {
+            config.set_qpack_blocked_streams(v);
+        }
+        Ok(config)
+    }
+}
+
+// No wildcard arm, as with quiche_error_atom.
+#[cfg(feature = "h3")]
+fn h3_error_atom(err: quiche::h3::Error) -> Atom {
+    use quiche::h3::Error;
+    match err {
+        Error::Done => atoms::done(),
+        Error::BufferTooShort => atoms::buffer_too_short(),
+        Error::InternalError => atoms::internal_error(),
+        Error::ExcessiveLoad => atoms::excessive_load(),
+        Error::IdError => atoms::id_error(),
+        Error::StreamCreationError => atoms::stream_creation_error(),
+        Error::ClosedCriticalStream => atoms::closed_critical_stream(),
+        Error::MissingSettings => atoms::missing_settings(),
+        Error::FrameUnexpected => atoms::frame_unexpected(),
+        Error::FrameError => atoms::frame_error(),
+        Error::QpackDecompressionFailed => atoms::qpack_decompression_failed(),
+        Error::TransportError(e) => common::quiche_error_atom(e),
+        Error::StreamBlocked => atoms::stream_blocked(),
+        Error::SettingsError => atoms::settings_error(),
+        Error::RequestRejected => atoms::request_rejected(),
+        Error::RequestCancelled => atoms::request_cancelled(),
+        Error::RequestIncomplete => atoms::request_incomplete(),
+        Error::MessageError => atoms::message_error(),
+        Error::ConnectError => atoms::connect_error(),
+        Error::VersionFallback => atoms::version_fallback(),
+    }
+}
+
+#[cfg(feature = "h3")]
+fn to_headers<'a>(headers: &'a [(Binary, Binary)]) -> Vec<quiche::h3::HeaderRef<'a>> {
+    headers
+        .iter()
+        .map(|(name, value)| quiche::h3::HeaderRef::new(name.as_slice(), value.as_slice()))
+        .collect()
+}
+
+#[cfg(feature = "h3")]
+impl Connection {
+    // The control and QPACK streams can only be opened once the handshake is
+    // far enough along. Until then the config is kept, and the streams are
+    // left alone so the peer's SETTINGS isn't delivered as plain stream data.
+    fn attach_h3(&mut self, env: &Env, config: H3Config) -> Result<NextTimeout, Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        if self.h3.is_some() || self.h3_pending.is_some() {
+            return Err(atoms::already_exists());
+        }
+        if !self.is_handshake_progressed() {
+            self.h3_pending = Some(config);
+            return self.next_timeout();
+        }
+        let config = config.build().map_err(h3_error_atom)?;
+        let h3 = quiche::h3::Connection::with_transport(&mut self.raw, &config)
+            .map_err(h3_error_atom)?;
+        self.h3 = Some(h3);
+        self.drain(env, &env.pid())?;
+        self.next_timeout()
+    }
+
+    fn send_h3_request(
+        &mut self,
+        env: &Env,
+        headers: &[(Binary, Binary)],
+        fin: bool,
+    ) -> Result<(u64, NextTimeout), Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let h3 = self.h3.as_mut().ok_or_else(atoms::bad_state)?;
+        let stream_id = h3
+            .send_request(&mut self.raw, &to_headers(headers), fin)
+            .map_err(h3_error_atom)?;
+        self.drain(env, &env.pid())?;
+        Ok((stream_id, self.next_timeout()?))
+    }
+
+    fn send_h3_response(
+        &mut self,
+        env: &Env,
+        stream_id: u64,
+        headers: &[(Binary, Binary)],
+        fin: bool,
+    ) -> Result<NextTimeout, Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let h3 = self.h3.as_mut().ok_or_else(atoms::bad_state)?;
+        h3.send_response(&mut self.raw, stream_id, &to_headers(headers), fin)
+            .map_err(h3_error_atom)?;
+        self.drain(env, &env.pid())?;
+        self.next_timeout()
+    }
+
+    // Like send_stream_data, returns how much was taken, and the stream is
+    // reported with __stream_writable__ once it can take the rest.
+    fn send_h3_body(
+        &mut self,
+        env: &Env,
+        stream_id: u64,
+        body: &[u8],
+        fin: bool,
+    ) -> Result<(usize, NextTimeout), Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let h3 = self.h3.as_mut().ok_or_else(atoms::bad_state)?;
+        let sent = match h3.send_body(&mut self.raw, stream_id, body, fin) {
+            Ok(len) => len,
+            Err(quiche::h3::Error::Done) => 0,
+            Err(e) => return Err(h3_error_atom(e)),
+        };
+        if sent < body.len() {
+            self.blocked_streams.insert(stream_id);
+        }
+        self.drain(env, &env.pid())?;
+        Ok((sent, self.next_timeout()?))
+    }
+
+    // Reads at most max_len bytes of body, capped at the stream buffer size.
+    // __h3_data__ only comes again once the body has been read dry.
+    fn recv_h3_body(
+        &mut self,
+        env: &Env,
+        stream_id: u64,
+        max_len: usize,
+    ) -> Result<OwnedBinary, Atom> {
+        if self.raw.is_closed() {
+            return Err(atoms::already_closed());
+        }
+        let h3 = self.h3.as_mut().ok_or_else(atoms::bad_state)?;
+        let cap = max_len.min(self.stream_buf.len());
+        match h3.recv_body(&mut self.raw, stream_id, &mut self.stream_buf[..cap]) {
+            Ok(len) => {
+                let data = common::owned_binary(&self.stream_buf[..len]);
+                self.telemetry.add(Counter::StreamBytes, len);
+                self.drain(env, &env.pid())?;
+                Ok(data)
+            }
+            Err(quiche::h3::Error::Done) => Err(atoms::would_block()),
+            Err(e) => Err(h3_error_atom(e)),
+        }
+    }
+
+    // Called from handle_stream in place of reading the streams directly.
+    // A protocol error has quiche close the connection, which the usual
+    // __closed__ reports.
+    pub(crate) fn handle_h3(&mut self, env: &Env, pid: &LocalPid) {
+        if !self.is_handshake_progressed() {
+            return;
+        }
+        if let Some(config) = self.h3_pending.take() {
+            let attached = config
+                .build()
+                .and_then(|config| quiche::h3::Connection::with_transport(&mut self.raw, &config));
+            match attached {
+                Ok(h3) => self.h3 = Some(h3),
+                Err(_) => {
+                    // H3_INTERNAL_ERROR
+                    self.raw.close(true, 0x102, b"").ok();
+                    return;
+                }
+            }
+        }
+        let h3 = match self.h3.as_mut() {
+            Some(h3) => h3,
+            None => return,
+        };
+        loop {
+            let message = match h3.poll(&mut self.raw) {
+                Ok((sid, quiche::h3::Event::Headers { list, .. })) => {
+                    let headers: Vec<Term> = list
+                        .iter()
+                        .map(|header| {
+                            let name = common::owned_binary(header.name());
+                            let value = common::owned_binary(header.value());
+                            (name.release(*env), value.release(*env)).encode(*env)
+                        })
+                        .collect();
+                    make_tuple(
+                        *env,
+                        &[
+                            atoms::__h3_headers__().to_term(*env),
+                            sid.encode(*env),
+                            headers.encode(*env),
+                        ],
+                    )
+                }
+                Ok((sid, quiche::h3::Event::Data)) => make_tuple(
+                    *env,
+                    &[atoms::__h3_data__().to_term(*env), sid.encode(*env)],
+                ),
+                Ok((sid, quiche::h3::Event::Finished)) => make_tuple(
+                    *env,
+                    &[atoms::__h3_finished__().to_term(*env), sid.encode(*env)],
+                ),
+                Ok((sid, quiche::h3::Event::Reset(code))) => make_tuple(
+                    *env,
+                    &[
+                        atoms::__h3_reset__().to_term(*env),
+                        sid.encode(*env),
+                        code.encode(*env),
+                    ],
+                ),
+                Ok((id, quiche::h3::Event::GoAway)) => make_tuple(
+                    *env,
+                    &[atoms::__h3_goaway__().to_term(*env), id.encode(*env)],
+                ),
+                // DATAGRAM frames keep coming as __dgram_recv__
+                Ok((_, quiche::h3::Event::Datagram)) => continue,
+                Err(_) => break,
+            };
+            self.telemetry.send(env, pid, message);
+        }
+    }
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_config_new() -> NifResult<(Atom, i64)> {
+    let ptr = Box::into_raw(Box::new(H3Config::default())) as i64;
+    Ok((atoms::ok(), ptr))
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_config_destroy(conf_ptr: i64) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut H3Config;
+    unsafe { drop(Box::from_raw(conf_ptr)) };
+    Ok(atoms::ok())
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_config_set_max_field_section_size(conf_ptr: i64, v: u64) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut H3Config;
+    let config = unsafe { &mut *conf_ptr };
+    config.max_field_section_size = Some(v);
+    Ok(atoms::ok())
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_config_set_qpack_max_table_capacity(conf_ptr: i64, v: u64) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut H3Config;
+    let config = unsafe { &mut *conf_ptr };
+    config.qpack_max_table_capacity = Some(v);
+    Ok(atoms::ok())
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_config_set_qpack_blocked_streams(conf_ptr: i64, v: u64) -> NifResult<Atom> {
+    let conf_ptr = conf_ptr as *mut H3Config;
+    let config = unsafe { &mut *conf_ptr };
+    config.qpack_blocked_streams = Some(v);
+    Ok(atoms::ok())
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_conn_new_with_transport(
+    env: Env,
+    conn_ptr: i64,
+    conf_ptr: i64,
+) -> NifResult<(Atom, NextTimeout)> {
+    let mut conn = lock_connection(conn_ptr);
+    let conf_ptr = conf_ptr as *mut H3Config;
+    let config = unsafe { *conf_ptr };
+
+    match conn.attach_h3(&env, config) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_send_request(
+    env: Env,
+    conn_ptr: i64,
+    headers: Headers,
+    fin: bool,
+) -> NifResult<(Atom, u64, NextTimeout)> {
+    let mut conn = lock_connection(conn_ptr);
+
+    match conn.send_h3_request(&env, &headers, fin) {
+        Ok((stream_id, next_timeout)) => Ok((atoms::ok(), stream_id, next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_send_response(
+    env: Env,
+    conn_ptr: i64,
+    stream_id: u64,
+    headers: Headers,
+    fin: bool,
+) -> NifResult<(Atom, NextTimeout)> {
+    let mut conn = lock_connection(conn_ptr);
+
+    match conn.send_h3_response(&env, stream_id, &headers, fin) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_send_body(
+    env: Env,
+    conn_ptr: i64,
+    stream_id: u64,
+    data: Binary,
+    fin: bool,
+) -> NifResult<(Atom, u64, NextTimeout)> {
+    let mut conn = lock_connection(conn_ptr);
+
+    match conn.send_h3_body(&env, stream_id, data.as_slice(), fin) {
+        Ok((sent, next_timeout)) if sent < data.len() => {
+            Ok((atoms::partial(), sent as u64, next_timeout))
+        }
+        Ok((sent, next_timeout)) => Ok((atoms::ok(), sent as u64, next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+#[cfg(feature = "h3")]
+#[rustler::nif]
+pub fn h3_recv_body(
+    env: Env,
+    conn_ptr: i64,
+    stream_id: u64,
+    max_len: u64,
+) -> NifResult<(Atom, Binary)> {
+    let mut conn = lock_connection(conn_ptr);
+
+    match conn.recv_h3_body(&env, stream_id, max_len as usize) {
+        Ok(data) => Ok((atoms::ok(), data.release(env))),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+// Built without the h3 feature.
+
+#[cfg(not(feature = "h3"))]
+fn not_supported() -> NifResult<Atom> {
+    Err(common::error_term(atoms::not_supported()))
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_config_new() -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_config_destroy(_conf_ptr: i64) -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_config_set_max_field_section_size(_conf_ptr: i64, _v: u64) -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_config_set_qpack_max_table_capacity(_conf_ptr: i64, _v: u64) -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_config_set_qpack_blocked_streams(_conf_ptr: i64, _v: u64) -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_conn_new_with_transport(_conn_ptr: i64, _conf_ptr: i64) -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_send_request(_conn_ptr: i64, _headers: Term, _fin: bool) -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_send_response(
+    _conn_ptr: i64,
+    _stream_id: u64,
+    _headers: Term,
+    _fin: bool,
+) -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_send_body(_conn_ptr: i64, _stream_id: u64, _data: Term, _fin: bool) -> NifResult<Atom> {
+    not_supported()
+}
+
+#[cfg(not(feature = "h3"))]
+#[rustler::nif]
+pub fn h3_recv_body(_conn_ptr: i64, _stream_id: u64, _max_len: u64) -> NifResult<Atom> {
+    not_supported()
+}
@@ -1,26 +1,37 @@
 use rustler::{Env, Term};
 
+mod cid;
 mod common;
 mod config;
 mod connection;
+mod h3;
+mod logger;
 mod packet;
+mod registry;
 mod socket;
+mod telemetry;
 
 rustler::init!(
     "Elixir.Requiem.QUIC.NIF",
     [
         config::config_new,
         config::config_destroy,
+        config::config_dump,
         config::config_load_cert_chain_from_pem_file,
         config::config_load_priv_key_from_pem_file,
         config::config_load_verify_locations_from_file,
         config::config_load_verify_locations_from_directory,
         config::config_verify_peer,
+        config::config_require_peer_cert,
         config::config_grease,
         config::config_enable_early_data,
+        config::config_log_keys,
+        config::config_set_ticket_key,
         config::config_set_application_protos,
         config::config_set_max_idle_timeout,
         config::config_set_max_udp_payload_size,
+        config::config_set_max_recv_udp_payload_size,
+        config::config_set_max_send_udp_payload_size,
         config::config_set_initial_max_data,
         config::config_set_initial_max_stream_data_bidi_local,
         config::config_set_initial_max_stream_data_bidi_remote,
@@ -31,29 +42,96 @@ rustler::init!(
         config::config_set_max_ack_delay,
         config::config_set_disable_active_migration,
         config::config_set_cc_algorithm_name,
+        config::config_set_cc_algorithm,
+        config::config_cc_available_algorithms,
         config::config_enable_hystart,
         config::config_enable_dgram,
+        config::config_set_max_connection_window,
+        config::config_set_max_stream_window,
+        config::config_set_stats_module,
+        packet::packet_parse_header,
         packet::packet_builder_new,
         packet::packet_builder_destroy,
         packet::packet_builder_build_negotiate_version,
         packet::packet_builder_build_retry,
         connection::connection_accept,
+        connection::connection_connect,
         connection::connection_destroy,
         connection::connection_close,
         connection::connection_is_closed,
+        connection::connection_is_draining,
+        connection::connection_is_established,
+        connection::connection_is_in_early_data,
+        connection::connection_is_resumed,
+        connection::connection_is_early_data_accepted,
+        connection::connection_stats,
+        connection::connection_application_proto,
+        connection::connection_server_name,
+        connection::connection_set_keylog_path,
+        connection::connection_trace_id,
+        connection::connection_source_id,
+        connection::connection_destination_id,
+        connection::connection_session,
+        connection::connection_peer_cert,
+        connection::connection_peer_cert_chain,
         connection::connection_on_packet,
+        connection::connection_on_packets,
         connection::connection_on_timeout,
         connection::connection_stream_send,
+        connection::connection_stream_recv,
+        connection::connection_stream_priority,
+        connection::connection_readable,
+        connection::connection_writable,
+        connection::connection_peer_streams_left_bidi,
+        connection::connection_peer_streams_left_uni,
         connection::connection_dgram_send,
+        connection::connection_max_send_udp_payload_size,
+        connection::connection_dgram_max_writable_len,
+        connection::connection_dgram_send_queue_len,
+        connection::connection_dgram_send_queue_byte_size,
+        connection::connection_dgram_recv_queue_len,
+        connection::connection_dgram_purge_outgoing,
+        registry::connection_register,
+        registry::connection_unregister,
+        registry::connection_on_packet_by_dcid,
+        h3::h3_config_new,
+        h3::h3_config_destroy,
+        h3::h3_config_set_max_field_section_size,
+        h3::h3_config_set_qpack_max_table_capacity,
+        h3::h3_config_set_qpack_blocked_streams,
+        h3::h3_conn_new_with_transport,
+        h3::h3_send_request,
+        h3::h3_send_response,
+        h3::h3_send_body,
+        h3::h3_recv_body,
+        logger::quic_enable_debug_logging,
+        logger::quic_disable_debug_logging,
+        telemetry::quic_stats,
+        telemetry::quic_stats_reset,
         socket::cpu_num,
         socket::socket_sender_get,
         socket::socket_sender_send,
+        socket::socket_sender_send_batch,
+        socket::socket_sender_send_batch_to,
+        socket::socket_sender_send_gso,
         socket::socket_sender_destroy,
         socket::socket_new,
         socket::socket_start,
+        socket::socket_stop,
+        socket::socket_set_controlling_process,
+        socket::socket_set_target_pid,
+        socket::socket_stats,
+        socket::socket_stats_reset,
+        socket::socket_gso_supported,
+        socket::socket_set_ecn,
+        socket::socket_set_send_retries,
+        socket::socket_set_poll_interval,
         socket::socket_destroy,
         socket::socket_address_parts,
         socket::socket_address_from_string,
+        cid::cid_generate,
+        cid::cid_sign,
+        cid::cid_open,
     ],
     load = load
 );
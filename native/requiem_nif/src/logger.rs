@@ -0,0 +1,153 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Sender};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use parking_lot::Mutex;
+use rustler::env::OwnedEnv;
+use rustler::types::tuple::make_tuple;
+use rustler::types::{Encoder, LocalPid};
+use rustler::{Atom, NifResult};
+
+use crate::common::{self, atoms};
+
+// Lines beyond this many per second are dropped, and so are lines that find
+// the queue to the forwarding thread full. Either way they're counted and
+// reported with the next line that gets through.
+const MAX_LINES_PER_SEC: u32 = 1000;
+const QUEUE_LEN: usize = 1000;
+
+struct Sink {
+    tx: Sender<(Level, String)>,
+    window_start: Instant,
+    window_count: u32,
+    dropped: u64,
+}
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+struct ElixirLogger;
+
+static LOGGER: ElixirLogger = ElixirLogger;
+
+impl Log for ElixirLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("quiche")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut guard = SINK.lock();
+        let sink = match guard.as_mut() {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        let now = Instant::now();
+        if now.duration_since(sink.window_start) >= Duration::from_secs(1) {
+            sink.window_start = now;
+            sink.window_count = 0;
+        }
+        if sink.window_count >= MAX_LINES_PER_SEC {
+            sink.dropped += 1;
+            return;
+        }
+
+        if sink.dropped > 0 {
+            let notice = format!("{} quiche log lines dropped", sink.dropped);
+            if sink.tx.try_send((Level::Warn, notice)).is_ok() {
+                sink.dropped = 0;
+            }
+        }
+        match sink
+            .tx
+            .try_send((record.level(), record.args().to_string()))
+        {
+            Ok(()) => sink.window_count += 1,
+            Err(_) => sink.dropped += 1,
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn level_atom(level: Level) -> Atom {
+    match level {
+        Level::Error => atoms::error(),
+        Level::Warn => atoms::warn(),
+        Level::Info => atoms::info(),
+        Level::Debug => atoms::debug(),
+        Level::Trace => atoms::trace(),
+    }
+}
+
+fn level_filter(level: Atom) -> Option<LevelFilter> {
+    if level == atoms::error() {
+        Some(LevelFilter::Error)
+    } else if level == atoms::warn() {
+        Some(LevelFilter::Warn)
+    } else if level == atoms::info() {
+        Some(LevelFilter::Info)
+    } else if level == atoms::debug() {
+        Some(LevelFilter::Debug)
+    } else if level == atoms::trace() {
+        Some(LevelFilter::Trace)
+    } else {
+        None
+    }
+}
+
+// quiche logs from inside NIF calls, i.e. on scheduler threads where
+// OwnedEnv can't send, so lines are handed over to a thread of our own.
+// It exits once the sender is dropped by the next enable or by disable.
+fn spawn_forwarder(pid: LocalPid) -> Sender<(Level, String)> {
+    let (tx, rx) = bounded::<(Level, String)>(QUEUE_LEN);
+    thread::spawn(move || {
+        let mut oenv = OwnedEnv::new();
+        for (level, line) in rx {
+            let line = common::owned_binary(line.as_bytes());
+            oenv.send_and_clear(&pid, |env| {
+                make_tuple(
+                    env,
+                    &[
+                        atoms::__quiche_log__().to_term(env),
+                        level_atom(level).encode(env),
+                        line.release(env).to_term(env),
+                    ],
+                )
+            });
+        }
+    });
+    tx
+}
+
+// Sends quiche's log lines at or above level to pid as
+// {:__quiche_log__, level, line}. Calling it again moves logging to the new
+// pid and level.
+#[rustler::nif]
+pub fn quic_enable_debug_logging(pid: LocalPid, level: Atom) -> NifResult<Atom> {
+    let filter = level_filter(level).ok_or_else(|| common::error_term(atoms::bad_format()))?;
+
+    let mut guard = SINK.lock();
+    *guard = Some(Sink {
+        tx: spawn_forwarder(pid),
+        window_start: Instant::now(),
+        window_count: 0,
+        dropped: 0,
+    });
+
+    // fails once the logger is installed, which is fine as it's never removed
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(filter);
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn quic_disable_debug_logging() -> NifResult<Atom> {
+    log::set_max_level(LevelFilter::Off);
+    let mut guard = SINK.lock();
+    *guard = None;
+    Ok(atoms::ok())
+}
@@ -1,7 +1,14 @@
+use std::cell::RefCell;
+
 use rustler::types::binary::{Binary, OwnedBinary};
-use rustler::{Atom, Env, NifResult};
+use rustler::types::Encoder;
+use rustler::{Atom, Env, NifResult, Term};
+
+use crate::common::{self, atoms};
 
-use crate::common::atoms;
+thread_local! {
+    static HEADER_BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
 
 pub(crate) fn packet_type(ty: quiche::Type) -> Atom {
     match ty {
@@ -36,6 +43,75 @@ pub(crate) fn header_scid_binary(hdr: &quiche::Header) -> OwnedBinary {
     scid
 }
 
+// Header lengths are encoded in a single byte, and quiche doesn't check
+// what it's given when writing one.
+fn check_conn_id_len(ids: &[&[u8]]) -> Result<(), Atom> {
+    if ids.iter().all(|id| id.len() <= quiche::MAX_CONN_ID_LEN) {
+        Ok(())
+    } else {
+        Err(atoms::bad_format())
+    }
+}
+
+// Short headers don't carry the dcid length, so the caller has to know it,
+// and a packet too short to hold that many bytes is bad_format. Long
+// headers use the length they carry. The result is a map so that more
+// header fields can be added later without breaking callers; versions is
+// nil unless the packet is a Version Negotiation.
+#[rustler::nif]
+pub fn packet_parse_header<'a>(
+    env: Env<'a>,
+    packet: Binary,
+    dcid_len: u64,
+) -> NifResult<(Atom, Term<'a>)> {
+    let dcid_len = usize::try_from(dcid_len)
+        .ok()
+        .filter(|len| *len <= quiche::MAX_CONN_ID_LEN)
+        .ok_or_else(|| common::error_term(atoms::bad_format()))?;
+
+    // quiche only parses from a mutable slice; copy into a buffer kept per
+    // scheduler thread instead of allocating one per call
+    HEADER_BUF.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        buf.clear();
+        buf.extend_from_slice(packet.as_slice());
+        let hdr = quiche::Header::from_slice(&mut buf, dcid_len).map_err(|e| match e {
+            quiche::Error::BufferTooShort => common::error_term(atoms::bad_format()),
+            e => common::error_term(common::quiche_error_atom(e)),
+        })?;
+        header_map(env, &hdr)
+    })
+}
+
+fn header_map<'a>(env: Env<'a>, hdr: &quiche::Header) -> NifResult<(Atom, Term<'a>)> {
+    let form = if hdr.ty == quiche::Type::Short {
+        atoms::short()
+    } else {
+        atoms::long()
+    };
+
+    let keys = [
+        atoms::packet_type().to_term(env),
+        atoms::form().to_term(env),
+        atoms::version().to_term(env),
+        atoms::dcid().to_term(env),
+        atoms::scid().to_term(env),
+        atoms::token().to_term(env),
+        atoms::versions().to_term(env),
+    ];
+    let values = [
+        packet_type(hdr.ty).to_term(env),
+        form.to_term(env),
+        hdr.version.encode(env),
+        header_dcid_binary(hdr).release(env).to_term(env),
+        header_scid_binary(hdr).release(env).to_term(env),
+        header_token_binary(hdr).release(env).to_term(env),
+        hdr.versions.encode(env),
+    ];
+    let map = Term::map_from_arrays(env, &keys, &values)?;
+    Ok((atoms::ok(), map))
+}
+
 pub struct PacketBuilder {
     buf: [u8; 1500],
 }
@@ -45,14 +121,18 @@ impl PacketBuilder {
         PacketBuilder { buf: [0; 1500] }
     }
 
-    pub fn build_negotiate_version(&mut self, scid: &[u8], dcid: &[u8]) -> OwnedBinary {
+    pub fn build_negotiate_version(
+        &mut self,
+        scid: &[u8],
+        dcid: &[u8],
+    ) -> Result<OwnedBinary, Atom> {
+        check_conn_id_len(&[scid, dcid])?;
         let scid = quiche::ConnectionId::from_ref(scid);
         let dcid = quiche::ConnectionId::from_ref(dcid);
-        let len = quiche::negotiate_version(&scid, &dcid, &mut self.buf).unwrap();
+        let len = quiche::negotiate_version(&scid, &dcid, &mut self.buf)
+            .map_err(common::quiche_error_atom)?;
 
-        let mut resp = OwnedBinary::new(len).unwrap();
-        resp.as_mut_slice().copy_from_slice(&self.buf[..len]);
-        resp
+        Ok(common::owned_binary(&self.buf[..len]))
     }
 
     pub fn build_retry(
@@ -62,14 +142,15 @@ impl PacketBuilder {
         odcid: &[u8],
         token: &[u8],
         version: u32,
-    ) -> OwnedBinary {
+    ) -> Result<OwnedBinary, Atom> {
+        check_conn_id_len(&[scid, dcid, odcid])?;
         let scid = quiche::ConnectionId::from_ref(scid);
         let dcid = quiche::ConnectionId::from_ref(dcid);
         let odcid = quiche::ConnectionId::from_ref(odcid);
-        let len = quiche::retry(&scid, &odcid, &dcid, &token, version, &mut self.buf).unwrap();
-        let mut resp = OwnedBinary::new(len).unwrap();
-        resp.as_mut_slice().copy_from_slice(&self.buf[..len]);
-        resp
+        // a token too large for the buffer comes back as buffer_too_short
+        let len = quiche::retry(&scid, &odcid, &dcid, &token, version, &mut self.buf)
+            .map_err(common::quiche_error_atom)?;
+        Ok(common::owned_binary(&self.buf[..len]))
     }
 }
 
@@ -96,9 +177,10 @@ pub fn packet_builder_build_negotiate_version<'a>(
     let builder_ptr = builder_ptr as *mut PacketBuilder;
     let builder = unsafe { &mut *builder_ptr };
 
-    let resp = builder.build_negotiate_version(scid.as_slice(), dcid.as_slice());
-
-    Ok((atoms::ok(), resp.release(env)))
+    match builder.build_negotiate_version(scid.as_slice(), dcid.as_slice()) {
+        Ok(resp) => Ok((atoms::ok(), resp.release(env))),
+        Err(reason) => Err(common::error_term(reason)),
+    }
 }
 
 #[rustler::nif]
@@ -114,13 +196,14 @@ pub fn packet_builder_build_retry<'a>(
     let builder_ptr = builder_ptr as *mut PacketBuilder;
     let builder = unsafe { &mut *builder_ptr };
 
-    let resp = builder.build_retry(
+    match builder.build_retry(
         scid.as_slice(),
         dcid.as_slice(),
         odcid.as_slice(),
         token.as_slice(),
         version,
-    );
-
-    Ok((atoms::ok(), resp.release(env)))
+    ) {
+        Ok(resp) => Ok((atoms::ok(), resp.release(env))),
+        Err(reason) => Err(common::error_term(reason)),
+    }
 }
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use parking_lot::{Mutex, RwLock};
+use rustler::types::binary::Binary;
+use rustler::types::LocalPid;
+use rustler::{Atom, Env, NifResult, ResourceArc};
+
+use crate::common::{self, atoms};
+use crate::connection::{Connection, NextTimeout, SharedConnection};
+use crate::socket::Peer;
+
+// Lets a dispatcher that has read a packet's dcid feed the packet to the
+// connection directly, instead of sending it to the connection's owner
+// first. Optional: connections that are never registered aren't affected.
+//
+// The lock is held only to look a connection up or to change the table, never
+// while a packet is processed; the connection's own lock covers that. When
+// both are held, the connection's is taken first.
+static REGISTRY: RwLock<BTreeMap<Vec<u8>, Registered>> = RwLock::new(BTreeMap::new());
+
+struct Registered {
+    conn: SharedConnection,
+    // where the connection's messages go when fed through the registry
+    owner: LocalPid,
+}
+
+fn shared(conn_ptr: i64) -> SharedConnection {
+    let conn_ptr = conn_ptr as *const Mutex<Connection>;
+    // the handle keeps its own count; this is one more for the registry
+    unsafe {
+        Arc::increment_strong_count(conn_ptr);
+        Arc::from_raw(conn_ptr)
+    }
+}
+
+// Called by connection_destroy, with the connection locked, so a destroyed
+// connection doesn't live on in the table. Entries since taken over by
+// another connection are left.
+pub(crate) fn unregister_all(conn: &SharedConnection, dcids: Vec<Vec<u8>>) {
+    let mut registry = REGISTRY.write();
+    for dcid in dcids {
+        if let Some(entry) = registry.get(&dcid) {
+            if Arc::ptr_eq(&entry.conn, conn) {
+                registry.remove(&dcid);
+            }
+        }
+    }
+}
+
+// The calling process becomes the pid stream data, datagrams and the other
+// connection messages go to for packets fed by dcid. A connection can be
+// registered under several dcids, as it is when it issues new ids.
+#[rustler::nif]
+pub fn connection_register(pid: LocalPid, conn_ptr: i64, dcid: Binary) -> NifResult<Atom> {
+    if dcid.is_empty() || dcid.len() > quiche::MAX_CONN_ID_LEN {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let conn = shared(conn_ptr);
+    // Both are changed under the connection's lock, as connection_destroy
+    // takes the list under it, so a destroy can't run in between and leave
+    // the entry behind.
+    let mut locked = conn.lock();
+    {
+        let mut registry = REGISTRY.write();
+        if registry.contains_key(dcid.as_slice()) {
+            return Err(common::error_term(atoms::already_exists()));
+        }
+        registry.insert(
+            dcid.as_slice().to_vec(),
+            Registered {
+                conn: conn.clone(),
+                owner: pid,
+            },
+        );
+    }
+    locked.registered_dcids.push(dcid.as_slice().to_vec());
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn connection_unregister(dcid: Binary) -> NifResult<Atom> {
+    let removed = REGISTRY.write().remove(dcid.as_slice());
+    match removed {
+        Some(entry) => {
+            let mut conn = entry.conn.lock();
+            conn.registered_dcids
+                .retain(|id| id.as_slice() != dcid.as_slice());
+            Ok(atoms::ok())
+        }
+        None => Err(common::error_term(atoms::not_found())),
+    }
+}
+
+// Like connection_on_packet, with the connection and the pid its messages
+// go to found by dcid. The next timeout comes back to the caller; the
+// owner's timer isn't touched, so it's up to the caller to pass it on.
+#[rustler::nif]
+pub fn connection_on_packet_by_dcid(
+    env: Env,
+    dcid: Binary,
+    peer: ResourceArc<Peer>,
+    packet: Binary,
+) -> NifResult<(Atom, NextTimeout)> {
+    let (conn, owner) = {
+        let registry = REGISTRY.read();
+        let entry = registry
+            .get(dcid.as_slice())
+            .ok_or_else(|| common::error_term(atoms::not_found()))?;
+        (entry.conn.clone(), entry.owner.clone())
+    };
+    let mut conn = conn.lock();
+
+    match conn.process_packet(&env, &owner, peer, packet.as_slice()) {
+        Ok(next_timeout) => Ok((atoms::ok(), next_timeout)),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
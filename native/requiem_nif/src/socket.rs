@@ -1,8 +1,13 @@
-use std::net::{IpAddr, SocketAddr, UdpSocket};
-//use std::os::unix::io::AsRawFd;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::ptr;
 use std::str;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
@@ -11,12 +16,15 @@ use rustler::env::OwnedEnv;
 use rustler::types::binary::{Binary, OwnedBinary};
 use rustler::types::tuple::make_tuple;
 use rustler::types::{Encoder, LocalPid};
-use rustler::{Atom, Env, ListIterator, NifResult, ResourceArc};
+use rustler::{Atom, Env, ListIterator, NifResult, ResourceArc, Term};
 
 use crossbeam_channel::{bounded, select, unbounded, Receiver, Sender};
+use parking_lot::{Mutex, RwLock};
 //use nix::sched::CpuSet;
 //use nix::sched::{sched_setaffinity, CpuSet};
 //use nix::unistd::gettid;
+#[cfg(target_os = "linux")]
+use socket2::SockAddr;
 use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::common::{self, atoms};
@@ -30,6 +38,870 @@ impl Peer {
     pub fn new(addr: SocketAddr) -> Self {
         Peer { addr }
     }
+
+    pub fn ip_octets(&self) -> Vec<u8> {
+        match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        }
+    }
+}
+
+// The common reasons a bind fails get atoms of their own, so they can be
+// told apart from the rest of socket_error.
+fn bind_error_atom(err: &std::io::Error) -> Atom {
+    match err.kind() {
+        std::io::ErrorKind::AddrInUse => atoms::eaddrinuse(),
+        std::io::ErrorKind::AddrNotAvailable => atoms::eaddrnotavail(),
+        std::io::ErrorKind::PermissionDenied => atoms::eacces(),
+        _ => atoms::socket_error(),
+    }
+}
+
+// What the sender threads are handed: datagrams for one peer that can leave
+// in a single syscall, or one buffer the kernel cuts into segment_size
+// datagrams (UDP GSO).
+pub enum Outgoing {
+    Datagrams(SocketAddr, Vec<Vec<u8>>),
+    Segments(SocketAddr, Vec<u8>, usize),
+}
+
+// Sends as many of packets as the kernel takes in one sendmmsg. An error
+// means not even the first one went out.
+#[cfg(target_os = "linux")]
+fn send_batch(sock: &UdpSocket, addr: &SocketAddr, packets: &[Vec<u8>]) -> io::Result<usize> {
+    let addr = SockAddr::from(*addr);
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|packet| libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iov| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = addr.len();
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    let sent = unsafe {
+        libc::sendmmsg(
+            sock.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            0,
+        )
+    };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(sent as usize)
+    }
+}
+
+// Same contract as the sendmmsg version, one send_to at a time.
+#[cfg(not(target_os = "linux"))]
+fn send_batch(sock: &UdpSocket, addr: &SocketAddr, packets: &[Vec<u8>]) -> io::Result<usize> {
+    for (idx, packet) in packets.iter().enumerate() {
+        if let Err(e) = sock.send_to(packet, addr) {
+            return if idx == 0 { Err(e) } else { Ok(idx) };
+        }
+    }
+    Ok(packets.len())
+}
+
+// Why a datagram couldn't be sent or received. Some of these are for the
+// peer, not the socket, so the caller may want to tell them apart.
+fn io_error_atom(err: &io::Error) -> Atom {
+    if err.kind() == io::ErrorKind::WouldBlock {
+        return atoms::would_block();
+    }
+    match err.raw_os_error() {
+        Some(libc::EMSGSIZE) => atoms::message_too_big(),
+        Some(libc::ENETUNREACH) => atoms::network_unreachable(),
+        Some(libc::EHOSTUNREACH) => atoms::host_unreachable(),
+        Some(libc::ECONNREFUSED) => atoms::connection_refused(),
+        Some(libc::ENOBUFS) => atoms::no_buffer_space(),
+        Some(libc::EACCES) | Some(libc::EPERM) => atoms::eacces(),
+        Some(libc::EINVAL) | Some(libc::EAFNOSUPPORT) => atoms::bad_format(),
+        _ => atoms::socket_error(),
+    }
+}
+
+// Datagrams that couldn't be sent: why, and how big they were.
+type SendFailures = Vec<(Atom, usize)>;
+
+// Keeps going until every packet has been either sent or dropped. A full
+// send buffer (WouldBlock once the write timeout runs out) is retried up to
+// retries times for each datagram; SO_SNDTIMEO already makes every attempt
+// wait, so there is no extra backoff. Any other error drops just the packet
+// it happened on.
+fn send_all(
+    sock: &UdpSocket,
+    addr: &SocketAddr,
+    packets: &[Vec<u8>],
+    retries: usize,
+    stats: &SocketStats,
+) -> SendFailures {
+    let mut failures = Vec::new();
+    let mut attempts = 0;
+    let mut done = 0;
+    while done < packets.len() {
+        match send_batch(sock, addr, &packets[done..]) {
+            Ok(sent) => {
+                let bytes: usize = packets[done..done + sent].iter().map(Vec::len).sum();
+                SocketStats::add(&stats.sent, sent);
+                SocketStats::add(&stats.bytes_sent, bytes);
+                done += sent;
+                attempts = 0;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && attempts < retries => {
+                SocketStats::count(&stats.would_block);
+                attempts += 1;
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    SocketStats::count(&stats.would_block);
+                }
+                SocketStats::count(&stats.send_errors);
+                failures.push((io_error_atom(&e), packets[done].len()));
+                done += 1;
+                attempts = 0;
+            }
+        }
+    }
+    failures
+}
+
+// Every slot in a receive batch holds a buffer of max_datagram_size + 1.
+const MAX_RECV_BATCH_SIZE: usize = 256;
+
+// Buffers for up to one batch of datagrams, reused across reads.
+struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    // length, sender and ECN codepoint, where the platform reports it
+    received: Vec<(usize, SocketAddr, Option<u8>)>,
+    // recvmmsg's headers point into bufs and the vectors below, none of
+    // which is resized after new, so they're built once
+    #[cfg(target_os = "linux")]
+    msgs: Vec<libc::mmsghdr>,
+    #[cfg(target_os = "linux")]
+    #[allow(dead_code)]
+    iovecs: Vec<libc::iovec>,
+    #[cfg(target_os = "linux")]
+    addrs: Vec<libc::sockaddr_storage>,
+    // room for the IP_TOS or IPV6_TCLASS cmsg, u64s for alignment
+    #[cfg(target_os = "linux")]
+    controls: Vec<[u64; 8]>,
+}
+
+impl RecvBatch {
+    #[cfg(target_os = "linux")]
+    fn new(size: usize, max_datagram_size: usize) -> Self {
+        let mut bufs = vec![vec![0u8; max_datagram_size + 1]; size];
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; size];
+        let mut controls = vec![[0u64; 8]; size];
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .zip(controls.iter_mut())
+            .map(|((iov, addr), control)| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg
+            })
+            .collect();
+        Self {
+            bufs,
+            received: Vec::with_capacity(size),
+            msgs,
+            iovecs,
+            addrs,
+            controls,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new(size: usize, max_datagram_size: usize) -> Self {
+        Self {
+            bufs: vec![vec![0u8; max_datagram_size + 1]; size],
+            received: Vec::with_capacity(size),
+        }
+    }
+
+    // Waits up to the read timeout for the first datagram, then takes
+    // whatever else is already queued without waiting, so a batch never
+    // holds packets back. received is filled in the order of bufs.
+    #[cfg(target_os = "linux")]
+    fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        // the kernel shortens these to what it wrote last time
+        let headers = self.addrs.iter().zip(self.controls.iter());
+        for (msg, (addr, control)) in self.msgs.iter_mut().zip(headers) {
+            msg.msg_hdr.msg_namelen = mem::size_of_val(addr) as u32;
+            msg.msg_hdr.msg_controllen = mem::size_of_val(control) as _;
+        }
+        let msgs = &mut self.msgs;
+
+        let num = unsafe {
+            libc::recvmmsg(
+                sock.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                std::ptr::null_mut(),
+            )
+        };
+        if num < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.received.clear();
+        for msg in &msgs[..num as usize] {
+            let addr = unsafe {
+                SockAddr::from_raw_parts(
+                    msg.msg_hdr.msg_name as *const libc::sockaddr,
+                    msg.msg_hdr.msg_namelen,
+                )
+            };
+            let peer = match (addr.as_inet(), addr.as_inet6()) {
+                (Some(v4), _) => SocketAddr::V4(v4),
+                (_, Some(v6)) => SocketAddr::V6(v6),
+                // a UDP socket bound to an IP address only hears from those
+                _ => SocketAddr::from(([0, 0, 0, 0], 0)),
+            };
+            let ecn = unsafe { recv_ecn(&msg.msg_hdr) };
+            self.received.push((msg.msg_len as usize, peer, ecn));
+        }
+        Ok(self.received.len())
+    }
+
+    // Same contract as the recvmmsg version: a blocking recv_from for the
+    // first datagram, then non-blocking ones until the socket runs dry.
+    #[cfg(not(target_os = "linux"))]
+    fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        self.received.clear();
+        let (len, peer) = sock.recv_from(&mut self.bufs[0])?;
+        self.received.push((len, peer, None));
+
+        sock.set_nonblocking(true)?;
+        for buf in self.bufs.iter_mut().skip(1) {
+            match sock.recv_from(buf) {
+                Ok((len, peer)) => self.received.push((len, peer, None)),
+                Err(_) => break,
+            }
+        }
+        sock.set_nonblocking(false)?;
+
+        Ok(self.received.len())
+    }
+}
+
+// The ECN bits of the TOS or traffic class byte a datagram arrived with.
+#[cfg(target_os = "linux")]
+unsafe fn recv_ecn(msg: &libc::msghdr) -> Option<u8> {
+    let mut ecn = None;
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_TOS) => {
+                ecn = Some(*libc::CMSG_DATA(cmsg) & ECN_MASK);
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                let tclass = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                ecn = Some(tclass as u8 & ECN_MASK);
+            }
+            _ => {}
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    ecn
+}
+
+const ECN_MASK: u8 = 0x03;
+
+fn ecn_atom(ecn: u8) -> Atom {
+    match ecn {
+        0b01 => atoms::ect1(),
+        0b10 => atoms::ect0(),
+        0b11 => atoms::ce(),
+        _ => atoms::not_ect(),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set_int_option(
+    sock: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Asks for the TOS / traffic class of incoming datagrams and marks outgoing
+// ones with ecn. An IPv6 socket may also carry IPv4 traffic, so it gets the
+// IPv4 options too where the kernel allows them.
+#[cfg(target_os = "linux")]
+fn set_ecn_options(sock: &Socket, is_ipv4: bool, ecn: u8) -> io::Result<()> {
+    let ecn = libc::c_int::from(ecn);
+    if is_ipv4 {
+        set_int_option(sock, libc::IPPROTO_IP, libc::IP_RECVTOS, 1)?;
+        set_int_option(sock, libc::IPPROTO_IP, libc::IP_TOS, ecn)
+    } else {
+        set_int_option(sock, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+        set_int_option(sock, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, ecn)?;
+        let _ = set_int_option(sock, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+        let _ = set_int_option(sock, libc::IPPROTO_IP, libc::IP_TOS, ecn);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_ecn_options(_sock: &Socket, _is_ipv4: bool, _ecn: u8) -> io::Result<()> {
+    Ok(())
+}
+
+// Sets DF on every datagram. QUIC must not be fragmented, and a fragment
+// lost to a middlebox looks like random loss. Probe mode leaves the path
+// MTU to QUIC instead of the kernel's cache, so only datagrams over the
+// interface MTU fail, with EMSGSIZE.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(sock: &Socket, is_ipv4: bool) -> io::Result<()> {
+    if is_ipv4 {
+        set_int_option(
+            sock,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        )
+    } else {
+        set_int_option(
+            sock,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        )?;
+        let _ = set_int_option(
+            sock,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn set_dont_fragment(sock: &Socket, is_ipv4: bool) -> io::Result<()> {
+    if is_ipv4 {
+        set_int_option(sock, libc::IPPROTO_IP, libc::IP_DONTFRAG, 1)
+    } else {
+        set_int_option(sock, libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, 1)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_dont_fragment(_sock: &Socket, _is_ipv4: bool) -> io::Result<()> {
+    Ok(())
+}
+
+// What socket_start takes besides the address.
+#[derive(Clone, Default)]
+pub struct StartOptions {
+    // IPV6_V6ONLY, left to the OS default when unset
+    v6_only: Option<bool>,
+    // report ::ffff:a.b.c.d peers as a.b.c.d
+    normalize_v4_mapped: bool,
+    // hand peers over as {ip, port} instead of a Peer resource
+    peer_parts: bool,
+    // SO_RCVBUF and SO_SNDBUF, left to the OS default when unset
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    // told with {:__owner_down__} when the socket shuts itself down
+    supervisor: Option<LocalPid>,
+    recv_error_policy: RecvErrorPolicy,
+    // where datagrams that aren't QUIC go instead of being dropped
+    passthrough: Option<Passthrough>,
+    // where datagrams whose header can't be parsed go instead of being
+    // dropped; ahead of passthrough for those, as they may still be QUIC
+    fallback_pid: Option<LocalPid>,
+}
+
+impl StartOptions {
+    fn from_list(options: Vec<(Atom, Term)>) -> Result<Self, Atom> {
+        let mut opts = Self::default();
+        let mut other_pid = None;
+        let mut other_prefix = None;
+        for (key, value) in options {
+            if key == atoms::v6_only() {
+                opts.v6_only = Some(value.decode().map_err(|_| atoms::bad_format())?);
+            } else if key == atoms::normalize_v4_mapped() {
+                opts.normalize_v4_mapped = value.decode().map_err(|_| atoms::bad_format())?;
+            } else if key == atoms::peer_parts() {
+                opts.peer_parts = value.decode().map_err(|_| atoms::bad_format())?;
+            } else if key == atoms::recv_buffer_size() {
+                opts.recv_buffer_size = Some(Self::buffer_size(value)?);
+            } else if key == atoms::send_buffer_size() {
+                opts.send_buffer_size = Some(Self::buffer_size(value)?);
+            } else if key == atoms::supervisor() {
+                opts.supervisor = Some(value.decode().map_err(|_| atoms::bad_format())?);
+            } else if key == atoms::recv_error_policy() {
+                opts.recv_error_policy = RecvErrorPolicy::decode(value)?;
+            } else if key == atoms::other_pid() {
+                other_pid = Some(value.decode().map_err(|_| atoms::bad_format())?);
+            } else if key == atoms::fallback_pid() {
+                opts.fallback_pid = Some(value.decode().map_err(|_| atoms::bad_format())?);
+            } else if key == atoms::other_prefix() {
+                let prefix = value.decode::<Binary>().map_err(|_| atoms::bad_format())?;
+                if prefix.is_empty() {
+                    return Err(atoms::bad_format());
+                }
+                other_prefix = Some(prefix.as_slice().to_vec());
+            } else {
+                return Err(atoms::bad_format());
+            }
+        }
+        opts.passthrough = match (other_pid, other_prefix) {
+            (Some(pid), prefix) => Some(Passthrough { pid, prefix }),
+            (None, Some(_)) => return Err(atoms::bad_format()),
+            (None, None) => None,
+        };
+        Ok(opts)
+    }
+
+    fn buffer_size(value: Term) -> Result<usize, Atom> {
+        match value.decode::<u32>() {
+            Ok(size) if size > 0 => Ok(size as usize),
+            _ => Err(atoms::bad_format()),
+        }
+    }
+}
+
+// What a receiver does about a failed read besides counting it in
+// recv_errors.
+#[derive(Clone, Copy, Default)]
+enum RecvErrorPolicy {
+    Ignore,
+    // send {:socket_error, reason} to the owner
+    #[default]
+    Notify,
+    // notify, and shut the socket down after this many errors in a row
+    Shutdown(u32),
+}
+
+impl RecvErrorPolicy {
+    // :ignore, :notify or {:shutdown, n}
+    fn decode(value: Term) -> Result<Self, Atom> {
+        if let Ok(policy) = value.decode::<Atom>() {
+            if policy == atoms::ignore() {
+                return Ok(Self::Ignore);
+            } else if policy == atoms::notify() {
+                return Ok(Self::Notify);
+            }
+        } else if let Ok((policy, n)) = value.decode::<(Atom, u32)>() {
+            if policy == atoms::shutdown() && n > 0 {
+                return Ok(Self::Shutdown(n));
+            }
+        }
+        Err(atoms::bad_format())
+    }
+}
+
+// Picks out datagrams meant for another protocol sharing the port. Per the
+// RFC 9443 demultiplexing rules QUIC sets the fixed bit (0x40) of the first
+// byte, which STUN, ZRTP, DTLS and RTP don't. Datagrams that pass this but
+// still don't parse as QUIC are handed over too, unless fallback_pid is set.
+#[derive(Clone)]
+struct Passthrough {
+    pid: LocalPid,
+    // datagrams starting with it are handed over whatever their first byte
+    prefix: Option<Vec<u8>>,
+}
+
+impl Passthrough {
+    fn takes(&self, data: &[u8]) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if data.starts_with(prefix) {
+                return true;
+            }
+        }
+        data.len() < 4 || data[0] & 0x40 == 0
+    }
+
+    // {:__other_packet__, peer, data}
+    fn deliver(
+        &self,
+        oenv: &mut OwnedEnv,
+        peer: SocketAddr,
+        data: &[u8],
+        peer_parts: bool,
+    ) -> bool {
+        hand_over(
+            oenv,
+            &self.pid,
+            atoms::__other_packet__(),
+            peer,
+            data,
+            peer_parts,
+        )
+    }
+}
+
+// {tag, peer, data}, telling whether pid was still alive to get it
+fn hand_over(
+    oenv: &mut OwnedEnv,
+    pid: &LocalPid,
+    tag: Atom,
+    peer: SocketAddr,
+    data: &[u8],
+    peer_parts: bool,
+) -> bool {
+    send_and_clear(oenv, pid, |env| {
+        make_tuple(
+            env,
+            &[
+                tag.to_term(env),
+                encode_peer(env, peer, peer_parts),
+                common::owned_binary(data).release(env).to_term(env),
+            ],
+        )
+    })
+}
+
+// A Peer resource, or {ip, port} with the peer_parts start option.
+fn encode_peer(env: Env, addr: SocketAddr, peer_parts: bool) -> Term {
+    if peer_parts {
+        let ip = common::owned_binary(&Peer::new(addr).ip_octets());
+        (ip.release(env), addr.port()).encode(env)
+    } else {
+        ResourceArc::new(Peer::new(addr)).encode(env)
+    }
+}
+
+fn unmap_v4(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+// An IPv6 socket can only send to IPv4 peers through their mapped address.
+fn map_v4(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        SocketAddr::V6(_) => addr,
+    }
+}
+
+// A datagram on its way to a dispatcher, encoded as
+// {peer, packet, scid, dcid, token, version, packet_type, is_version_supported,
+// ecn}, ecn being nil where the platform doesn't report it. peer is a Peer
+// resource, or {ip, port} with the peer_parts start option.
+struct Incoming {
+    peer: SocketAddr,
+    body: OwnedBinary,
+    scid: OwnedBinary,
+    dcid: OwnedBinary,
+    token: OwnedBinary,
+    version: u32,
+    typ: Atom,
+    is_version_supported: bool,
+    ecn: Option<u8>,
+}
+
+impl Incoming {
+    fn encode<'a>(self, env: Env<'a>, peer_parts: bool) -> Term<'a> {
+        make_tuple(
+            env,
+            &[
+                encode_peer(env, self.peer, peer_parts),
+                self.body.release(env).to_term(env),
+                self.scid.release(env).to_term(env),
+                self.dcid.release(env).to_term(env),
+                self.token.release(env).to_term(env),
+                self.version.encode(env),
+                self.typ.to_term(env),
+                self.is_version_supported.encode(env),
+                self.ecn.map(ecn_atom).encode(env),
+            ],
+        )
+    }
+}
+
+// Whether the kernel takes UDP_SEGMENT on this socket.
+#[cfg(target_os = "linux")]
+fn gso_supported(sock: &UdpSocket) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    ret == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn gso_supported(_sock: &UdpSocket) -> bool {
+    false
+}
+
+// Sends buf as one sendmsg with a UDP_SEGMENT cmsg, leaving it to the kernel
+// to split it into segment_size datagrams (the last one may be shorter).
+#[cfg(target_os = "linux")]
+fn send_segments(
+    sock: &UdpSocket,
+    addr: &SocketAddr,
+    buf: &[u8],
+    segment_size: usize,
+) -> io::Result<()> {
+    let addr = SockAddr::from(*addr);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64s keep the buffer aligned for cmsghdr
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = addr.as_ptr() as *mut libc::c_void;
+    msg.msg_namelen = addr.len();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size as u16);
+        libc::sendmsg(sock.as_raw_fd(), &msg, 0)
+    };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_segments(
+    _sock: &UdpSocket,
+    _addr: &SocketAddr,
+    _buf: &[u8],
+    _segment_size: usize,
+) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Other))
+}
+
+// A full send buffer is retried as in send_all. Anything else (the kernel
+// refusing the segment count, or a NIC that can't checksum segments) falls
+// back to sending the segments one datagram each.
+fn send_all_segments(
+    sock: &UdpSocket,
+    addr: &SocketAddr,
+    buf: &[u8],
+    segment_size: usize,
+    gso: bool,
+    retries: usize,
+    stats: &SocketStats,
+) -> SendFailures {
+    if gso {
+        let mut attempts = 0;
+        loop {
+            match send_segments(sock, addr, buf, segment_size) {
+                Ok(()) => {
+                    SocketStats::add(&stats.sent, buf.len().div_ceil(segment_size));
+                    SocketStats::add(&stats.bytes_sent, buf.len());
+                    return Vec::new();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    SocketStats::count(&stats.would_block);
+                    if attempts == retries {
+                        SocketStats::count(&stats.send_errors);
+                        return vec![(atoms::would_block(), buf.len())];
+                    }
+                    attempts += 1;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    let packets: Vec<Vec<u8>> = buf.chunks(segment_size).map(|c| c.to_vec()).collect();
+    send_all(sock, addr, &packets, retries, stats)
+}
+
+// Counters shared by every receiver and sender thread of a cluster. They
+// are plain atomics, so reading them never holds up traffic.
+#[derive(Default)]
+struct SocketStats {
+    received: AtomicU64,
+    bytes_received: AtomicU64,
+    too_small: AtomicU64,
+    too_big: AtomicU64,
+    not_quic: AtomicU64,
+    recv_errors: AtomicU64,
+    sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    send_errors: AtomicU64,
+    would_block: AtomicU64,
+    passed_through: AtomicU64,
+    fell_back: AtomicU64,
+}
+
+impl SocketStats {
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn counters(&self) -> [(Atom, &AtomicU64); 12] {
+        [
+            (atoms::received(), &self.received),
+            (atoms::bytes_received(), &self.bytes_received),
+            (atoms::too_small(), &self.too_small),
+            (atoms::too_big(), &self.too_big),
+            (atoms::not_quic(), &self.not_quic),
+            (atoms::recv_errors(), &self.recv_errors),
+            (atoms::sent(), &self.sent),
+            (atoms::bytes_sent(), &self.bytes_sent),
+            (atoms::send_errors(), &self.send_errors),
+            (atoms::would_block(), &self.would_block),
+            (atoms::passed_through(), &self.passed_through),
+            (atoms::fell_back(), &self.fell_back),
+        ]
+    }
+}
+
+const DEFAULT_SEND_RETRIES: usize = 3;
+
+#[derive(Clone, Copy)]
+struct BufferSizes {
+    recv: usize,
+    send: usize,
+}
+
+// The pid socket errors are reported to. Threads hold the read lock while
+// sending, so once a swap returns nothing more goes to the old pid.
+type Owner = Arc<RwLock<LocalPid>>;
+
+// The pids received packets go to, each receiver using its own slice.
+// Locked like Owner, so a restarted dispatcher can take over its slot.
+type Targets = Arc<RwLock<Vec<LocalPid>>>;
+
+// Breaks a receiver out of a blocking read, so stop or a teardown doesn't
+// have to wait out the read timeout. Reads return nothing from then on.
+#[cfg(target_os = "linux")]
+fn wake_receiver(sock: &UdpSocket) {
+    // fails with ENOTCONN on an unconnected socket, but wakes the reader all
+    // the same
+    unsafe { libc::shutdown(sock.as_raw_fd(), libc::SHUT_RD) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wake_receiver(_sock: &UdpSocket) {}
+
+// OwnedEnv::send_and_clear, but tells whether pid was still alive to get
+// the message.
+fn send_and_clear<F>(oenv: &mut OwnedEnv, pid: &LocalPid, closure: F) -> bool
+where
+    F: for<'a> FnOnce(Env<'a>) -> Term<'a>,
+{
+    let sent = oenv.run(|env| {
+        let message = closure(env);
+        unsafe {
+            rustler_sys::enif_send(
+                ptr::null_mut(),
+                pid.as_c_arg(),
+                env.as_c_arg(),
+                message.as_c_arg(),
+            ) != 0
+        }
+    });
+    oenv.clear();
+    sent
+}
+
+// Raised by the first thread that finds the owner or a target pid gone, or
+// that runs out of receive errors under RecvErrorPolicy::Shutdown, after
+// which every thread of the cluster exits and drops its socket, as if
+// stopped.
+struct Teardown {
+    down: AtomicBool,
+    supervisor: Option<LocalPid>,
+    // Clones of the receivers' sockets, to wake them up and retune their
+    // timeout. Dropped when raised, as they would keep the port bound once
+    // the threads are gone.
+    r_socks: Mutex<Vec<UdpSocket>>,
+}
+
+impl Teardown {
+    fn new(supervisor: Option<LocalPid>, r_socks: Vec<UdpSocket>) -> Self {
+        Teardown {
+            down: AtomicBool::new(false),
+            supervisor,
+            r_socks: Mutex::new(r_socks),
+        }
+    }
+
+    fn is_down(&self) -> bool {
+        self.down.load(Ordering::Acquire)
+    }
+
+    // only the first call tells the supervisor
+    fn raise(&self, oenv: &mut OwnedEnv) {
+        if self.down.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        for r_sock in self.r_socks.lock().drain(..) {
+            wake_receiver(&r_sock);
+        }
+        if let Some(supervisor) = &self.supervisor {
+            send_and_clear(oenv, supervisor, |env| {
+                make_tuple(env, &[atoms::__owner_down__().to_term(env)])
+            });
+        }
+    }
 }
 
 #[derive(Eq, PartialEq)]
@@ -45,20 +917,42 @@ pub struct SocketCluster {
     r_closers: Vec<Sender<()>>,
     s_handles: Vec<Option<JoinHandle<()>>>,
     s_closers: Vec<Sender<()>>,
-    s_senders: Vec<Sender<(SocketAddr, Vec<u8>)>>,
-    s_receivers: Vec<Receiver<(SocketAddr, Vec<u8>)>>,
+    s_senders: Vec<Sender<Outgoing>>,
+    s_receivers: Vec<Receiver<Outgoing>>,
     barrier: Arc<Barrier>,
     state: ClusterState,
     read_timeout: u64,
+    // read_timeout, shared with the senders, which wait this long for work
+    // before checking for a teardown
+    poll_interval: Arc<AtomicU64>,
     write_timeout: u64,
+    // Short headers don't say how long their dcid is, so it has to match
+    // the length of the ids this server hands out.
+    dcid_len: usize,
+    max_datagram_size: usize,
+    recv_batch_size: usize,
+    // detected on the bound socket by start
+    gso: bool,
+    // ECN codepoint outgoing datagrams are marked with
+    ecn: u8,
+    owner: Option<Owner>,
+    targets: Option<Targets>,
+    teardown: Option<Arc<Teardown>>,
+    // how often a send that found the buffer full is tried again
+    send_retries: usize,
+    // read back from the sockets by the last start
+    buffer_sizes: Option<BufferSizes>,
+    stats: Arc<SocketStats>,
 }
 
 impl SocketCluster {
-    fn build_socket(addr: &str, read_timeout: u64, write_timeout: u64) -> Result<UdpSocket, Atom> {
-        let addr = addr
-            .parse::<SocketAddr>()
-            .map_err(|_| atoms::bad_format())?;
-
+    fn build_socket(
+        addr: SocketAddr,
+        read_timeout: u64,
+        write_timeout: u64,
+        ecn: u8,
+        opts: &StartOptions,
+    ) -> Result<(UdpSocket, BufferSizes), Atom> {
         let domain = if addr.is_ipv4() {
             Domain::ipv4()
         } else {
@@ -80,18 +974,51 @@ impl SocketCluster {
         sock.set_write_timeout(Some(Duration::from_millis(write_timeout)))
             .map_err(|_| atoms::socket_error())?;
 
-        sock.bind(&addr.into()).map_err(|_| atoms::socket_error())?;
+        set_ecn_options(&sock, addr.is_ipv4(), ecn).map_err(|_| atoms::socket_error())?;
+
+        set_dont_fragment(&sock, addr.is_ipv4()).map_err(|_| atoms::socket_error())?;
+
+        if let (true, Some(v6_only)) = (addr.is_ipv6(), opts.v6_only) {
+            sock.set_only_v6(v6_only)
+                .map_err(|_| atoms::socket_error())?;
+        }
+
+        if let Some(size) = opts.recv_buffer_size {
+            sock.set_recv_buffer_size(size)
+                .map_err(|_| atoms::socket_error())?;
+        }
+
+        if let Some(size) = opts.send_buffer_size {
+            sock.set_send_buffer_size(size)
+                .map_err(|_| atoms::socket_error())?;
+        }
+
+        // what the kernel actually gave, after clamping (Linux also doubles
+        // the requested size for its own bookkeeping)
+        let buffer_sizes = BufferSizes {
+            recv: sock.recv_buffer_size().map_err(|_| atoms::socket_error())?,
+            send: sock.send_buffer_size().map_err(|_| atoms::socket_error())?,
+        };
+
+        sock.bind(&addr.into()).map_err(|e| bind_error_atom(&e))?;
 
         let std_sock = sock.into_udp_socket();
 
-        Ok(std_sock)
+        Ok((std_sock, buffer_sizes))
     }
 
-    pub fn new(num_node: usize, read_timeout: u64, write_timeout: u64) -> Self {
+    pub fn new(
+        num_node: usize,
+        read_timeout: u64,
+        write_timeout: u64,
+        dcid_len: usize,
+        max_datagram_size: usize,
+        recv_batch_size: usize,
+    ) -> Self {
         let mut s_senders = Vec::with_capacity(num_node);
         let mut s_receivers = Vec::with_capacity(num_node);
         for _ in 0..num_node {
-            let (tx, rx) = unbounded::<(SocketAddr, Vec<u8>)>();
+            let (tx, rx) = unbounded::<Outgoing>();
             s_senders.push(tx);
             s_receivers.push(rx);
         }
@@ -106,7 +1033,19 @@ impl SocketCluster {
             barrier: Arc::new(Barrier::new(num_node * 2)),
             state: ClusterState::Idle,
             read_timeout,
+            poll_interval: Arc::new(AtomicU64::new(read_timeout)),
             write_timeout,
+            dcid_len,
+            max_datagram_size,
+            recv_batch_size,
+            gso: false,
+            ecn: 0,
+            owner: None,
+            targets: None,
+            teardown: None,
+            send_retries: DEFAULT_SEND_RETRIES,
+            buffer_sizes: None,
+            stats: Arc::new(SocketStats::default()),
         }
     }
 
@@ -120,9 +1059,10 @@ impl SocketCluster {
 
     pub fn start(
         &mut self,
-        addr: &str,
+        addr: SocketAddr,
         caller_pid: &LocalPid,
         target_pids: &[LocalPid],
+        opts: StartOptions,
     ) -> Result<(), Atom> {
         if self.state != ClusterState::Idle {
             return Err(atoms::bad_state());
@@ -130,78 +1070,146 @@ impl SocketCluster {
 
         let num_node = self.num_node;
 
-        let mut sockets: Vec<Option<UdpSocket>> = Vec::with_capacity(num_node);
+        // every socket is ready before any thread starts, so a failure
+        // leaves the cluster idle
+        let mut sockets: Vec<(UdpSocket, UdpSocket, UdpSocket)> = Vec::with_capacity(num_node);
 
         for _n in 0..num_node {
-            let sock = Self::build_socket(&addr, self.read_timeout, self.write_timeout)?;
-            sockets.push(Some(sock));
+            let (r_sock, buffer_sizes) =
+                Self::build_socket(addr, self.read_timeout, self.write_timeout, self.ecn, &opts)?;
+            // the sender's clone shares the receiver's socket, options and all
+            self.buffer_sizes = Some(buffer_sizes);
+            let s_sock = r_sock.try_clone().map_err(|_| atoms::socket_error())?;
+            let w_sock = r_sock.try_clone().map_err(|_| atoms::socket_error())?;
+            sockets.push((r_sock, s_sock, w_sock));
         }
 
+        self.gso = sockets.iter().all(|(_, s_sock, _)| gso_supported(s_sock));
+
+        let owner = Arc::new(RwLock::new(caller_pid.clone()));
+        self.owner = Some(owner.clone());
+        let (sockets, w_socks): (Vec<_>, Vec<_>) = sockets
+            .into_iter()
+            .map(|(r_sock, s_sock, w_sock)| ((r_sock, s_sock), w_sock))
+            .unzip();
+        let teardown = Arc::new(Teardown::new(opts.supervisor.clone(), w_socks));
+        self.teardown = Some(teardown.clone());
+
+        let targets = Arc::new(RwLock::new(target_pids.to_vec()));
+        self.targets = Some(targets.clone());
+
         let step = target_pids.len() / self.num_node;
 
-        for (n, sock) in sockets.iter_mut().enumerate() {
-            let r_sock = sock.take().unwrap();
-            let s_sock = r_sock.try_clone().unwrap();
-            self.start_receiver_thread(n, r_sock, caller_pid, target_pids, step);
-            self.start_sender_thread(n, s_sock);
+        for (n, (r_sock, s_sock)) in sockets.into_iter().enumerate() {
+            self.start_receiver_thread(n, r_sock, &owner, &teardown, &targets, step, opts.clone());
+            self.start_sender_thread(
+                n,
+                s_sock,
+                &owner,
+                &teardown,
+                self.gso,
+                addr.is_ipv6(),
+                opts.peer_parts,
+            );
         }
+        self.state = ClusterState::Started;
 
         Ok(())
     }
 
-    pub fn sender(&self, idx: usize) -> Sender<(SocketAddr, Vec<u8>)> {
-        self.s_senders[idx].clone()
+    pub fn sender(&self, idx: usize) -> Option<Sender<Outgoing>> {
+        self.s_senders.get(idx).cloned()
     }
 
-    pub fn stop(&mut self) {
+    // Returns once every thread has exited and dropped its socket, so the
+    // port is free again and the cluster can be started anew. Returns false
+    // if it wasn't running.
+    pub fn stop(&mut self) -> bool {
         if !self.is_started() {
-            return;
+            return false;
         }
         self.state = ClusterState::Closed;
-        for r_closer in self.r_closers.iter() {
+        for r_closer in self.r_closers.drain(..) {
             let _ = r_closer.send(());
         }
-        for s_closer in self.s_closers.iter() {
+        if let Some(teardown) = self.teardown.as_ref() {
+            for r_sock in teardown.r_socks.lock().iter() {
+                wake_receiver(r_sock);
+            }
+        }
+        for s_closer in self.s_closers.drain(..) {
             let _ = s_closer.send(());
         }
-        for r_handle in self.r_handles.iter_mut() {
-            if let Some(handle) = r_handle.take() {
-                let _ = handle.join();
-            }
+        for handle in self.r_handles.drain(..).flatten() {
+            let _ = handle.join();
         }
-        for s_handle in self.s_handles.iter_mut() {
-            if let Some(handle) = s_handle.take() {
-                let _ = handle.join();
-            }
+        for handle in self.s_handles.drain(..).flatten() {
+            let _ = handle.join();
         }
+        self.teardown = None;
+        self.owner = None;
+        self.targets = None;
+        self.state = ClusterState::Idle;
+        true
+    }
+
+    // Like gen_udp:controlling_process/2, only the current owner may hand
+    // the socket over.
+    pub fn set_owner(&self, env: Env, new_owner: LocalPid) -> Result<(), Atom> {
+        let owner = self.owner.as_ref().ok_or_else(atoms::bad_state)?;
+        let mut pid = owner.write();
+        if pid.encode(env) != env.pid().encode(env) {
+            return Err(atoms::not_owner());
+        }
+        *pid = new_owner;
+        Ok(())
     }
 
+    // Points the idx-th target pid at pid, for a dispatcher that was
+    // restarted under a new pid.
+    pub fn set_target_pid(&self, idx: usize, pid: LocalPid) -> Result<(), Atom> {
+        let targets = self.targets.as_ref().ok_or_else(atoms::bad_state)?;
+        let mut targets = targets.write();
+        let target = targets.get_mut(idx).ok_or_else(atoms::bad_format)?;
+        *target = pid;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn start_receiver_thread(
         &mut self,
         nth: usize,
         sock: UdpSocket,
-        caller_pid: &LocalPid,
-        target_pids: &[LocalPid],
+        owner: &Owner,
+        teardown: &Arc<Teardown>,
+        targets: &Targets,
         step: usize,
+        opts: StartOptions,
     ) {
         let (closer_tx, closer_rx) = bounded::<()>(1);
         self.r_closers.push(closer_tx);
 
         let barrier = self.barrier.clone();
+        let dcid_len = self.dcid_len;
+        let max_datagram_size = self.max_datagram_size;
+        let recv_batch_size = self.recv_batch_size;
+        let stats = self.stats.clone();
+        let recv_error_policy = opts.recv_error_policy;
 
         let mut oenv = OwnedEnv::new();
 
-        let pid = caller_pid.clone();
+        let owner = owner.clone();
+        let teardown = teardown.clone();
+
+        let targets = targets.clone();
 
         let target_pid_start = nth * step;
         let target_pid_end = (nth + 1) * step;
 
-        let target_pids = target_pids[target_pid_start..target_pid_end].to_vec();
-
         let handle = thread::spawn(move || {
-            //oenv.run(move |env| {
-
-            let mut buf = [0u8; 65535];
+            let mut batch = RecvBatch::new(recv_batch_size, max_datagram_size);
+            let mut incoming: Vec<Vec<Incoming>> = (0..step).map(|_| Vec::new()).collect();
+            let mut consecutive_errors = 0;
 
             barrier.wait();
 
@@ -211,76 +1219,159 @@ impl SocketCluster {
                         break;
                     },
                     default => {
-                        match sock.recv_from(&mut buf) {
-                            Ok((len, peer)) => {
+                        if teardown.is_down() {
+                            break;
+                        }
+                        let received = batch.recv(&sock);
+                        // woken up by stop or a teardown
+                        if !closer_rx.is_empty() || teardown.is_down() {
+                            break;
+                        }
+                        match received {
+                            Ok(num) => {
+                                consecutive_errors = 0;
+                                for i in 0..num {
+                                    let (len, peer, ecn) = batch.received[i];
+                                    let peer = if opts.normalize_v4_mapped {
+                                        unmap_v4(peer)
+                                    } else {
+                                        peer
+                                    };
+                                    let buf = &mut batch.bufs[i];
+
+                                    // the buffer is one byte longer than allowed, so
+                                    // anything that filled it was truncated
+                                    if len > max_datagram_size {
+                                        SocketStats::count(&stats.too_big);
+                                        continue;
+                                    }
 
-                                if len < 4 {
-                                    continue;
-                                }
+                                    if let Some(passthrough) = &opts.passthrough {
+                                        if passthrough.takes(&buf[..len]) {
+                                            SocketStats::count(&stats.passed_through);
+                                            if !passthrough.deliver(&mut oenv, peer, &buf[..len], opts.peer_parts) {
+                                                teardown.raise(&mut oenv);
+                                            }
+                                            continue;
+                                        }
+                                    }
 
-                                if len > 1500 {
-                                    continue;
-                                }
+                                    if len < 4 {
+                                        match &opts.fallback_pid {
+                                            Some(pid) => {
+                                                SocketStats::count(&stats.fell_back);
+                                                if !hand_over(&mut oenv, pid, atoms::__unparsed_packet__(), peer, &buf[..len], opts.peer_parts) {
+                                                    teardown.raise(&mut oenv);
+                                                }
+                                            },
+                                            None => SocketStats::count(&stats.too_small),
+                                        }
+                                        continue;
+                                    }
 
-                                match quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN) {
-
-                                    Ok(hdr) => {
-                                        let scid = packet::header_scid_binary(&hdr);
-                                        let dcid = packet::header_dcid_binary(&hdr);
-                                        let token = packet::header_token_binary(&hdr);
-
-                                        let version = hdr.version;
-
-                                        let typ = packet::packet_type(hdr.ty);
-                                        let is_version_supported = quiche::version_is_supported(hdr.version);
-
-                                        let mut body = OwnedBinary::new(len).unwrap();
-                                        body.as_mut_slice().copy_from_slice(&buf[..len]);
-
-                                        // TODO
-                                        // 下記のtarget_indexは、peerのアドレスの値のhashから算出するようにする
-                                        let mut hasher = DefaultHasher::new();
-                                        peer.hash(&mut hasher);
-                                        let idx = hasher.finish() % (target_pids.len() as u64);
-
-                                        oenv.send_and_clear(
-                                            &target_pids[idx as usize],
-                                            |env| {
-                                                make_tuple(
-                                                    env,
-                                                    &[
-                                                        atoms::__packet__().to_term(env),
-                                                        ResourceArc::new(Peer::new(peer)).encode(env),
-                                                        body.release(env).to_term(env),
-                                                        scid.release(env).to_term(env),
-                                                        dcid.release(env).to_term(env),
-                                                        token.release(env).to_term(env),
-                                                        version.encode(env),
-                                                        typ.to_term(env),
-                                                        is_version_supported.encode(env),
-                                                    ],
-                                                )
+                                    match quiche::Header::from_slice(&mut buf[..len], dcid_len) {
+                                        Ok(hdr) => {
+                                            SocketStats::count(&stats.received);
+                                            SocketStats::add(&stats.bytes_received, len);
+
+                                            let mut body = OwnedBinary::new(len).unwrap();
+                                            body.as_mut_slice().copy_from_slice(&buf[..len]);
+
+                                            // Keyed by dcid rather than the peer address, so a
+                                            // connection keeps to one dispatcher when the peer
+                                            // migrates. Routing only needs the dcid to be spread
+                                            // evenly; the registry lookup still decides ownership.
+                                            let mut hasher = DefaultHasher::new();
+                                            hdr.dcid.as_ref().hash(&mut hasher);
+                                            let idx = hasher.finish() % (step as u64);
+
+                                            incoming[idx as usize].push(Incoming {
+                                                peer,
+                                                body,
+                                                scid: packet::header_scid_binary(&hdr),
+                                                dcid: packet::header_dcid_binary(&hdr),
+                                                token: packet::header_token_binary(&hdr),
+                                                version: hdr.version,
+                                                typ: packet::packet_type(hdr.ty),
+                                                is_version_supported: quiche::version_is_supported(hdr.version),
+                                                ecn,
+                                            });
+                                        },
+                                        Err(_) => match (&opts.fallback_pid, &opts.passthrough) {
+                                            (Some(pid), _) => {
+                                                SocketStats::count(&stats.fell_back);
+                                                if !hand_over(&mut oenv, pid, atoms::__unparsed_packet__(), peer, &buf[..len], opts.peer_parts) {
+                                                    teardown.raise(&mut oenv);
+                                                }
+                                            },
+                                            (None, Some(passthrough)) => {
+                                                SocketStats::count(&stats.passed_through);
+                                                if !passthrough.deliver(&mut oenv, peer, &buf[..len], opts.peer_parts) {
+                                                    teardown.raise(&mut oenv);
+                                                }
+                                            },
+                                            (None, None) => {
+                                                // this is not a QUIC packet, ignore.
+                                                SocketStats::count(&stats.not_quic);
                                             }
-                                        );
-                                    },
-                                    Err(_) => {
-                                        // this is not a QUIC packet, ignore.
+                                        }
+                                    }
+                                }
+
+                                // one message per dispatcher for the whole batch
+                                let targets = targets.read();
+                                let target_pids = &targets[target_pid_start..target_pid_end];
+                                for (target, packets) in target_pids.iter().zip(incoming.iter_mut()) {
+                                    if packets.is_empty() {
                                         continue;
                                     }
+                                    let packets = mem::take(packets);
+                                    let sent = send_and_clear(&mut oenv, target, |env| {
+                                        let packets: Vec<Term> =
+                                            packets.into_iter().map(|p| p.encode(env, opts.peer_parts)).collect();
+                                        make_tuple(
+                                            env,
+                                            &[atoms::__packets__().to_term(env), packets.encode(env)],
+                                        )
+                                    });
+                                    if !sent {
+                                        teardown.raise(&mut oenv);
+                                    }
                                 }
                             },
                             Err(e) => {
                                 match e.kind() {
-                                    std::io::ErrorKind::WouldBlock => {
+                                    // the read timeout ran out
+                                    io::ErrorKind::WouldBlock
+                                    | io::ErrorKind::TimedOut
+                                    | io::ErrorKind::Interrupted => {
                                         continue;
                                     },
                                     _ => {
-                                        oenv.send_and_clear(&pid, |env| {
-                                            make_tuple(env, &[
-                                                atoms::socket_error().to_term(env),
-                                                atoms::cant_receive().to_term(env),
-                                            ])
-                                        });
+                                        SocketStats::count(&stats.recv_errors);
+                                        consecutive_errors += 1;
+
+                                        let reason = match io_error_atom(&e) {
+                                            reason if reason == atoms::socket_error() => atoms::cant_receive(),
+                                            reason => reason,
+                                        };
+                                        if let RecvErrorPolicy::Notify | RecvErrorPolicy::Shutdown(_) = recv_error_policy {
+                                            let pid = owner.read();
+                                            let sent = send_and_clear(&mut oenv, &pid, |env| {
+                                                make_tuple(env, &[
+                                                    atoms::socket_error().to_term(env),
+                                                    reason.to_term(env),
+                                                ])
+                                            });
+                                            if !sent {
+                                                teardown.raise(&mut oenv);
+                                            }
+                                        }
+                                        if let RecvErrorPolicy::Shutdown(n) = recv_error_policy {
+                                            if consecutive_errors >= n {
+                                                teardown.raise(&mut oenv);
+                                            }
+                                        }
                                         continue;
                                     }
                                 }
@@ -289,14 +1380,22 @@ impl SocketCluster {
                     },
                 }
             }
-
-            //});
         });
 
         self.r_handles.push(Some(handle));
     }
 
-    fn start_sender_thread(&mut self, nth: usize, sock: UdpSocket) {
+    #[allow(clippy::too_many_arguments)]
+    fn start_sender_thread(
+        &mut self,
+        nth: usize,
+        sock: UdpSocket,
+        owner: &Owner,
+        teardown: &Arc<Teardown>,
+        gso: bool,
+        is_ipv6: bool,
+        peer_parts: bool,
+    ) {
         let (closer_tx, closer_rx) = bounded::<()>(1);
         self.s_closers.push(closer_tx);
 
@@ -304,6 +1403,15 @@ impl SocketCluster {
 
         let barrier = self.barrier.clone();
 
+        let stats = self.stats.clone();
+        let send_retries = self.send_retries;
+        let poll_interval = self.poll_interval.clone();
+
+        let mut oenv = OwnedEnv::new();
+
+        let owner = owner.clone();
+        let teardown = teardown.clone();
+
         let handle = thread::spawn(move || {
             barrier.wait();
 
@@ -313,28 +1421,35 @@ impl SocketCluster {
                         break;
                     },
                     recv(sender_rx) -> msg => {
-                        if let Ok((peer, packet)) = msg {
-                            'send: loop {
-                                match sock.send_to(&packet, peer) {
-                                    Ok(_) => {
-                                        break 'send;
-                                    },
-                                    Err(e) => {
-                                        match e.kind() {
-                                            std::io::ErrorKind::WouldBlock => {
-                                                continue 'send;
-                                            },
-                                            _ => {
-                                                //error!("sender IO error: {:?}", e);
-                                                break 'send;
-                                            }
-
-                                        }
-                                    },
-                                }
+                        let to = |peer| if is_ipv6 { map_v4(peer) } else { peer };
+                        let (peer, failures) = match msg {
+                            Ok(Outgoing::Datagrams(peer, packets)) => {
+                                (peer, send_all(&sock, &to(peer), &packets, send_retries, &stats))
+                            },
+                            Ok(Outgoing::Segments(peer, buf, segment_size)) => {
+                                (peer, send_all_segments(&sock, &to(peer), &buf, segment_size, gso, send_retries, &stats))
+                            },
+                            Err(_) => continue,
+                        };
+                        for (reason, size) in failures {
+                            let pid = owner.read();
+                            let sent = send_and_clear(&mut oenv, &pid, |env| {
+                                make_tuple(env, &[
+                                    atoms::__send_error__().to_term(env),
+                                    reason.to_term(env),
+                                    encode_peer(env, peer, peer_parts),
+                                    size.encode(env),
+                                ])
+                            });
+                            if !sent {
+                                teardown.raise(&mut oenv);
                             }
                         }
-                    }
+                    },
+                    default(Duration::from_millis(poll_interval.load(Ordering::Relaxed))) => {},
+                }
+                if teardown.is_down() {
+                    break;
                 }
             }
         });
@@ -358,7 +1473,10 @@ pub fn cpu_num() -> i32 {
 pub fn socket_sender_get(socket_ptr: i64, idx: i32) -> NifResult<(Atom, i64)> {
     let socket_ptr = socket_ptr as *mut SocketCluster;
     let socket = unsafe { &mut *socket_ptr };
-    let sender = socket.sender(idx as usize);
+    let sender = usize::try_from(idx)
+        .ok()
+        .and_then(|idx| socket.sender(idx))
+        .ok_or_else(|| common::error_term(atoms::bad_format()))?;
     let sender_ptr = Box::into_raw(Box::new(sender));
     Ok((atoms::ok(), sender_ptr as i64))
 }
@@ -369,23 +1487,131 @@ pub fn socket_sender_send(
     peer: ResourceArc<Peer>,
     data: Binary,
 ) -> NifResult<Atom> {
-    let sender_ptr = sender_ptr as *mut Sender<(SocketAddr, Vec<u8>)>;
+    let sender_ptr = sender_ptr as *mut Sender<Outgoing>;
     let sender = unsafe { &mut *sender_ptr };
-    let _ = sender.send((peer.addr, data.as_slice().to_vec()));
+    let _ = sender.send(Outgoing::Datagrams(
+        peer.addr,
+        vec![data.as_slice().to_vec()],
+    ));
     Ok(atoms::ok())
 }
 
+#[rustler::nif]
+pub fn socket_sender_send_batch(
+    sender_ptr: i64,
+    peer: ResourceArc<Peer>,
+    packets: Vec<Binary>,
+) -> NifResult<Atom> {
+    let sender_ptr = sender_ptr as *mut Sender<Outgoing>;
+    let sender = unsafe { &mut *sender_ptr };
+    let packets = packets
+        .iter()
+        .map(|data| data.as_slice().to_vec())
+        .collect();
+    let _ = sender.send(Outgoing::Datagrams(peer.addr, packets));
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+pub fn socket_sender_send_batch_to(
+    sender_ptr: i64,
+    ip: Binary,
+    port: u16,
+    packets: Vec<Binary>,
+) -> NifResult<Atom> {
+    let addr = socket_addr_from_parts(ip.as_slice(), port)
+        .ok_or_else(|| common::error_term(atoms::bad_format()))?;
+    let sender_ptr = sender_ptr as *mut Sender<Outgoing>;
+    let sender = unsafe { &mut *sender_ptr };
+    let packets = packets
+        .iter()
+        .map(|data| data.as_slice().to_vec())
+        .collect();
+    let _ = sender.send(Outgoing::Datagrams(addr, packets));
+    Ok(atoms::ok())
+}
+
+// Queues buffer to go out as datagrams of segment_size bytes each. With GSO
+// that is a single sendmsg; on a Linux kernel without it the sender thread
+// splits the buffer itself, see socket_gso_supported.
+#[cfg(target_os = "linux")]
+#[rustler::nif]
+pub fn socket_sender_send_gso(
+    sender_ptr: i64,
+    peer: ResourceArc<Peer>,
+    buffer: Binary,
+    segment_size: u64,
+) -> NifResult<Atom> {
+    let segment_size = match usize::try_from(segment_size) {
+        Ok(n) if (1..=common::MAX_UDP_PAYLOAD_SIZE).contains(&n) => n,
+        _ => return Err(common::error_term(atoms::bad_format())),
+    };
+    if buffer.is_empty() {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    let sender_ptr = sender_ptr as *mut Sender<Outgoing>;
+    let sender = unsafe { &mut *sender_ptr };
+    let _ = sender.send(Outgoing::Segments(
+        peer.addr,
+        buffer.as_slice().to_vec(),
+        segment_size,
+    ));
+    Ok(atoms::ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+#[rustler::nif]
+pub fn socket_sender_send_gso(
+    _sender_ptr: i64,
+    _peer: ResourceArc<Peer>,
+    _buffer: Binary,
+    _segment_size: u64,
+) -> NifResult<Atom> {
+    Err(common::error_term(atoms::not_supported()))
+}
+
 #[rustler::nif]
 pub fn socket_sender_destroy(sender_ptr: i64) -> NifResult<Atom> {
-    let sender_ptr = sender_ptr as *mut Sender<(SocketAddr, Vec<u8>)>;
+    let sender_ptr = sender_ptr as *mut Sender<Outgoing>;
     unsafe { drop(Box::from_raw(sender_ptr)) };
     Ok(atoms::ok())
 }
 
 #[rustler::nif]
-pub fn socket_new(num_node: i32, read_timeout: u64, write_timeout: u64) -> NifResult<(Atom, i64)> {
-    let num_node = num_node as usize;
-    let socket = SocketCluster::new(num_node, read_timeout, write_timeout);
+pub fn socket_new(
+    num_node: i32,
+    read_timeout: u64,
+    write_timeout: u64,
+    dcid_len: u64,
+    max_datagram_size: u64,
+    recv_batch_size: u64,
+) -> NifResult<(Atom, i64)> {
+    let num_node = match usize::try_from(num_node) {
+        Ok(n) if n > 0 => n,
+        _ => return Err(common::error_term(atoms::bad_format())),
+    };
+    let dcid_len = match usize::try_from(dcid_len) {
+        Ok(n) if n <= quiche::MAX_CONN_ID_LEN => n,
+        _ => return Err(common::error_term(atoms::bad_format())),
+    };
+    // anything a UDP datagram can carry, down to the smallest an Initial
+    // may be sent in
+    let max_datagram_size = match usize::try_from(max_datagram_size) {
+        Ok(n) if (1200..=common::MAX_UDP_PAYLOAD_SIZE).contains(&n) => n,
+        _ => return Err(common::error_term(atoms::bad_format())),
+    };
+    let recv_batch_size = match usize::try_from(recv_batch_size) {
+        Ok(n) if (1..=MAX_RECV_BATCH_SIZE).contains(&n) => n,
+        _ => return Err(common::error_term(atoms::bad_format())),
+    };
+    let socket = SocketCluster::new(
+        num_node,
+        read_timeout,
+        write_timeout,
+        dcid_len,
+        max_datagram_size,
+        recv_batch_size,
+    );
 
     let socket_ptr = Box::into_raw(Box::new(socket));
     Ok((atoms::ok(), socket_ptr as i64))
@@ -394,32 +1620,197 @@ pub fn socket_new(num_node: i32, read_timeout: u64, write_timeout: u64) -> NifRe
 #[rustler::nif]
 pub fn socket_start(
     socket_ptr: i64,
-    address: Binary,
+    address: Term,
     pid: LocalPid,
     target_pids: ListIterator,
+    options: Vec<(Atom, Term)>,
 ) -> NifResult<Atom> {
     let socket_ptr = socket_ptr as *mut SocketCluster;
     let socket = unsafe { &mut *socket_ptr };
 
     let targets: Vec<LocalPid> = match target_pids.map(|x| x.decode::<LocalPid>()).collect() {
         Ok(v) => v,
-        Err(_) => return Err(common::error_term(atoms::system_error())),
+        Err(_) => return Err(common::error_term(atoms::bad_format())),
     };
 
     let num_node = socket.get_num_node();
     if targets.len() < num_node || targets.len() % num_node != 0 {
-        // TODO better error type
-        return Err(common::error_term(atoms::system_error()));
+        return Err(common::error_term(atoms::bad_format()));
     }
 
-    let address = str::from_utf8(address.as_slice()).unwrap();
+    let address =
+        socket_addr_from_term(address).ok_or_else(|| common::error_term(atoms::bad_format()))?;
+    let opts = StartOptions::from_list(options).map_err(common::error_term)?;
 
-    match socket.start(address, &pid, &targets) {
+    match socket.start(address, &pid, &targets, opts) {
         Ok(()) => Ok(atoms::ok()),
         Err(reason) => Err(common::error_term(reason)),
     }
 }
 
+// Sets how many times a datagram that found the send buffer full is tried
+// again before it's dropped and reported as would_block. Takes effect at the
+// next socket_start.
+#[rustler::nif]
+pub fn socket_set_send_retries(socket_ptr: i64, retries: u32) -> NifResult<Atom> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    if socket.is_started() {
+        return Err(common::error_term(atoms::bad_state()));
+    }
+    socket.send_retries = retries as usize;
+    Ok(atoms::ok())
+}
+
+// Sets how long receivers block in a read, and senders wait for work,
+// before they look for a stop or a teardown, right away if started.
+// Received datagrams and queued sends don't wait for it.
+#[rustler::nif]
+pub fn socket_set_poll_interval(socket_ptr: i64, interval: u64) -> NifResult<Atom> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    if interval == 0 {
+        return Err(common::error_term(atoms::bad_format()));
+    }
+    if let Some(teardown) = socket.teardown.as_ref() {
+        for r_sock in teardown.r_socks.lock().iter() {
+            r_sock
+                .set_read_timeout(Some(Duration::from_millis(interval)))
+                .map_err(|_| common::error_term(atoms::socket_error()))?;
+        }
+    }
+    socket.read_timeout = interval;
+    socket.poll_interval.store(interval, Ordering::Relaxed);
+    Ok(atoms::ok())
+}
+
+// Makes new_pid the one socket errors are reported to. Must be called by
+// the current owner, the pid given to socket_start.
+#[rustler::nif]
+pub fn socket_set_controlling_process(
+    env: Env,
+    socket_ptr: i64,
+    new_pid: LocalPid,
+) -> NifResult<Atom> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    match socket.set_owner(env, new_pid) {
+        Ok(()) => Ok(atoms::ok()),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+// Sends the packets the idx-th target pid given to socket_start got to pid
+// instead, from the next batch on.
+#[rustler::nif]
+pub fn socket_set_target_pid(socket_ptr: i64, idx: usize, pid: LocalPid) -> NifResult<Atom> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    match socket.set_target_pid(idx, pid) {
+        Ok(()) => Ok(atoms::ok()),
+        Err(reason) => Err(common::error_term(reason)),
+    }
+}
+
+// Stops the threads and releases the port, then sends {:__socket_closed__}
+// to the caller. Joining waits out the read timeout, hence the dirty
+// scheduler.
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn socket_stop(env: Env, socket_ptr: i64) -> NifResult<Atom> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    if !socket.stop() {
+        return Err(common::error_term(atoms::bad_state()));
+    }
+    env.send(
+        &env.pid(),
+        make_tuple(env, &[atoms::__socket_closed__().to_term(env)]),
+    );
+    Ok(atoms::ok())
+}
+
+// Sets the ECN codepoint (:not_ect, :ect0 or :ect1) sent datagrams are
+// marked with. Takes effect at the next socket_start.
+#[cfg(target_os = "linux")]
+#[rustler::nif]
+pub fn socket_set_ecn(socket_ptr: i64, codepoint: Atom) -> NifResult<Atom> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    let ecn = if codepoint == atoms::not_ect() {
+        0b00
+    } else if codepoint == atoms::ect1() {
+        0b01
+    } else if codepoint == atoms::ect0() {
+        0b10
+    } else {
+        return Err(common::error_term(atoms::bad_format()));
+    };
+    if socket.is_started() {
+        return Err(common::error_term(atoms::bad_state()));
+    }
+    socket.ecn = ecn;
+    Ok(atoms::ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+#[rustler::nif]
+pub fn socket_set_ecn(_socket_ptr: i64, _codepoint: Atom) -> NifResult<Atom> {
+    Err(common::error_term(atoms::not_supported()))
+}
+
+// Whether the kernel segments sends for the bound sockets, as found by
+// socket_start.
+#[rustler::nif]
+pub fn socket_gso_supported(socket_ptr: i64) -> NifResult<(Atom, bool)> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    if !socket.is_started() {
+        return Err(common::error_term(atoms::bad_state()));
+    }
+    Ok((atoms::ok(), socket.gso))
+}
+
+// Counters for the datagrams the receivers have taken off the sockets and
+// the senders have put on them. Received ones that were too small, too big
+// or didn't parse as QUIC were dropped. Also the socket buffer sizes the
+// last start got, nil before that.
+#[rustler::nif]
+pub fn socket_stats(env: Env, socket_ptr: i64) -> NifResult<(Atom, Term)> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    let counters = socket.stats.counters();
+    let mut keys: Vec<Term> = counters.iter().map(|(key, _)| key.to_term(env)).collect();
+    let mut values: Vec<Term> = counters
+        .iter()
+        .map(|(_, counter)| counter.load(Ordering::Relaxed).encode(env))
+        .collect();
+    keys.push(atoms::recv_buffer_size().to_term(env));
+    values.push(socket.buffer_sizes.map(|sizes| sizes.recv).encode(env));
+    keys.push(atoms::send_buffer_size().to_term(env));
+    values.push(socket.buffer_sizes.map(|sizes| sizes.send).encode(env));
+    let map = Term::map_from_arrays(env, &keys, &values)?;
+    Ok((atoms::ok(), map))
+}
+
+#[rustler::nif]
+pub fn socket_stats_reset(socket_ptr: i64) -> NifResult<Atom> {
+    let socket_ptr = socket_ptr as *mut SocketCluster;
+    let socket = unsafe { &mut *socket_ptr };
+
+    for (_, counter) in socket.stats.counters().iter() {
+        counter.store(0, Ordering::Relaxed);
+    }
+    Ok(atoms::ok())
+}
+
 #[rustler::nif]
 pub fn socket_destroy(socket_ptr: i64) -> NifResult<Atom> {
     let socket_ptr = socket_ptr as *mut SocketCluster;
@@ -427,12 +1818,30 @@ pub fn socket_destroy(socket_ptr: i64) -> NifResult<Atom> {
     Ok(atoms::ok())
 }
 
+fn socket_addr_from_parts(ip: &[u8], port: u16) -> Option<SocketAddr> {
+    if let Ok(octets) = <[u8; 4]>::try_from(ip) {
+        Some(SocketAddr::new(IpAddr::from(octets), port))
+    } else if let Ok(octets) = <[u8; 16]>::try_from(ip) {
+        Some(SocketAddr::new(IpAddr::from(octets), port))
+    } else {
+        None
+    }
+}
+
+// Either "host:port" or {ip, port} with 4 or 16 bytes of ip, as
+// socket_address_parts gives them out.
+fn socket_addr_from_term(term: Term) -> Option<SocketAddr> {
+    if let Ok(address) = term.decode::<Binary>() {
+        str::from_utf8(address.as_slice()).ok()?.parse().ok()
+    } else {
+        let (ip, port) = term.decode::<(Binary, u16)>().ok()?;
+        socket_addr_from_parts(ip.as_slice(), port)
+    }
+}
+
 #[rustler::nif]
 pub fn socket_address_parts(env: Env, peer: ResourceArc<Peer>) -> NifResult<(Atom, Binary, u16)> {
-    let ip_bytes = match peer.addr.ip() {
-        IpAddr::V4(ip) => ip.octets().to_vec(),
-        IpAddr::V6(ip) => ip.octets().to_vec(),
-    };
+    let ip_bytes = peer.ip_octets();
 
     let mut ip = OwnedBinary::new(ip_bytes.len()).unwrap();
     ip.as_mut_slice().copy_from_slice(&ip_bytes);
@@ -448,7 +1857,9 @@ pub fn socket_address_from_string(address: Binary) -> NifResult<(Atom, ResourceA
             return Err(common::error_term(atoms::bad_format()));
         }
     };
-    let addr: SocketAddr = addr.parse().unwrap();
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| common::error_term(atoms::bad_format()))?;
     Ok((atoms::ok(), ResourceArc::new(Peer::new(addr))))
 }
 
@@ -456,3 +1867,59 @@ pub fn on_load(env: Env) -> bool {
     rustler::resource!(Peer, env);
     true
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn int_option(sock: &Socket, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                sock.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        value
+    }
+
+    fn bound(addr: &str) -> Option<Socket> {
+        let addr: SocketAddr = addr.parse().unwrap();
+        let domain = if addr.is_ipv4() {
+            Domain::ipv4()
+        } else {
+            Domain::ipv6()
+        };
+        let sock = Socket::new(domain, Type::dgram(), Some(Protocol::udp())).ok()?;
+        set_dont_fragment(&sock, addr.is_ipv4()).unwrap();
+        sock.bind(&addr.into()).ok()?;
+        Some(sock)
+    }
+
+    #[test]
+    fn ipv4_sockets_never_fragment() {
+        let sock = bound("127.0.0.1:0").unwrap();
+        assert_eq!(
+            int_option(&sock, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER),
+            libc::IP_PMTUDISC_PROBE
+        );
+    }
+
+    #[test]
+    fn ipv6_sockets_never_fragment() {
+        // not every host running the tests has IPv6
+        let sock = match bound("[::1]:0") {
+            Some(sock) => sock,
+            None => return,
+        };
+        assert_eq!(
+            int_option(&sock, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER),
+            libc::IPV6_PMTUDISC_PROBE
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use rustler::types::{Encoder, LocalPid};
+use rustler::{Atom, Env, NifResult, Term};
+
+use crate::common::{self, atoms};
+
+// What the NIF itself does for the connections of one handler module,
+// summed over every config tagged with that module (config_set_stats_module)
+// and the connections made from them. The registry lock is only taken when a
+// config is tagged and when the stats are read; counting is relaxed atomics.
+static REGISTRY: RwLock<BTreeMap<String, Arc<ModuleStats>>> = RwLock::new(BTreeMap::new());
+
+// recv errors are counted per quiche error; the payload of the variants that
+// carry one doesn't matter here
+const RECV_ERRORS: [quiche::Error; 16] = [
+    quiche::Error::Done,
+    quiche::Error::BufferTooShort,
+    quiche::Error::UnknownVersion,
+    quiche::Error::InvalidFrame,
+    quiche::Error::InvalidPacket,
+    quiche::Error::InvalidState,
+    quiche::Error::InvalidStreamState(0),
+    quiche::Error::InvalidTransportParam,
+    quiche::Error::CryptoFail,
+    quiche::Error::TlsFail,
+    quiche::Error::FlowControl,
+    quiche::Error::StreamLimit,
+    quiche::Error::StreamStopped(0),
+    quiche::Error::StreamReset(0),
+    quiche::Error::FinalSize,
+    quiche::Error::CongestionControl,
+];
+
+#[derive(Default)]
+pub(crate) struct ModuleStats {
+    accepted: AtomicU64,
+    // a gauge, left alone by reset
+    live_connections: AtomicU64,
+    packets_received: AtomicU64,
+    recv_errors: [AtomicU64; RECV_ERRORS.len()],
+    packets_drained: AtomicU64,
+    stream_bytes: AtomicU64,
+    dgrams: AtomicU64,
+    messages: AtomicU64,
+}
+
+impl ModuleStats {
+    fn counters(&self) -> [(Atom, &AtomicU64); 7] {
+        [
+            (atoms::accepted(), &self.accepted),
+            (atoms::live_connections(), &self.live_connections),
+            (atoms::packets_received(), &self.packets_received),
+            (atoms::packets_drained(), &self.packets_drained),
+            (atoms::stream_bytes(), &self.stream_bytes),
+            (atoms::dgrams(), &self.dgrams),
+            (atoms::messages(), &self.messages),
+        ]
+    }
+}
+
+pub(crate) enum Counter {
+    Accepted,
+    PacketsReceived,
+    PacketsDrained,
+    StreamBytes,
+    Dgrams,
+}
+
+// Held by a config and by each connection made from it. Untagged configs
+// hold nothing and count nothing.
+#[derive(Clone, Default)]
+pub(crate) struct Telemetry(Option<Arc<ModuleStats>>);
+
+impl Telemetry {
+    pub(crate) fn for_module(module: &str) -> Self {
+        if let Some(stats) = REGISTRY.read().get(module) {
+            return Telemetry(Some(stats.clone()));
+        }
+        let mut registry = REGISTRY.write();
+        let stats = registry.entry(module.to_owned()).or_default();
+        Telemetry(Some(stats.clone()))
+    }
+
+    pub(crate) fn add(&self, counter: Counter, n: usize) {
+        if let Some(stats) = self.0.as_ref() {
+            let counter = match counter {
+                Counter::Accepted => &stats.accepted,
+                Counter::PacketsReceived => &stats.packets_received,
+                Counter::PacketsDrained => &stats.packets_drained,
+                Counter::StreamBytes => &stats.stream_bytes,
+                Counter::Dgrams => &stats.dgrams,
+            };
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn recv_error(&self, err: &quiche::Error) {
+        if let Some(stats) = self.0.as_ref() {
+            let slot = RECV_ERRORS
+                .iter()
+                .position(|e| mem::discriminant(e) == mem::discriminant(err));
+            if let Some(slot) = slot {
+                stats.recv_errors[slot].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        if let Some(stats) = self.0.as_ref() {
+            stats.live_connections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn connection_dropped(&self) {
+        if let Some(stats) = self.0.as_ref() {
+            stats.live_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // env.send, counted
+    pub(crate) fn send<'a>(&self, env: &Env<'a>, pid: &LocalPid, message: Term<'a>) {
+        if let Some(stats) = self.0.as_ref() {
+            stats.messages.fetch_add(1, Ordering::Relaxed);
+        }
+        env.send(pid, message);
+    }
+}
+
+pub(crate) fn module_name(env: Env, module: Atom) -> NifResult<String> {
+    module
+        .to_term(env)
+        .atom_to_string()
+        .map_err(|_| common::error_term(atoms::bad_format()))
+}
+
+// {:ok, map}, where recv_errors is a map of quiche error to count holding
+// only the errors seen, or not_found for a module no config was tagged with.
+#[rustler::nif]
+pub fn quic_stats(env: Env, module: Atom) -> NifResult<(Atom, Term)> {
+    let module = module_name(env, module)?;
+    let registry = REGISTRY.read();
+    let stats = registry
+        .get(&module)
+        .ok_or_else(|| common::error_term(atoms::not_found()))?;
+
+    let counters = stats.counters();
+    let mut keys: Vec<Term> = counters.iter().map(|(key, _)| key.to_term(env)).collect();
+    let mut values: Vec<Term> = counters
+        .iter()
+        .map(|(_, counter)| counter.load(Ordering::Relaxed).encode(env))
+        .collect();
+
+    let mut error_keys = Vec::new();
+    let mut error_values = Vec::new();
+    for (err, counter) in RECV_ERRORS.iter().zip(stats.recv_errors.iter()) {
+        let n = counter.load(Ordering::Relaxed);
+        if n > 0 {
+            error_keys.push(common::quiche_error_atom(*err).to_term(env));
+            error_values.push(n.encode(env));
+        }
+    }
+    keys.push(atoms::recv_errors().to_term(env));
+    values.push(Term::map_from_arrays(env, &error_keys, &error_values)?);
+
+    let map = Term::map_from_arrays(env, &keys, &values)?;
+    Ok((atoms::ok(), map))
+}
+
+#[rustler::nif]
+pub fn quic_stats_reset(env: Env, module: Atom) -> NifResult<Atom> {
+    let module = module_name(env, module)?;
+    let registry = REGISTRY.read();
+    let stats = registry
+        .get(&module)
+        .ok_or_else(|| common::error_term(atoms::not_found()))?;
+
+    for (key, counter) in stats.counters().iter() {
+        if *key != atoms::live_connections() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+    for counter in stats.recv_errors.iter() {
+        counter.store(0, Ordering::Relaxed);
+    }
+    Ok(atoms::ok())
+}
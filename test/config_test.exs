@@ -8,6 +8,7 @@ defmodule RequiemTest.ConfigTest do
 
     try do
       assert Config.load_cert_chain_from_pem_file(c, "") == {:error, :system_error}
+      assert Config.load_cert_chain_from_pem_file(c, <<0xFF>>) == {:error, :bad_format}
       assert Config.load_cert_chain_from_pem_file(c, "test/support/cert.crt") == :ok
       assert Config.load_priv_key_from_pem_file(c, "") == {:error, :system_error}
       assert Config.load_priv_key_from_pem_file(c, "test/support/cert.key") == :ok
@@ -16,12 +17,25 @@ defmodule RequiemTest.ConfigTest do
       assert Config.load_verify_locations_from_directory(c, "") == {:error, :system_error}
       assert Config.verify_peer(c, true) == :ok
       assert Config.verify_peer(c, false) == :ok
+      assert Config.require_peer_cert(c, true) == :ok
+      assert Config.require_peer_cert(c, false) == :ok
       assert Config.grease(c, true) == :ok
       assert Config.grease(c, false) == :ok
+      assert Config.log_keys(c) == :ok
+      assert Config.set_ticket_key(c, :crypto.strong_rand_bytes(48)) == :ok
+      assert Config.set_ticket_key(c, :crypto.strong_rand_bytes(32)) == {:error, :bad_format}
       assert Config.enable_early_data(c) == :ok
       assert Config.set_application_protos(c, ["wq-vvv-01"]) == :ok
+      assert Config.set_application_protos(c, ["h3", "wq-vvv-01"]) == :ok
+      assert Config.set_application_protos(c, [""]) == {:error, :bad_format}
+      assert Config.set_application_protos(c, [String.duplicate("a", 256)]) ==
+               {:error, :bad_format}
       assert Config.set_max_idle_timeout(c, 10000) == :ok
       assert Config.set_max_udp_payload_size(c, 1000) == :ok
+      assert Config.set_max_recv_udp_payload_size(c, 1500) == :ok
+      assert Config.set_max_send_udp_payload_size(c, 1200) == :ok
+      assert Config.set_max_connection_window(c, 64 * 1024 * 1024) == :ok
+      assert Config.set_max_stream_window(c, 32 * 1024 * 1024) == :ok
       assert Config.set_initial_max_data(c, 1000) == :ok
       assert Config.set_initial_max_stream_data_bidi_local(c, 1000) == :ok
       assert Config.set_initial_max_stream_data_bidi_remote(c, 1000) == :ok
@@ -31,8 +45,11 @@ defmodule RequiemTest.ConfigTest do
       assert Config.set_ack_delay_exponent(c, 1000) == :ok
       assert Config.set_disable_active_migration(c, true) == :ok
       assert Config.set_disable_active_migration(c, false) == :ok
-      assert Config.set_cc_algorithm_name(c, "") == {:error, :system_error}
+      assert Config.set_cc_algorithm_name(c, "") == {:error, :bad_format}
+      assert Config.set_cc_algorithm_name(c, "cubuc") == {:error, :bad_format}
       assert Config.set_cc_algorithm_name(c, "reno") == :ok
+      assert Config.set_cc_algorithm(c, :cubuc) == {:error, :bad_format}
+      assert Config.set_cc_algorithm(c, :cubic) == :ok
       assert Config.enable_hystart(c, true) == :ok
       assert Config.enable_hystart(c, false) == :ok
       assert Config.enable_dgram(c, true, 100, 100) == :ok
@@ -42,13 +59,38 @@ defmodule RequiemTest.ConfigTest do
     end
   end
 
-  test "ALPN param" do
-    assert Requiem.QUIC.Config.ALPN.encode("http/1.1") ==
-             <<0x08, 0x68, 0x74, 0x74, 0x70, 0x2F, 0x31, 0x2E, 0x31>>
+  test "dump config" do
+    {:ok, c} = Config.new()
+
+    try do
+      {:ok, settings} = Config.dump(c)
+      assert settings.max_idle_timeout == nil
+      assert settings.application_protos == nil
+
+      assert Config.set_max_idle_timeout(c, 10000) == :ok
+      assert Config.set_application_protos(c, ["h3", "wq-vvv-01"]) == :ok
+      assert Config.set_max_udp_payload_size(c, 1350) == :ok
+      # a rejected value isn't recorded
+      assert Config.set_cc_algorithm_name(c, "") == {:error, :bad_format}
+      assert Config.load_cert_chain_from_pem_file(c, "test/support/cert.crt") == :ok
+
+      {:ok, settings} = Config.dump(c)
+      assert settings.max_idle_timeout == 10000
+      assert settings.application_protos == ["h3", "wq-vvv-01"]
+      assert settings.max_recv_udp_payload_size == 1350
+      assert settings.max_send_udp_payload_size == 1350
+      assert settings.cc_algorithm_name == nil
+      assert settings.cert_chain_file == "test/support/cert.crt"
+      assert settings.initial_max_data == nil
+    after
+      Config.destroy(c)
+    end
+  end
 
-    assert Requiem.QUIC.Config.ALPN.encode_list(["http/1.0", "http/1.1"]) ==
-             <<0x08, 0x68, 0x74, 0x74, 0x70, 0x2F, 0x31, 0x2E, 0x30, 0x08, 0x68, 0x74, 0x74, 0x70,
-               0x2F, 0x31, 0x2E, 0x31>>
+  test "available cc algorithms" do
+    algos = Config.cc_available_algorithms()
+    assert :reno in algos
+    assert :cubic in algos
   end
 
   test "config typo" do
@@ -60,4 +102,14 @@ defmodule RequiemTest.ConfigTest do
       Requiem.Config.check_key_existence(opts2)
     end
   end
+
+  test "config cc algorithm" do
+    assert Requiem.Config.check_cc_algorithm([]) == :ok
+    assert Requiem.Config.check_cc_algorithm(cc_algorithm_name: :reno) == :ok
+    assert Requiem.Config.check_cc_algorithm(cc_algorithm_name: "cubic") == :ok
+
+    assert_raise RuntimeError, fn ->
+      Requiem.Config.check_cc_algorithm(cc_algorithm_name: "cubuc")
+    end
+  end
 end
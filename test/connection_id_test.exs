@@ -15,4 +15,37 @@ defmodule RequiemTest.ConnectionIDTest do
     assert byte_size(newid1) == 20
     assert byte_size(newid2) == 20
   end
+
+  test "generate connection id" do
+    {:ok, id1} = ConnectionID.generate(20)
+    {:ok, id2} = ConnectionID.generate(20)
+    assert byte_size(id1) == 20
+    assert id1 != id2
+    assert {:ok, <<_>>} = ConnectionID.generate(1)
+    assert ConnectionID.generate(0) == {:error, :bad_format}
+    assert ConnectionID.generate(21) == {:error, :bad_format}
+  end
+
+  test "signed connection id" do
+    secret = :crypto.strong_rand_bytes(32)
+
+    {:ok, id1} = ConnectionID.sign(secret, <<0, 3>>)
+    {:ok, id2} = ConnectionID.sign(secret, <<0, 3>>)
+    assert byte_size(id1) == 20
+    assert id1 != id2
+    assert ConnectionID.open(secret, id1) == {:ok, <<0, 3>>}
+    assert ConnectionID.open(secret, id2) == {:ok, <<0, 3>>}
+    assert {:ok, id3} = ConnectionID.sign(secret, "")
+    assert ConnectionID.open(secret, id3) == {:ok, ""}
+
+    # forged or tampered ids don't open
+    assert ConnectionID.open(:crypto.strong_rand_bytes(32), id1) == {:error, :bad_format}
+    <<head::binary-size(19), last>> = id1
+    tampered = head <> <<:erlang.bxor(last, 1)>>
+    assert ConnectionID.open(secret, tampered) == {:error, :bad_format}
+    assert ConnectionID.open(secret, binary_part(id1, 0, 10)) == {:error, :bad_format}
+
+    assert ConnectionID.sign(secret, :crypto.strong_rand_bytes(8)) == {:error, :bad_format}
+    assert ConnectionID.sign("short", <<1>>) == {:error, :bad_format}
+  end
 end
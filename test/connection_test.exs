@@ -4,6 +4,10 @@ defmodule RequiemTest.ConnectionTest do
   alias Requiem.QUIC.Config
   alias Requiem.QUIC.Socket
   alias Requiem.QUIC.Connection
+  alias Requiem.QUIC.H3
+  alias Requiem.QUIC.PacketHeader
+
+  import RequiemTest.QUICHelper
 
   defmodule TestSender do
     use GenServer
@@ -16,7 +20,7 @@ defmodule RequiemTest.ConnectionTest do
       {:ok, %{}}
     end
 
-    def handle_info({:__drain__, _peer, _packet}, _state) do
+    def handle_info({:__drain__, _ip, _port, _packets, _delay}, _state) do
     end
   end
 
@@ -30,12 +34,78 @@ defmodule RequiemTest.ConnectionTest do
     {:ok, c} = Config.new()
 
     try do
-      {:ok, conn} = Connection.accept(c, scid, odcid, peer, sender_pid, 1024 * 10)
+      {:ok, conn} = Connection.accept(c, scid, odcid, peer, sender_pid, 1024 * 10, 1350)
 
       try do
         assert Connection.is_closed?(conn) == false
-        assert Connection.close(conn, false, 0x1, "") == {:error, :already_closed}
+        assert Connection.is_draining?(conn) == false
+        assert Connection.readable(conn) == []
+        assert Connection.writable(conn) == []
+        assert {:ok, _next_timeout, []} = Connection.on_packets(conn, peer, [])
+        assert Connection.is_established?(conn) == false
+        assert Connection.is_in_early_data?(conn) == false
+        assert Connection.is_resumed?(conn) == false
+        assert Connection.is_early_data_accepted?(conn) == {:error, :not_ready}
+        assert Connection.application_proto(conn) == {:error, :not_ready}
+        assert Connection.server_name(conn) == {:error, :not_ready}
+        assert Connection.peer_cert(conn) == {:error, :not_found}
+        assert Connection.max_send_udp_payload_size(conn) == 1200
+        assert Connection.dgram_max_writable_len(conn) == {:error, :not_negotiated}
+        assert Connection.dgram_send(conn, "hello") == {:error, :not_negotiated}
+        assert Connection.dgram_send(conn, ["he", "llo"]) == {:error, :not_negotiated}
+        assert Connection.dgram_send_queue_len(conn) == 0
+        assert Connection.dgram_send_queue_byte_size(conn) == 0
+        assert Connection.dgram_recv_queue_len(conn) == 0
+        assert Connection.dgram_purge_outgoing(conn, <<1>>) == :ok
+        assert Connection.trace_id(conn) == Base.encode16(scid, case: :lower)
+        assert Connection.source_id(conn) == scid
+        assert Connection.set_keylog_path(conn, "/nonexistent/keylog") == {:error, :not_found}
+        # nothing has been received yet, so the connection closes right away
+        assert Connection.close(conn, false, :internal_error, "") == :ok
         assert Connection.is_closed?(conn) == true
+        assert Connection.on_timeout(conn) == {:error, :already_closed}
+        assert Connection.readable(conn) == []
+        assert Connection.writable(conn) == []
+
+        {:ok, stats} = Connection.stats(conn)
+        assert stats.recv == 0
+        assert is_integer(stats.rtt)
+        assert is_integer(stats.cwnd)
+        assert Map.has_key?(stats, :lost_bytes)
+        assert Map.has_key?(stats, :peer_active_conn_id_limit)
+      after
+        Connection.destroy(conn)
+      end
+    after
+      Config.destroy(c)
+      Process.exit(sender_pid, :kill)
+    end
+  end
+
+  test "stream send" do
+    {:ok, sender_pid} = TestSender.start_link()
+
+    scid = :crypto.strong_rand_bytes(20)
+    odcid = :crypto.strong_rand_bytes(20)
+
+    {:ok, peer} = Socket.address_from_string("192.168.0.1:4000")
+    {:ok, c} = Config.new()
+
+    try do
+      {:ok, conn} = Connection.accept(c, scid, odcid, peer, sender_pid, 1024 * 10, 1350)
+
+      try do
+        # peer hasn't granted any stream credit before handshake
+        assert Connection.stream_send(conn, 3, "hello", false) == {:error, :stream_limit}
+        assert Connection.stream_send(conn, 3, ["he", ["ll"], "o"], false) ==
+                 {:error, :stream_limit}
+
+        assert Connection.stream_send(conn, 3, [:hello], false) == {:error, :bad_format}
+        # nothing has been received on the stream yet
+        assert Connection.stream_recv(conn, 0, 1024) == {:error, :invalid_stream_state}
+        assert Connection.close(conn, false, 0x1, "") == :ok
+        assert Connection.stream_send(conn, 3, "hello", false) == {:error, :already_closed}
+        assert Connection.stream_recv(conn, 0, 1024) == {:error, :already_closed}
       after
         Connection.destroy(conn)
       end
@@ -45,6 +115,690 @@ defmodule RequiemTest.ConnectionTest do
     end
   end
 
+  test "stream priority" do
+    {:ok, sender_pid} = TestSender.start_link()
+
+    scid = :crypto.strong_rand_bytes(20)
+    odcid = :crypto.strong_rand_bytes(20)
+
+    {:ok, peer} = Socket.address_from_string("192.168.0.1:4000")
+    {:ok, c} = Config.new()
+
+    try do
+      {:ok, conn} = Connection.accept(c, scid, odcid, peer, sender_pid, 1024 * 10, 1350)
+
+      try do
+        assert Connection.stream_priority(conn, 3, 256, false) == {:error, :bad_format}
+        # client-initiated stream can't be opened locally
+        assert Connection.stream_priority(conn, 0, 0, false) == {:error, :invalid_stream_state}
+        # peer hasn't granted any stream credit before handshake
+        assert Connection.peer_streams_left_bidi(conn) == 0
+        assert Connection.peer_streams_left_uni(conn) == 0
+        assert Connection.stream_priority(conn, 3, 0, false) == {:error, :stream_limit}
+        assert Connection.close(conn, false, 0x1, "") == :ok
+        assert Connection.stream_priority(conn, 3, 0, true) == {:error, :already_closed}
+      after
+        Connection.destroy(conn)
+      end
+    after
+      Config.destroy(c)
+      Process.exit(sender_pid, :kill)
+    end
+  end
+
+  test "client connection" do
+    scid = :crypto.strong_rand_bytes(20)
+
+    {:ok, peer} = Socket.address_from_string("192.168.0.1:4433")
+    {:ok, c} = Config.new()
+
+    try do
+      assert Config.verify_peer(c, false) == :ok
+      assert Config.set_application_protos(c, ["test"]) == :ok
+
+      assert Connection.connect(c, "example.com", scid, peer, self(), 1024 * 10, 1350, "bad") ==
+               {:error, :buffer_too_short}
+
+      {:ok, conn} = Connection.connect(c, "example.com", scid, peer, self(), 1024 * 10, 1350)
+
+      try do
+        # the first flight is written as soon as the connection is created
+        assert_receive {:__drain__, <<192, 168, 0, 1>>, 4433, [_initial | _], _delay}
+        assert Connection.is_established?(conn) == false
+        assert Connection.session(conn) == {:error, :not_found}
+        assert {:ok, :active, _next_timeout} = Connection.on_timeout(conn)
+        refute_received {:__connected__, _alpn}
+      after
+        Connection.destroy(conn)
+      end
+    after
+      Config.destroy(c)
+    end
+  end
+
+  test "handshake without retry" do
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+    {:ok, client_conf} = Config.new()
+    {:ok, server_conf} = Config.new()
+
+    try do
+      assert Config.verify_peer(client_conf, false) == :ok
+      assert Config.set_application_protos(client_conf, ["test"]) == :ok
+      assert Config.load_cert_chain_from_pem_file(server_conf, "test/support/cert.crt") == :ok
+      assert Config.load_priv_key_from_pem_file(server_conf, "test/support/cert.key") == :ok
+      assert Config.set_application_protos(server_conf, ["test"]) == :ok
+      assert Config.set_max_send_udp_payload_size(server_conf, 1350) == :ok
+
+      server_scid = :crypto.strong_rand_bytes(20)
+
+      {:ok, server} =
+        Connection.accept(
+          server_conf,
+          server_scid,
+          "",
+          client_peer,
+          self(),
+          1024 * 10,
+          1350
+        )
+
+      {:ok, client} =
+        Connection.connect(
+          client_conf,
+          "example.com",
+          :crypto.strong_rand_bytes(20),
+          server_peer,
+          self(),
+          1024 * 10,
+          1350
+        )
+
+      try do
+        initial_dcid = Connection.destination_id(client)
+        exchange_packets(client, server, client_peer, server_peer)
+
+        # the client switches over to the id the server picked
+        assert initial_dcid != server_scid
+        assert Connection.destination_id(client) == server_scid
+        assert Connection.destination_id(server) == Connection.source_id(client)
+
+        assert Connection.is_established?(client) == true
+        assert Connection.is_established?(server) == true
+        assert Connection.application_proto(server) == {:ok, "test"}
+        assert Connection.is_early_data_accepted?(server) == {:ok, false}
+        # raised from the handshake minimum to our limit, which the client allows
+        assert Connection.max_send_udp_payload_size(server) == 1350
+        refute_received {:__early_data__}
+        assert_received {:__connected__, "test"}
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  test "http/3 request attached before the handshake" do
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+
+    case H3.config_new() do
+      {:error, :not_supported} ->
+        assert H3.attach(0, 0) == {:error, :not_supported}
+
+      {:ok, h3_conf} ->
+        {:ok, client_conf} = Config.new()
+        {:ok, server_conf} = Config.new()
+
+        try do
+          for c <- [client_conf, server_conf] do
+            :ok = Config.set_application_protos(c, ["h3"])
+            :ok = Config.set_initial_max_data(c, 1024 * 1024)
+            :ok = Config.set_initial_max_stream_data_bidi_local(c, 1024 * 1024)
+            :ok = Config.set_initial_max_stream_data_bidi_remote(c, 1024 * 1024)
+            :ok = Config.set_initial_max_stream_data_uni(c, 1024 * 1024)
+            :ok = Config.set_initial_max_streams_bidi(c, 100)
+            :ok = Config.set_initial_max_streams_uni(c, 100)
+          end
+
+          :ok = Config.verify_peer(client_conf, false)
+          :ok = Config.load_cert_chain_from_pem_file(server_conf, "test/support/cert.crt")
+          :ok = Config.load_priv_key_from_pem_file(server_conf, "test/support/cert.key")
+
+          {:ok, server} =
+            Connection.accept(
+              server_conf,
+              :crypto.strong_rand_bytes(20),
+              "",
+              client_peer,
+              self(),
+              1024 * 10,
+              1350
+            )
+
+          {:ok, client} =
+            Connection.connect(
+              client_conf,
+              "example.com",
+              :crypto.strong_rand_bytes(20),
+              server_peer,
+              self(),
+              1024 * 10,
+              1350
+            )
+
+          try do
+            assert {:ok, _} = H3.attach(server, h3_conf)
+            assert {:ok, _} = H3.attach(client, h3_conf)
+            assert H3.attach(client, h3_conf) == {:error, :already_exists}
+            exchange_packets(client, server, client_peer, server_peer)
+
+            headers = [
+              {":method", "GET"},
+              {":scheme", "https"},
+              {":authority", "example.com"},
+              {":path", "/"}
+            ]
+
+            {:ok, stream_id, _} = H3.send_request(client, headers, true)
+            exchange_packets(client, server, client_peer, server_peer)
+
+            assert_received {:__h3_headers__, ^stream_id, ^headers}
+            assert_received {:__h3_finished__, ^stream_id}
+            # the control and QPACK streams never show up as plain streams
+            refute_received {:__stream_recv__, _, _}
+          after
+            Connection.destroy(client)
+            Connection.destroy(server)
+          end
+        after
+          Config.destroy(client_conf)
+          Config.destroy(server_conf)
+          H3.config_destroy(h3_conf)
+        end
+    end
+  end
+
+  test "NIF stats by module" do
+    module = RequiemTest.ConnectionTest.StatsHandler
+    assert Requiem.QUIC.stats(module) == {:error, :not_found}
+
+    {:ok, client_conf} = Config.new()
+    server_conf = server_config(:crypto.strong_rand_bytes(48))
+
+    try do
+      :ok = Config.verify_peer(client_conf, false)
+      :ok = Config.set_application_protos(client_conf, ["test"])
+      assert Config.set_stats_module(server_conf, module) == :ok
+
+      {client, server} = handshake(client_conf, server_conf, "192.168.0.1:4433")
+
+      try do
+        assert {:ok, stats} = Requiem.QUIC.stats(module)
+        assert %{accepted: 1, live_connections: 1, recv_errors: %{}} = stats
+        assert stats.packets_received > 0
+        assert stats.packets_drained > 0
+        assert stats.messages > 0
+
+        assert Requiem.QUIC.stats_reset(module) == :ok
+        assert {:ok, %{accepted: 0, live_connections: 1}} = Requiem.QUIC.stats(module)
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+
+      assert {:ok, %{live_connections: 0}} = Requiem.QUIC.stats(module)
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  test "packets fed by dcid from another process" do
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+    {:ok, client_conf} = Config.new()
+    server_conf = server_config(:crypto.strong_rand_bytes(48))
+
+    try do
+      :ok = Config.verify_peer(client_conf, false)
+      :ok = Config.set_application_protos(client_conf, ["test"])
+      server_scid = :crypto.strong_rand_bytes(20)
+
+      {:ok, server} =
+        Connection.accept(server_conf, server_scid, "", client_peer, self(), 1024 * 10, 1350)
+
+      {:ok, client} =
+        Connection.connect(
+          client_conf,
+          "example.com",
+          :crypto.strong_rand_bytes(20),
+          server_peer,
+          self(),
+          1024 * 10,
+          1350
+        )
+
+      try do
+        initial_dcid = Connection.destination_id(client)
+        assert Connection.register(server, initial_dcid) == :ok
+        assert Connection.register(server, server_scid) == :ok
+        assert Connection.register(client, server_scid) == {:error, :already_exists}
+        assert Connection.register(server, "") == {:error, :bad_format}
+
+        exchange_packets_by_dcid(client, client_peer, server_peer)
+
+        assert Connection.is_established?(server) == true
+        # one from each side, the server's to the process that registered it
+        assert_received {:__connected__, "test"}
+        assert_received {:__connected__, "test"}
+
+        assert Connection.unregister(initial_dcid) == :ok
+        assert Connection.unregister(initial_dcid) == {:error, :not_found}
+        assert Connection.on_packet_by_dcid(initial_dcid, client_peer, "x") ==
+                 {:error, :not_found}
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+
+      # destroy dropped the rest
+      assert Connection.unregister(server_scid) == {:error, :not_found}
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  test "one connection driven from many processes at once" do
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+    {:ok, client_conf} = Config.new()
+    server_conf = server_config(:crypto.strong_rand_bytes(48))
+    tasks = 32
+    rounds = 50
+
+    try do
+      :ok = Config.verify_peer(client_conf, false)
+      :ok = Config.set_application_protos(client_conf, ["test"])
+
+      for c <- [client_conf, server_conf] do
+        :ok = Config.set_initial_max_data(c, 16 * 1024 * 1024)
+        :ok = Config.set_initial_max_stream_data_bidi_local(c, 1024 * 1024)
+        :ok = Config.set_initial_max_stream_data_bidi_remote(c, 1024 * 1024)
+        :ok = Config.set_initial_max_streams_bidi(c, tasks)
+      end
+
+      server_scid = :crypto.strong_rand_bytes(20)
+
+      {:ok, server} =
+        Connection.accept(server_conf, server_scid, "", client_peer, self(), 1024 * 10, 1350)
+
+      {:ok, client} =
+        Connection.connect(
+          client_conf,
+          "example.com",
+          :crypto.strong_rand_bytes(20),
+          server_peer,
+          self(),
+          1024 * 10,
+          1350
+        )
+
+      try do
+        :ok = Connection.register(server, Connection.destination_id(client))
+        :ok = Connection.register(server, server_scid)
+        exchange_packets_by_dcid(client, client_peer, server_peer)
+        assert Connection.is_established?(server)
+
+        # each task writes its own server-initiated stream, and in between
+        # runs the timer and feeds the server packets it can't decrypt, all
+        # on the one handle
+        1..tasks
+        |> Enum.map(fn n ->
+          Task.async(fn ->
+            stream_id = 4 * n - 3
+
+            for round <- 1..rounds do
+              assert {:ok, _, _} = Connection.stream_send(server, stream_id, <<round::32>>, false)
+              assert {:ok, :active, _} = Connection.on_timeout(server)
+
+              # dropped by quiche, or refused; either way the connection lives on
+              junk = <<0x40>> <> server_scid <> :crypto.strong_rand_bytes(64)
+              result = Connection.on_packet_by_dcid(server_scid, client_peer, junk)
+              assert elem(result, 0) in [:ok, :error]
+            end
+
+            assert {:ok, _, _} = Connection.stream_send(server, stream_id, "", true)
+          end)
+        end)
+        |> Task.await_many(30_000)
+
+        exchange_packets_by_dcid(client, client_peer, server_peer)
+
+        # nothing was lost or reordered within a stream
+        expected = for round <- 1..rounds, into: "", do: <<round::32>>
+
+        for n <- 1..tasks do
+          assert collect_stream(4 * n - 3, "") == expected
+        end
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  test "resumption across configs sharing a ticket key" do
+    ticket_key = :crypto.strong_rand_bytes(48)
+    {:ok, client_conf} = Config.new()
+    server_conf1 = server_config(ticket_key)
+    server_conf2 = server_config(ticket_key)
+
+    try do
+      assert Config.verify_peer(client_conf, false) == :ok
+      assert Config.set_application_protos(client_conf, ["test"]) == :ok
+
+      {client1, server1} = handshake(client_conf, server_conf1, "192.168.0.1:4433")
+      assert Connection.is_established?(server1) == true
+      assert Connection.is_resumed?(server1) == false
+      assert {:ok, session} = Connection.session(client1)
+      Connection.destroy(client1)
+      Connection.destroy(server1)
+
+      # a different "node" holding the same key accepts the ticket
+      {client2, server2} = handshake(client_conf, server_conf2, "192.168.0.3:4433", session)
+      assert Connection.is_established?(server2) == true
+      assert Connection.is_resumed?(server2) == true
+      assert Connection.is_resumed?(client2) == true
+      Connection.destroy(client2)
+      Connection.destroy(server2)
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf1)
+      Config.destroy(server_conf2)
+    end
+  end
+
+  test "pull mode streams are read with stream_recv" do
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+    {:ok, client_conf} = Config.new()
+    server_conf = server_config(:crypto.strong_rand_bytes(48))
+
+    try do
+      assert Config.verify_peer(client_conf, false) == :ok
+      assert Config.set_application_protos(client_conf, ["test"]) == :ok
+
+      for c <- [client_conf, server_conf] do
+        assert Config.set_initial_max_data(c, 1024 * 1024) == :ok
+        assert Config.set_initial_max_stream_data_bidi_local(c, 1024 * 1024) == :ok
+        assert Config.set_initial_max_stream_data_bidi_remote(c, 1024 * 1024) == :ok
+        assert Config.set_initial_max_streams_bidi(c, 10) == :ok
+      end
+
+      {:ok, server} =
+        Connection.accept(
+          server_conf,
+          :crypto.strong_rand_bytes(20),
+          "",
+          client_peer,
+          self(),
+          1024 * 10,
+          1350,
+          true
+        )
+
+      {:ok, client} =
+        Connection.connect(
+          client_conf,
+          "example.com",
+          :crypto.strong_rand_bytes(20),
+          server_peer,
+          self(),
+          1024 * 10,
+          1350
+        )
+
+      try do
+        exchange_packets(client, server, client_peer, server_peer)
+        assert Connection.is_established?(server)
+
+        data = :crypto.strong_rand_bytes(3000)
+        assert {:ok, 3000, _} = Connection.stream_send(client, 0, data, true)
+        exchange_packets(client, server, client_peer, server_peer)
+
+        # only told, not sent the data
+        assert_received {:__stream_readable__, 0}
+        refute_received {:__stream_recv__, _, _}
+
+        assert {:ok, first, false} = Connection.stream_recv(server, 0, 1000)
+        assert byte_size(first) == 1000
+        assert read_stream(server, 0, first) == data
+
+        # told once until read dry
+        assert {:ok, _, _} = Connection.stream_send(client, 4, "a", false)
+        exchange_packets(client, server, client_peer, server_peer)
+        assert_received {:__stream_readable__, 4}
+        assert {:ok, _, _} = Connection.stream_send(client, 4, "b", false)
+        exchange_packets(client, server, client_peer, server_peer)
+        refute_received {:__stream_readable__, 4}
+
+        assert Connection.stream_recv(server, 4, 1000) == {:ok, "ab", false}
+        assert Connection.stream_recv(server, 4, 1000) == {:error, :would_block}
+
+        assert {:ok, _, _} = Connection.stream_send(client, 4, "c", false)
+        exchange_packets(client, server, client_peer, server_peer)
+        assert_received {:__stream_readable__, 4}
+        assert Connection.stream_recv(server, 4, 1000) == {:ok, "c", false}
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  test "a peer close is reported" do
+    {:ok, client_conf} = Config.new()
+    server_conf = server_config(:crypto.strong_rand_bytes(48))
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+
+    try do
+      assert Config.verify_peer(client_conf, false) == :ok
+      assert Config.set_application_protos(client_conf, ["test"]) == :ok
+
+      {client, server} = handshake(client_conf, server_conf, "192.168.0.1:4433")
+
+      try do
+        assert {:ok, _timeout} = Connection.close(client, true, 0x10, "bye")
+        exchange_packets(client, server, client_peer, server_peer)
+
+        assert Connection.is_draining?(server)
+        assert_received {:__closed__, true, 0x10, "bye"}
+        # the client is still closing, and reports it once that's over
+        refute_received {:__closed__, _, _, _}
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  test "a local close that completes at once is reported" do
+    scid = :crypto.strong_rand_bytes(20)
+    {:ok, peer} = Socket.address_from_string("192.168.0.1:4000")
+    {:ok, c} = Config.new()
+
+    try do
+      {:ok, conn} = Connection.accept(c, scid, "", peer, self(), 1024 * 10, 1350)
+
+      try do
+        # nothing was received, so there is no one to send CONNECTION_CLOSE to
+        assert Connection.close(conn, true, 0x10, "bye") == :ok
+        assert Connection.is_closed?(conn)
+        assert_received {:__closed__, true, 0x10, "bye"}
+        # only once
+        assert Connection.close(conn, true, 0x10, "bye") == :ok
+        assert {:error, :already_closed} = Connection.on_timeout(conn)
+        refute_received {:__closed__, _, _, _}
+      after
+        Connection.destroy(conn)
+      end
+    after
+      Config.destroy(c)
+    end
+  end
+
+  test "partial stream writes resume on __stream_writable__" do
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+    {:ok, client_conf} = Config.new()
+    server_conf = server_config(:crypto.strong_rand_bytes(48))
+
+    try do
+      assert Config.verify_peer(client_conf, false) == :ok
+      assert Config.set_application_protos(client_conf, ["test"]) == :ok
+
+      # a stream window much smaller than what's sent, so it takes several
+      # MAX_STREAM_DATA updates from the server
+      for c <- [client_conf, server_conf] do
+        assert Config.set_initial_max_data(c, 1024 * 1024) == :ok
+        assert Config.set_initial_max_stream_data_bidi_local(c, 4096) == :ok
+        assert Config.set_initial_max_stream_data_bidi_remote(c, 4096) == :ok
+        assert Config.set_initial_max_streams_bidi(c, 10) == :ok
+      end
+
+      {client, server} = handshake(client_conf, server_conf, "192.168.0.1:4433")
+
+      try do
+        assert Connection.is_established?(client)
+        data = :crypto.strong_rand_bytes(20_000)
+        rounds = send_in_rounds(client, server, client_peer, server_peer, data, 0)
+        assert rounds > 2
+        assert collect_stream(0, "") == data
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  test "client certificate required and sent" do
+    {:ok, client_conf} = Config.new()
+    server_conf = server_config(:crypto.strong_rand_bytes(48))
+
+    try do
+      assert Config.verify_peer(client_conf, false) == :ok
+      assert Config.set_application_protos(client_conf, ["test"]) == :ok
+      assert Config.load_cert_chain_from_pem_file(client_conf, "test/support/cert.crt") == :ok
+      assert Config.load_priv_key_from_pem_file(client_conf, "test/support/cert.key") == :ok
+      assert Config.load_verify_locations_from_file(server_conf, "test/support/rootca.crt") == :ok
+      assert Config.require_peer_cert(server_conf, true) == :ok
+
+      {client, server} = handshake(client_conf, server_conf, "192.168.0.1:4433")
+
+      try do
+        assert Connection.is_established?(server) == true
+        assert {:ok, der} = Connection.peer_cert(server)
+        assert Connection.peer_cert_chain(server) == {:ok, [der]}
+        assert {:ok, [_server_cert]} = Connection.peer_cert_chain(client)
+        refute_received {:__closed__, _is_app, _code, _reason}
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  test "client certificate required but missing" do
+    {:ok, client_conf} = Config.new()
+    server_conf = server_config(:crypto.strong_rand_bytes(48))
+
+    try do
+      assert Config.verify_peer(client_conf, false) == :ok
+      assert Config.set_application_protos(client_conf, ["test"]) == :ok
+      assert Config.load_verify_locations_from_file(server_conf, "test/support/rootca.crt") == :ok
+      assert Config.require_peer_cert(server_conf, true) == :ok
+
+      for c <- [client_conf, server_conf] do
+        assert Config.set_initial_max_data(c, 1024 * 1024) == :ok
+        assert Config.set_initial_max_stream_data_bidi_local(c, 1024 * 1024) == :ok
+        assert Config.set_initial_max_stream_data_bidi_remote(c, 1024 * 1024) == :ok
+        assert Config.set_initial_max_streams_bidi(c, 10) == :ok
+        assert Config.enable_dgram(c, true, 10, 10) == :ok
+      end
+
+      {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+      {:ok, server_peer} = Socket.address_from_string("192.168.0.1:4433")
+
+      {:ok, server} =
+        Connection.accept(
+          server_conf,
+          :crypto.strong_rand_bytes(20),
+          "",
+          client_peer,
+          self(),
+          1024 * 10,
+          1350
+        )
+
+      {:ok, client} =
+        Connection.connect(
+          client_conf,
+          "example.com",
+          :crypto.strong_rand_bytes(20),
+          server_peer,
+          self(),
+          1024 * 10,
+          1350
+        )
+
+      try do
+        # the client's Finished is held back and goes to the server together
+        # with stream data and a datagram, all in one on_packets call
+        held = exchange_until_established(client, server, client_peer, server_peer, [])
+        assert Connection.is_established?(client)
+        assert {:ok, _, _} = Connection.stream_send(client, 0, "hello", true)
+        assert {:ok, _} = Connection.dgram_send(client, "hi")
+        held = held ++ collect_drained(server_peer)
+        Connection.on_packets(server, client_peer, held)
+
+        assert Connection.peer_cert_chain(server) == {:ok, []}
+        assert Connection.is_closed?(server) or Connection.is_draining?(server)
+        # certificate_required (116) as a CRYPTO_ERROR
+        assert_received {:__closed__, false, 0x174, "certificate required"}
+        refute_received {:__connected__, _}
+        refute_received {:__stream_recv__, _, _}
+        refute_received {:__dgram_recv__, _}
+      after
+        Connection.destroy(client)
+        Connection.destroy(server)
+      end
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
   test "multiple connection state" do
     scid1 = :crypto.strong_rand_bytes(20)
     odcid1 = :crypto.strong_rand_bytes(20)
@@ -58,24 +812,24 @@ defmodule RequiemTest.ConnectionTest do
     {:ok, c} = Config.new()
 
     try do
-      {:ok, conn1} = Connection.accept(c, scid1, odcid1, peer, sender_pid, 1024 * 10)
-      {:ok, conn2} = Connection.accept(c, scid2, odcid2, peer, sender_pid, 1024 * 10)
+      {:ok, conn1} = Connection.accept(c, scid1, odcid1, peer, sender_pid, 1024 * 10, 1350)
+      {:ok, conn2} = Connection.accept(c, scid2, odcid2, peer, sender_pid, 1024 * 10, 1350)
 
       try do
         assert Connection.is_closed?(conn1) == false
         assert Connection.is_closed?(conn2) == false
-        assert Connection.close(conn1, false, 0x1, "") == {:error, :already_closed}
+        assert Connection.close(conn1, false, 0x1, "") == :ok
         assert Connection.is_closed?(conn1) == true
         assert Connection.is_closed?(conn2) == false
 
-        assert Connection.close(conn2, false, 0x1, "") == {:error, :already_closed}
+        assert Connection.close(conn2, false, 0x1, "") == :ok
 
         assert Connection.is_closed?(conn1) == true
         assert Connection.is_closed?(conn2) == true
 
-        # duplicated close command
-        assert Connection.close(conn1, false, 0x1, "") == {:error, :already_closed}
-        assert Connection.close(conn2, false, 0x1, "") == {:error, :already_closed}
+        # duplicated close command, with a reason longer than a packet
+        assert Connection.close(conn1, false, 0x1, String.duplicate("a", 2000)) == :ok
+        assert Connection.close(conn2, false, 0x1, "") == :ok
       after
         Connection.destroy(conn1)
         Connection.destroy(conn2)
@@ -85,4 +839,106 @@ defmodule RequiemTest.ConnectionTest do
       Process.exit(sender_pid, :kill)
     end
   end
+
+  # like exchange_packets, except that once the client is established its
+  # packets for the server are returned instead of delivered
+  defp exchange_until_established(client, server, client_peer, server_peer, held) do
+    {:ok, client_ip, client_port} = Socket.address_parts(client_peer)
+    {:ok, server_ip, server_port} = Socket.address_parts(server_peer)
+
+    receive do
+      {:__drain__, ^server_ip, ^server_port, packets, _delay} ->
+        if Connection.is_established?(client) do
+          exchange_until_established(client, server, client_peer, server_peer, held ++ packets)
+        else
+          Enum.each(packets, &Connection.on_packet(server, client_peer, &1))
+          exchange_until_established(client, server, client_peer, server_peer, held)
+        end
+
+      {:__drain__, ^client_ip, ^client_port, packets, _delay} ->
+        Enum.each(packets, &Connection.on_packet(client, server_peer, &1))
+        exchange_until_established(client, server, client_peer, server_peer, held)
+    after
+      100 -> held
+    end
+  end
+
+  defp collect_drained(peer) do
+    {:ok, ip, port} = Socket.address_parts(peer)
+
+    receive do
+      {:__drain__, ^ip, ^port, packets, _delay} -> packets ++ collect_drained(peer)
+    after
+      100 -> []
+    end
+  end
+
+  # like exchange_packets, with the server's packets fed through the dcid
+  # registry from a task
+  defp exchange_packets_by_dcid(client, client_peer, server_peer) do
+    {:ok, client_ip, client_port} = Socket.address_parts(client_peer)
+    {:ok, server_ip, server_port} = Socket.address_parts(server_peer)
+
+    receive do
+      {:__drain__, ^server_ip, ^server_port, packets, _delay} ->
+        Task.async(fn ->
+          Enum.each(packets, fn packet ->
+            {:ok, %{dcid: dcid}} = PacketHeader.parse(packet, 20)
+            Connection.on_packet_by_dcid(dcid, client_peer, packet)
+          end)
+        end)
+        |> Task.await()
+
+        exchange_packets_by_dcid(client, client_peer, server_peer)
+
+      {:__drain__, ^client_ip, ^client_port, packets, _delay} ->
+        Enum.each(packets, &Connection.on_packet(client, server_peer, &1))
+        exchange_packets_by_dcid(client, client_peer, server_peer)
+    after
+      100 -> :ok
+    end
+  end
+
+  # writes data to the client's stream 0 as flow control lets it, one round
+  # per __stream_writable__, and returns how many rounds it took
+  defp send_in_rounds(client, server, client_peer, server_peer, data, rounds) do
+    case Connection.stream_send(client, 0, data, true) do
+      {:ok, _, _} ->
+        exchange_packets(client, server, client_peer, server_peer)
+        rounds + 1
+
+      {:partial, written, _} ->
+        refute_received {:__stream_writable__, 0}
+        # the server reads what came, which gets its MAX_STREAM_DATA back
+        exchange_packets(client, server, client_peer, server_peer)
+        assert_received {:__stream_writable__, 0}
+        rest = binary_part(data, written, byte_size(data) - written)
+        send_in_rounds(client, server, client_peer, server_peer, rest, rounds + 1)
+    end
+  end
+
+  # stream_recv until fin, 1000 bytes at a time
+  defp read_stream(conn, stream_id, acc) do
+    case Connection.stream_recv(conn, stream_id, 1000) do
+      {:ok, data, true} -> acc <> data
+      {:ok, data, false} -> read_stream(conn, stream_id, acc <> data)
+    end
+  end
+
+  defp collect_stream(stream_id, acc) do
+    receive do
+      {:__stream_recv__, ^stream_id, data} -> collect_stream(stream_id, acc <> data)
+    after
+      0 -> acc
+    end
+  end
+
+  defp server_config(ticket_key) do
+    {:ok, c} = Config.new()
+    :ok = Config.load_cert_chain_from_pem_file(c, "test/support/cert.crt")
+    :ok = Config.load_priv_key_from_pem_file(c, "test/support/cert.key")
+    :ok = Config.set_application_protos(c, ["test"])
+    :ok = Config.set_ticket_key(c, ticket_key)
+    c
+  end
 end
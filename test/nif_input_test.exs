@@ -0,0 +1,123 @@
+defmodule RequiemTest.NIFInputTest do
+  use ExUnit.Case, async: true
+
+  alias Requiem.QUIC.Config
+  alias Requiem.QUIC.Connection
+  alias Requiem.QUIC.PacketBuilder
+  alias Requiem.QUIC.Socket
+
+  # A panicking NIF takes the whole VM down with it, so every call here
+  # only has to come back with something.
+  @rounds 200
+
+  defp random_binary() do
+    :crypto.strong_rand_bytes(:rand.uniform(2000) - 1)
+  end
+
+  defp assert_returns(result) do
+    assert result == :ok or match?({:ok, _}, result) or match?({:error, _}, result)
+  end
+
+  test "config NIFs with random binaries" do
+    {:ok, c} = Config.new()
+
+    try do
+      for _ <- 1..@rounds do
+        assert_returns(Config.load_cert_chain_from_pem_file(c, random_binary()))
+        assert_returns(Config.load_priv_key_from_pem_file(c, random_binary()))
+        assert_returns(Config.load_verify_locations_from_file(c, random_binary()))
+        assert_returns(Config.load_verify_locations_from_directory(c, random_binary()))
+        assert_returns(Config.set_cc_algorithm_name(c, random_binary()))
+        assert_returns(Config.set_ticket_key(c, random_binary()))
+        assert_returns(Config.set_application_protos(c, [random_binary(), random_binary()]))
+      end
+
+      assert {:ok, _settings} = Config.dump(c)
+    after
+      Config.destroy(c)
+    end
+  end
+
+  test "address NIF with random binaries" do
+    for _ <- 1..@rounds do
+      assert_returns(Socket.address_from_string(random_binary()))
+    end
+
+    assert Socket.address_from_string("192.168.0.1:70000") == {:error, :bad_format}
+  end
+
+  test "packet builder NIFs with random binaries" do
+    {:ok, builder} = PacketBuilder.new()
+
+    try do
+      for _ <- 1..@rounds do
+        assert_returns(
+          PacketBuilder.build_negotiate_version(builder, random_binary(), random_binary())
+        )
+
+        assert_returns(
+          PacketBuilder.build_retry(
+            builder,
+            random_binary(),
+            random_binary(),
+            random_binary(),
+            random_binary(),
+            1
+          )
+        )
+      end
+
+      assert PacketBuilder.build_negotiate_version(builder, <<0::size(21)-unit(8)>>, "") ==
+               {:error, :bad_format}
+
+      scid = :crypto.strong_rand_bytes(20)
+      token = :crypto.strong_rand_bytes(1500)
+
+      assert PacketBuilder.build_retry(builder, scid, scid, scid, token, 1) ==
+               {:error, :buffer_too_short}
+    after
+      PacketBuilder.destroy(builder)
+    end
+  end
+
+  test "connection NIFs with random binaries" do
+    {:ok, peer} = Socket.address_from_string("192.168.0.1:4000")
+    {:ok, c} = Config.new()
+
+    try do
+      long_cid = :crypto.strong_rand_bytes(21)
+      scid = :crypto.strong_rand_bytes(20)
+
+      assert Connection.accept(c, long_cid, "", peer, self(), 1024, 1350) ==
+               {:error, :bad_format}
+
+      assert Connection.accept(c, scid, long_cid, peer, self(), 1024, 1350) ==
+               {:error, :bad_format}
+
+      assert Connection.accept(c, scid, "", peer, self(), 1024, 0xFFFF_FFFF) ==
+               {:error, :bad_format}
+
+      assert Connection.connect(c, random_binary(), long_cid, peer, self(), 1024, 1350) ==
+               {:error, :bad_format}
+
+      assert Connection.accept(c, scid, "", peer, self(), 0, 1350) == {:error, :bad_format}
+
+      assert Connection.accept(c, scid, "", peer, self(), 0xFFFF_FFFF_FFFF, 1350) ==
+               {:error, :bad_format}
+
+      {:ok, conn} = Connection.accept(c, scid, "", peer, self(), 1024, 1350)
+
+      try do
+        for _ <- 1..@rounds do
+          assert_returns(Connection.on_packet(conn, peer, random_binary()))
+        end
+
+        assert_returns(Connection.on_packets(conn, peer, [random_binary(), random_binary()]))
+      after
+        Connection.destroy(conn)
+      end
+    after
+      Config.destroy(c)
+    end
+  end
+end
@@ -0,0 +1,46 @@
+defmodule RequiemTest.PacketHeaderTest do
+  use ExUnit.Case, async: true
+
+  alias Requiem.QUIC.PacketBuilder
+  alias Requiem.QUIC.PacketHeader
+
+  test "version negotiation header" do
+    {:ok, builder} = PacketBuilder.new()
+
+    try do
+      scid = :crypto.strong_rand_bytes(20)
+      dcid = :crypto.strong_rand_bytes(20)
+      {:ok, packet} = PacketBuilder.build_negotiate_version(builder, scid, dcid)
+
+      assert {:ok, hdr} = PacketHeader.parse(packet, 20)
+      assert hdr.packet_type == :version_negotiation
+      assert hdr.form == :long
+      assert hdr.version == 0
+      # addressed back to the sender of the original packet
+      assert hdr.dcid == scid
+      assert hdr.scid == dcid
+      assert 0x00000001 in hdr.versions
+    after
+      PacketBuilder.destroy(builder)
+    end
+  end
+
+  test "short header" do
+    dcid = :crypto.strong_rand_bytes(20)
+    packet = <<0x40>> <> dcid <> :crypto.strong_rand_bytes(30)
+
+    assert {:ok, hdr} = PacketHeader.parse(packet, 20)
+    assert hdr.packet_type == :short
+    assert hdr.form == :short
+    assert hdr.dcid == dcid
+    assert hdr.versions == nil
+
+    assert PacketHeader.parse(packet, 21) == {:error, :bad_format}
+    # shorter than the dcid it should hold
+    assert PacketHeader.parse(<<0x40>> <> :crypto.strong_rand_bytes(7), 8) ==
+             {:error, :bad_format}
+
+    assert {:ok, %{dcid: short_dcid}} = PacketHeader.parse(packet, 8)
+    assert short_dcid == binary_part(dcid, 0, 8)
+  end
+end
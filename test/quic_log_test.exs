@@ -0,0 +1,62 @@
+defmodule RequiemTest.QUICLogTest do
+  # the log sink is global, so nothing else may run meanwhile
+  use ExUnit.Case, async: false
+
+  alias Requiem.QUIC
+  alias Requiem.QUIC.Config
+  alias Requiem.QUIC.Connection
+
+  import RequiemTest.QUICHelper
+
+  test "quiche log lines come while debug logging is on" do
+    {:ok, client_conf} = Config.new()
+    {:ok, server_conf} = Config.new()
+
+    try do
+      :ok = Config.verify_peer(client_conf, false)
+      :ok = Config.set_application_protos(client_conf, ["test"])
+      :ok = Config.load_cert_chain_from_pem_file(server_conf, "test/support/cert.crt")
+      :ok = Config.load_priv_key_from_pem_file(server_conf, "test/support/cert.key")
+      :ok = Config.set_application_protos(server_conf, ["test"])
+
+      assert QUIC.enable_debug_logging(self(), :bogus) == {:error, :bad_format}
+      assert QUIC.enable_debug_logging(self(), :trace) == :ok
+
+      try do
+        connect(client_conf, server_conf)
+        assert_receive {:__quiche_log__, level, line}, 1000
+        assert level in [:error, :warn, :info, :debug, :trace]
+        assert is_binary(line)
+      after
+        assert QUIC.disable_debug_logging() == :ok
+      end
+
+      # lines already queued may still arrive; after those, nothing
+      flush_logs()
+      connect(client_conf, server_conf)
+      refute_receive {:__quiche_log__, _, _}, 200
+    after
+      Config.destroy(client_conf)
+      Config.destroy(server_conf)
+    end
+  end
+
+  defp flush_logs() do
+    receive do
+      {:__quiche_log__, _, _} -> flush_logs()
+    after
+      100 -> :ok
+    end
+  end
+
+  defp connect(client_conf, server_conf) do
+    {client, server} = handshake(client_conf, server_conf, "192.168.0.1:4433")
+
+    try do
+      assert Connection.is_established?(client)
+    after
+      Connection.destroy(client)
+      Connection.destroy(server)
+    end
+  end
+end
@@ -0,0 +1,511 @@
+defmodule RequiemTest.SocketTest do
+  use ExUnit.Case, async: true
+
+  alias Requiem.QUIC.PacketBuilder
+  alias Requiem.QUIC.Socket
+  alias Requiem.QUIC.SocketSender
+
+  test "stop releases the port" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+
+    try do
+      assert Socket.stop(socket) == {:error, :bad_state}
+      assert Socket.set_send_retries(socket, 0) == :ok
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == {:error, :bad_state}
+      assert Socket.set_send_retries(socket, 5) == {:error, :bad_state}
+
+      assert Socket.stop(socket) == :ok
+      assert_received {:__socket_closed__}
+
+      # nobody holds the port anymore, not even with SO_REUSEPORT
+      {:ok, udp} = :gen_udp.open(port, ip: {127, 0, 0, 1})
+      :gen_udp.close(udp)
+
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+      assert Socket.stop(socket) == :ok
+    after
+      Socket.destroy(socket)
+    end
+  end
+
+  test "stop doesn't wait out the read timeout" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+
+    try do
+      assert Socket.set_poll_interval(socket, 0) == {:error, :bad_format}
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+      assert Socket.set_poll_interval(socket, 10_000) == :ok
+      # let the receiver go back to a read with the new timeout
+      Process.sleep(200)
+
+      {elapsed, :ok} = :timer.tc(fn -> Socket.stop(socket) end)
+
+      if match?({:unix, :linux}, :os.type()) do
+        assert elapsed < 5_000_000
+      end
+    after
+      Socket.destroy(socket)
+    end
+  end
+
+  test "dropped datagrams are counted" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0)
+
+    try do
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, <<1, 2>>)
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, :crypto.strong_rand_bytes(2000))
+      Process.sleep(200)
+
+      assert {:ok, stats} = Socket.stats(socket)
+      assert stats.too_small == 1
+      assert stats.too_big == 1
+      assert stats.received == 0
+      assert stats.bytes_received == 0
+
+      assert Socket.stats_reset(socket) == :ok
+      assert {:ok, stats} = Socket.stats(socket)
+      counters = Map.drop(stats, [:recv_buffer_size, :send_buffer_size])
+      assert Enum.all?(counters, fn {_key, count} -> count == 0 end)
+    after
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "datagrams arrive batched per dispatcher" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0)
+    {:ok, builder} = PacketBuilder.new()
+    cid = :crypto.strong_rand_bytes(20)
+    {:ok, packet} = PacketBuilder.build_negotiate_version(builder, cid, cid)
+
+    try do
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+
+      for _ <- 1..10 do
+        :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, packet)
+      end
+
+      Process.sleep(200)
+      assert {:ok, %{received: 10}} = Socket.stats(socket)
+
+      packets = receive_packets([])
+      assert length(packets) == 10
+      assert Enum.all?(packets, fn {_peer, p, _scid, dcid, _, _, _, _, _} ->
+               p == packet and dcid == cid
+             end)
+    after
+      PacketBuilder.destroy(builder)
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  defp receive_packets(acc) do
+    receive do
+      {:__packets__, packets} -> receive_packets(acc ++ packets)
+    after
+      0 -> acc
+    end
+  end
+
+  test "gso sends arrive as separate datagrams" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0, [:binary, active: false, ip: {127, 0, 0, 1}])
+    {:ok, udp_port} = :inet.port(udp)
+    {:ok, peer} = Socket.address_from_string("127.0.0.1:#{udp_port}")
+    {:ok, sender} = SocketSender.get(socket, 0)
+
+    try do
+      assert Socket.gso_supported(socket) == {:error, :bad_state}
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+      assert {:ok, supported} = Socket.gso_supported(socket)
+      assert is_boolean(supported)
+
+      assert SocketSender.send_gso(sender, peer, "", 1000) == {:error, :bad_format}
+      assert SocketSender.send_gso(sender, peer, "abc", 0) == {:error, :bad_format}
+
+      buffer = :crypto.strong_rand_bytes(2500)
+      assert SocketSender.send_gso(sender, peer, buffer, 1000) == :ok
+
+      received =
+        for _ <- 1..3 do
+          {:ok, {_, ^port, data}} = :gen_udp.recv(udp, 0, 1000)
+          data
+        end
+
+      assert Enum.map(received, &byte_size/1) == [1000, 1000, 500]
+      assert IO.iodata_to_binary(received) == buffer
+
+      assert {:ok, %{sent: 3, bytes_sent: 2500, send_errors: 0}} = Socket.stats(socket)
+    after
+      SocketSender.destroy(sender)
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "sent datagrams carry the ecn codepoint" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, self_addr} = Socket.address_from_string("127.0.0.1:#{port}")
+    {:ok, sender} = SocketSender.get(socket, 0)
+    {:ok, builder} = PacketBuilder.new()
+    cid = :crypto.strong_rand_bytes(20)
+    {:ok, packet} = PacketBuilder.build_negotiate_version(builder, cid, cid)
+
+    try do
+      case :os.type() do
+        {:unix, :linux} ->
+          assert Socket.set_ecn(socket, :ce) == {:error, :bad_format}
+          assert Socket.set_ecn(socket, :ect0) == :ok
+          assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+          assert Socket.set_ecn(socket, :ect1) == {:error, :bad_state}
+
+          # the socket sends to itself
+          assert SocketSender.send(sender, self_addr, packet) == :ok
+          assert_receive {:__packets__, [{_, ^packet, _, _, _, _, _, _, :ect0}]}, 1000
+
+        _ ->
+          assert Socket.set_ecn(socket, :ect0) == {:error, :not_supported}
+      end
+    after
+      PacketBuilder.destroy(builder)
+      SocketSender.destroy(sender)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "address as a tuple, and dual-stack options" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0, [:inet])
+    {:ok, builder} = PacketBuilder.new()
+    cid = :crypto.strong_rand_bytes(20)
+    {:ok, packet} = PacketBuilder.build_negotiate_version(builder, cid, cid)
+
+    try do
+      assert Socket.start(socket, {127, 0, 0, 1}, port, self(), [self()], foo: true) ==
+               {:error, :bad_format}
+
+      assert Socket.start(socket, {127, 0, 0, 1}, port, self(), [self()], recv_buffer_size: 0) ==
+               {:error, :bad_format}
+
+      for policy <- [:sometimes, {:shutdown, 0}, {:notify, 1}] do
+        opts = [recv_error_policy: policy]
+
+        assert Socket.start(socket, {127, 0, 0, 1}, port, self(), [self()], opts) ==
+                 {:error, :bad_format}
+      end
+
+      opts = [
+        recv_buffer_size: 262_144,
+        send_buffer_size: 131_072,
+        recv_error_policy: {:shutdown, 3}
+      ]
+
+      assert Socket.start(socket, {127, 0, 0, 1}, port, self(), [self()], opts) == :ok
+      assert {:ok, stats} = Socket.stats(socket)
+      assert stats.recv_buffer_size > 0
+      assert stats.send_buffer_size > 0
+      assert Socket.stop(socket) == :ok
+
+      opts = [v6_only: false, normalize_v4_mapped: true]
+
+      case Socket.start(socket, {0, 0, 0, 0, 0, 0, 0, 0}, port, self(), [self()], opts) do
+        :ok ->
+          :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, packet)
+          assert_receive {:__packets__, [{peer, ^packet, _, _, _, _, _, _, _}]}, 1000
+          assert {:ok, <<127, 0, 0, 1>>, _} = Socket.address_parts(peer)
+          assert Socket.stop(socket) == :ok
+
+        # no IPv6 on this host
+        {:error, reason} when reason in [:eaddrnotavail, :socket_error] ->
+          :ok
+      end
+    after
+      PacketBuilder.destroy(builder)
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "peers as {ip, port}" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0, [:inet, ip: {127, 0, 0, 1}])
+    {:ok, udp_port} = :inet.port(udp)
+    {:ok, builder} = PacketBuilder.new()
+    cid = :crypto.strong_rand_bytes(20)
+    {:ok, packet} = PacketBuilder.build_negotiate_version(builder, cid, cid)
+
+    try do
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()], peer_parts: 1) ==
+               {:error, :bad_format}
+
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()], peer_parts: true) == :ok
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, packet)
+      assert_receive {:__packets__, [{peer, ^packet, _, _, _, _, _, _, _}]}, 1000
+      assert peer == {<<127, 0, 0, 1>>, udp_port}
+      assert Socket.stop(socket) == :ok
+    after
+      PacketBuilder.destroy(builder)
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "a dead target pid shuts the socket down" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0)
+    {:ok, builder} = PacketBuilder.new()
+    cid = :crypto.strong_rand_bytes(20)
+    {:ok, packet} = PacketBuilder.build_negotiate_version(builder, cid, cid)
+    target = spawn(fn -> :ok end)
+    ref = Process.monitor(target)
+    assert_receive {:DOWN, ^ref, :process, ^target, _}
+
+    try do
+      opts = [supervisor: self()]
+      assert Socket.start(socket, "127.0.0.1", port, self(), [target], opts) == :ok
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, packet)
+      assert_receive {:__owner_down__}, 1000
+
+      assert Socket.stop(socket) == :ok
+      {:ok, freed} = :gen_udp.open(port, ip: {127, 0, 0, 1})
+      :gen_udp.close(freed)
+    after
+      PacketBuilder.destroy(builder)
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "a teardown frees the port without stop" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0)
+    {:ok, builder} = PacketBuilder.new()
+    cid = :crypto.strong_rand_bytes(20)
+    {:ok, packet} = PacketBuilder.build_negotiate_version(builder, cid, cid)
+    target = spawn(fn -> :ok end)
+    ref = Process.monitor(target)
+    assert_receive {:DOWN, ^ref, :process, ^target, _}
+
+    try do
+      opts = [supervisor: self()]
+      assert Socket.start(socket, "127.0.0.1", port, self(), [target], opts) == :ok
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, packet)
+      assert_receive {:__owner_down__}, 1000
+
+      # the threads exit on their own, within a poll interval or so
+      assert {:ok, freed} = open_when_free(port, 10)
+      :gen_udp.close(freed)
+    after
+      PacketBuilder.destroy(builder)
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "datagrams that aren't QUIC pass through" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0, [:inet, ip: {127, 0, 0, 1}])
+    {:ok, udp_port} = :inet.port(udp)
+    {:ok, builder} = PacketBuilder.new()
+    cid = :crypto.strong_rand_bytes(20)
+    # unlike version negotiation, retry packets always set the fixed bit
+    {:ok, packet} = PacketBuilder.build_retry(builder, cid, cid, cid, "token", 1)
+
+    try do
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()], other_prefix: "PING") ==
+               {:error, :bad_format}
+
+      opts = [other_pid: self(), other_prefix: "\xFFPING", peer_parts: true]
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()], opts) == :ok
+      peer = {<<127, 0, 0, 1>>, udp_port}
+
+      # a STUN binding request, a short datagram and a prefixed one
+      stun = <<0, 1, 0, 0, 0x21, 0x12, 0xA4, 0x42>> <> :crypto.strong_rand_bytes(12)
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, stun)
+      assert_receive {:__other_packet__, ^peer, ^stun}, 1000
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, <<1>>)
+      assert_receive {:__other_packet__, ^peer, <<1>>}, 1000
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, "\xFFPING")
+      assert_receive {:__other_packet__, ^peer, "\xFFPING"}, 1000
+
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, packet)
+      assert_receive {:__packets__, [{^peer, ^packet, _, _, _, _, _, _, _}]}, 1000
+
+      assert {:ok, %{passed_through: 3, received: 1}} = Socket.stats(socket)
+      assert Socket.stop(socket) == :ok
+    after
+      PacketBuilder.destroy(builder)
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "datagrams that don't parse go to the fallback pid" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0, [:inet, ip: {127, 0, 0, 1}])
+    {:ok, udp_port} = :inet.port(udp)
+    parent = self()
+    other = spawn_link(fn -> forward(parent, :other) end)
+
+    try do
+      opts = [fallback_pid: self(), other_pid: other, peer_parts: true]
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()], opts) == :ok
+      peer = {<<127, 0, 0, 1>>, udp_port}
+
+      # a short header too short for its 20 byte dcid, and a long header
+      # claiming a 255 byte one
+      short = <<0x40, 1, 2, 3, 4>>
+      long = <<0xC0, 0, 0, 0, 1, 0xFF, 1, 2, 3>>
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, short)
+      assert_receive {:__unparsed_packet__, ^peer, ^short}, 1000
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, long)
+      assert_receive {:__unparsed_packet__, ^peer, ^long}, 1000
+
+      # what other_pid picks out by the first byte still goes there
+      stun = <<0, 1, 0, 0, 0x21, 0x12, 0xA4, 0x42>> <> :crypto.strong_rand_bytes(12)
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, stun)
+      assert_receive {:other, {:__other_packet__, ^peer, ^stun}}, 1000
+
+      assert {:ok, %{fell_back: 2, passed_through: 1, not_quic: 0}} = Socket.stats(socket)
+      assert Socket.stop(socket) == :ok
+
+      # dropped, as before, without the option
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, long)
+      refute_receive {:__unparsed_packet__, _, _}, 200
+      assert {:ok, %{fell_back: 2, not_quic: 1}} = Socket.stats(socket)
+      assert Socket.stop(socket) == :ok
+    after
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "only the owner hands the socket over" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    parent = self()
+
+    try do
+      assert Socket.set_controlling_process(socket, self()) == {:error, :bad_state}
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+
+      other =
+        spawn(fn ->
+          send(parent, {:tried, Socket.set_controlling_process(socket, self())})
+
+          receive do
+            :done -> :ok
+          end
+        end)
+
+      assert_receive {:tried, {:error, :not_owner}}
+      assert Socket.set_controlling_process(socket, other) == :ok
+      assert Socket.set_controlling_process(socket, self()) == {:error, :not_owner}
+      send(other, :done)
+
+      assert Socket.stop(socket) == :ok
+    after
+      Socket.destroy(socket)
+    end
+  end
+
+  test "many processes racing for the socket" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+
+    try do
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+
+      results =
+        1..50
+        |> Enum.map(fn _ ->
+          Task.async(fn ->
+            for _ <- 1..200, do: Socket.set_controlling_process(socket, self())
+          end)
+        end)
+        |> Enum.flat_map(&Task.await/1)
+
+      assert Enum.uniq(results) == [{:error, :not_owner}]
+      # the lock is still usable by the real owner afterwards
+      assert Socket.set_controlling_process(socket, self()) == :ok
+      assert {:ok, _} = Socket.stats(socket)
+      assert Socket.stop(socket) == :ok
+    after
+      Socket.destroy(socket)
+    end
+  end
+
+  test "a target pid can be swapped for a restarted dispatcher" do
+    port = 20_000 + :rand.uniform(10_000)
+    {:ok, socket} = Socket.new(1, 100, 100, 20, 1500, 32)
+    {:ok, udp} = :gen_udp.open(0, [:inet, ip: {127, 0, 0, 1}])
+    {:ok, builder} = PacketBuilder.new()
+    cid = :crypto.strong_rand_bytes(20)
+    {:ok, packet} = PacketBuilder.build_negotiate_version(builder, cid, cid)
+    parent = self()
+    other = spawn_link(fn -> forward(parent, :other) end)
+
+    try do
+      assert Socket.set_target_pid(socket, 0, other) == {:error, :bad_state}
+      assert Socket.start(socket, "127.0.0.1", port, self(), [self()]) == :ok
+      assert Socket.set_target_pid(socket, 1, other) == {:error, :bad_format}
+      assert Socket.set_target_pid(socket, 0, other) == :ok
+
+      :ok = :gen_udp.send(udp, {127, 0, 0, 1}, port, packet)
+      assert_receive {:other, {:__packets__, [{_, ^packet, _, _, _, _, _, _, _}]}}, 1000
+      refute_received {:__packets__, _}
+
+      assert Socket.stop(socket) == :ok
+    after
+      PacketBuilder.destroy(builder)
+      :gen_udp.close(udp)
+      Socket.destroy(socket)
+    end
+  end
+
+  test "bad arguments" do
+    assert Socket.new(0, 100, 100, 20, 1500, 32) == {:error, :bad_format}
+    assert Socket.new(1, 100, 100, 21, 1500, 32) == {:error, :bad_format}
+    assert Socket.new(1, 100, 100, 20, 1199, 32) == {:error, :bad_format}
+    assert Socket.new(1, 100, 100, 20, 65_528, 32) == {:error, :bad_format}
+    assert Socket.new(1, 100, 100, 20, 1500, 0) == {:error, :bad_format}
+    assert Socket.new(1, 100, 100, 20, 1500, 257) == {:error, :bad_format}
+  end
+
+  defp forward(pid, tag) do
+    receive do
+      message ->
+        send(pid, {tag, message})
+        forward(pid, tag)
+    end
+  end
+
+  defp open_when_free(port, tries) do
+    case :gen_udp.open(port, ip: {127, 0, 0, 1}) do
+      {:error, :eaddrinuse} when tries > 0 ->
+        Process.sleep(100)
+        open_when_free(port, tries - 1)
+
+      result ->
+        result
+    end
+  end
+end
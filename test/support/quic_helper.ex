@@ -0,0 +1,60 @@
+defmodule RequiemTest.QUICHelper do
+  # Drives a pair of in-memory connections, both owned by the calling
+  # process, by handing each side's {:__drain__, ...} to the other.
+
+  alias Requiem.QUIC.Connection
+  alias Requiem.QUIC.Socket
+
+  # connects a client at 192.168.0.2:4000 to a server at server_addr and
+  # exchanges packets until both go quiet
+  @spec handshake(integer, integer, binary, binary | nil) :: {integer, integer}
+  def handshake(client_conf, server_conf, server_addr, session \\ nil) do
+    {:ok, client_peer} = Socket.address_from_string("192.168.0.2:4000")
+    {:ok, server_peer} = Socket.address_from_string(server_addr)
+
+    {:ok, server} =
+      Connection.accept(
+        server_conf,
+        :crypto.strong_rand_bytes(20),
+        "",
+        client_peer,
+        self(),
+        1024 * 10,
+        1350
+      )
+
+    {:ok, client} =
+      Connection.connect(
+        client_conf,
+        "example.com",
+        :crypto.strong_rand_bytes(20),
+        server_peer,
+        self(),
+        1024 * 10,
+        1350,
+        session
+      )
+
+    exchange_packets(client, server, client_peer, server_peer)
+    {client, server}
+  end
+
+  # delivers each drained flight to the other side until both go quiet
+  @spec exchange_packets(integer, integer, term, term) :: :ok
+  def exchange_packets(client, server, client_peer, server_peer) do
+    {:ok, client_ip, client_port} = Socket.address_parts(client_peer)
+    {:ok, server_ip, server_port} = Socket.address_parts(server_peer)
+
+    receive do
+      {:__drain__, ^server_ip, ^server_port, packets, _delay} ->
+        Enum.each(packets, &Connection.on_packet(server, client_peer, &1))
+        exchange_packets(client, server, client_peer, server_peer)
+
+      {:__drain__, ^client_ip, ^client_port, packets, _delay} ->
+        Enum.each(packets, &Connection.on_packet(client, server_peer, &1))
+        exchange_packets(client, server, client_peer, server_peer)
+    after
+      100 -> :ok
+    end
+  end
+end